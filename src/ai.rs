@@ -1,4 +1,5 @@
-use crate::config::{Config, AIProvider};
+use crate::config::{Config, AIProvider, PrivilegeMode, RiskLevel};
+use crate::interrupt;
 use crate::shell::ShellType;
 use anyhow::{Result, anyhow};
 use reqwest::header::{HeaderMap, HeaderValue, CONTENT_TYPE, AUTHORIZATION};
@@ -29,62 +30,96 @@ struct Content {
     text: String,
 }
 
-pub async fn get_command_suggestion(query: &str, config: &Config) -> Result<(String, bool)> {
-    match config.ai.provider {
-        AIProvider::Anthropic => get_anthropic_command(query, config).await,
-        AIProvider::OpenAI => get_openai_command(query, config).await,
-    }
+/// What the model answered to a natural language query.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Suggestion {
+    /// `terminal` is set when the model says the command is interactive or
+    /// full-screen.
+    Command { command: String, risk: RiskLevel, terminal: bool },
+    Clarify(Vec<Clarification>),
 }
 
-pub async fn get_error_suggestion(command: &str, stdout: &str, stderr: &str, config: &Config) -> Result<String> {
-    match config.ai.provider {
-        AIProvider::Anthropic => get_anthropic_error(command, stdout, stderr, config).await,
-        AIProvider::OpenAI => get_openai_error(command, stdout, stderr, config).await,
-    }
+/// A question the model needs answered before it can suggest a command.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Clarification {
+    pub question: String,
+    pub choices: Vec<String>,
 }
 
-async fn get_anthropic_error(command: &str, stdout: &str, stderr: &str, config: &Config) -> Result<String> {
-    let api_key = config.ai.anthropic_api_key.as_ref()
-        .ok_or_else(|| anyhow!("Anthropic API key not configured"))?;
+/// The user's reply to a `Clarification`, sent back with the original query.
+#[derive(Debug, Clone)]
+pub struct Answer {
+    pub question: String,
+    pub answer: String,
+}
 
-    let client = reqwest::Client::new();
-    let mut headers = HeaderMap::new();
-    headers.insert("anthropic-version", HeaderValue::from_static("2023-06-01"));
-    headers.insert("x-api-key", HeaderValue::from_str(api_key)?);
-    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+/// A rejected suggestion and what the user wants done differently.
+#[derive(Debug, Clone)]
+pub struct Feedback {
+    pub command: String,
+    pub note: String,
+}
+
+/// Everything sent to the model when asking for a command.
+#[derive(Debug, Clone, Default)]
+pub struct Query {
+    pub text: String,
+    pub answers: Vec<Answer>,
+    pub feedback: Vec<Feedback>,
+}
+
+impl Query {
+    pub fn new(text: &str) -> Self {
+        Query { text: text.to_string(), ..Default::default() }
+    }
+}
 
+const FORMAT_SYSTEM: &str = "You are Spren, a helpful command-line assistant. Respond only in the specified format.";
+const EXPLAIN_SYSTEM: &str = "You are Spren, a helpful command-line assistant. Provide clear and concise explanations.";
+
+pub async fn get_command_suggestion(query: &Query, config: &Config) -> Result<Suggestion> {
     let shell_type = ShellType::detect();
-    let shell_name = shell_type.get_shell_name();
+    let avoid_privileges = config.security.privileged_commands == PrivilegeMode::Avoid;
+    let prompt = command_prompt(query, shell_type.get_shell_name(), avoid_privileges);
+    let response = complete(FORMAT_SYSTEM, &prompt, config).await?;
+    parse_ai_response(&response)
+}
 
+pub async fn get_error_suggestion(command: &str, stdout: &str, stderr: &str, config: &Config) -> Result<String> {
+    let shell_type = ShellType::detect();
     let prompt = format!(
         "Analyze this {} command result:\nCommand: {}\nStdout: {}\nStderr: {}\n\
          Explain what happened and suggest improvements. Be specific and brief.",
-        shell_name, command, stdout, stderr
+        shell_type.get_shell_name(), command, stdout, stderr
     );
+    complete(EXPLAIN_SYSTEM, &prompt, config).await
+}
 
-    let response = client
-        .post("https://api.anthropic.com/v1/messages")
-        .headers(headers)
-        .json(&serde_json::json!({
-            "model": &config.ai.model,
-            "max_tokens": config.ai.max_tokens,
-            "system": "You are Spren, a helpful command-line assistant. Provide clear and concise explanations.",
-            "messages": [{
-                "role": "user",
-                "content": prompt
-            }]
-        }))
-        .send()
-        .await?
-        .json::<AnthropicResponse>()
-        .await?;
+pub async fn get_explanation(command: &str, config: &Config) -> Result<String> {
+    let shell_type = ShellType::detect();
+    let prompt = format!(
+        "Explain what this {} command does, going through each part (programs, flags, \
+         pipes, redirections). Mention anything it changes or deletes. Be brief.\nCommand: {}",
+        shell_type.get_shell_name(), command
+    );
+    complete(EXPLAIN_SYSTEM, &prompt, config).await
+}
 
-    Ok(response.content[0].text.trim().to_string())
+/// Sends one request to the configured provider. The user can cancel it
+/// while it is in flight.
+async fn complete(system: &str, prompt: &str, config: &Config) -> Result<String> {
+    let request = async {
+        match config.ai.provider {
+            AIProvider::Anthropic => anthropic_complete(system, prompt, config).await,
+            AIProvider::OpenAI => openai_complete(system, prompt, config).await,
+        }
+    };
+    interrupt::cancellable("Waiting for the model", request).await
 }
 
-async fn get_anthropic_command(query: &str, config: &Config) -> Result<(String, bool)> {
+async fn anthropic_complete(system: &str, prompt: &str, config: &Config) -> Result<String> {
     let api_key = config.ai.anthropic_api_key.as_ref()
-        .ok_or_else(|| anyhow!("Anthropic API key not configured"))?;
+        .ok_or_else(|| anyhow!("Anthropic API key not configured: set ANTHROPIC_API_KEY, add it to a .env file, the keyring or api_key_command"))?;
 
     let client = reqwest::Client::new();
     let mut headers = HeaderMap::new();
@@ -92,23 +127,13 @@ async fn get_anthropic_command(query: &str, config: &Config) -> Result<(String,
     headers.insert("x-api-key", HeaderValue::from_str(api_key)?);
     headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
 
-    let shell_type = ShellType::detect();
-    let shell_name = shell_type.get_shell_name();
-
-    let prompt = format!(
-        "Convert this natural language query into a {} command: '{}'. \
-         Also analyze if this command could be dangerous (e.g., system-wide deletions, \
-         format operations, etc). You must respond in exactly this format:\nDANGEROUS: true/false\nCOMMAND: <command>",
-        shell_name, query
-    );
-
     let response = client
         .post("https://api.anthropic.com/v1/messages")
         .headers(headers)
         .json(&serde_json::json!({
             "model": &config.ai.model,
             "max_tokens": config.ai.max_tokens,
-            "system": "You are Spren, a helpful command-line assistant. Respond only in the specified format.",
+            "system": system,
             "messages": [{
                 "role": "user",
                 "content": prompt
@@ -119,28 +144,20 @@ async fn get_anthropic_command(query: &str, config: &Config) -> Result<(String,
         .json::<AnthropicResponse>()
         .await?;
 
-    parse_ai_response(&response.content[0].text)
+    let content = response.content.first()
+        .ok_or_else(|| anyhow!("Anthropic response contained no content"))?;
+    Ok(content.text.trim().to_string())
 }
 
-async fn get_openai_command(query: &str, config: &Config) -> Result<(String, bool)> {
+async fn openai_complete(system: &str, prompt: &str, config: &Config) -> Result<String> {
     let api_key = config.ai.openai_api_key.as_ref()
-        .ok_or_else(|| anyhow!("OpenAI API key not configured"))?;
+        .ok_or_else(|| anyhow!("OpenAI API key not configured: set OPENAI_API_KEY, add it to a .env file, the keyring or api_key_command"))?;
 
     let client = reqwest::Client::new();
     let mut headers = HeaderMap::new();
     headers.insert(AUTHORIZATION, HeaderValue::from_str(&format!("Bearer {}", api_key))?);
     headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
 
-    let shell_type = ShellType::detect();
-    let shell_name = shell_type.get_shell_name();
-
-    let prompt = format!(
-        "Convert this natural language query into a {} command: '{}'. \
-         Also analyze if this command could be dangerous (e.g., system-wide deletions, \
-         format operations, etc). You must respond in exactly this format:\nDANGEROUS: true/false\nCOMMAND: <command>",
-        shell_name, query
-    );
-
     let response = client
         .post("https://api.openai.com/v1/chat/completions")
         .headers(headers)
@@ -150,7 +167,7 @@ async fn get_openai_command(query: &str, config: &Config) -> Result<(String, boo
             "messages": [
                 {
                     "role": "system",
-                    "content": "You are Spren, a helpful command-line assistant. Respond only in the specified format."
+                    "content": system
                 },
                 {
                     "role": "user",
@@ -163,69 +180,757 @@ async fn get_openai_command(query: &str, config: &Config) -> Result<(String, boo
         .json::<OpenAIResponse>()
         .await?;
 
-    parse_ai_response(&response.choices[0].message.content)
+    let choice = response.choices.first()
+        .ok_or_else(|| anyhow!("OpenAI response contained no choices"))?;
+    Ok(choice.message.content.trim().to_string())
 }
 
-async fn get_openai_error(command: &str, stdout: &str, stderr: &str, config: &Config) -> Result<String> {
-    let api_key = config.ai.openai_api_key.as_ref()
-        .ok_or_else(|| anyhow!("OpenAI API key not configured"))?;
+fn command_prompt(query: &Query, shell_name: &str, avoid_privileges: bool) -> String {
+    let mut prompt = format!(
+        "Convert this natural language query into a {} command: '{}'. \
+         Also rate its risk as one of: read-only (only reads or lists), modifying (creates or changes \
+         files or settings), destructive (deletes or overwrites data), system-critical (can break the \
+         system, e.g. system-wide deletions, disk formatting, shutdown). \
+         You must respond in exactly this format:\nRISK: <level>\nCOMMAND: <command>\n\
+         If the command spans several lines (heredocs, loops), put it in a fenced code block on the lines after COMMAND:.\n\
+         If it is interactive or takes over the screen (an editor, pager, live monitor, ssh session or REPL), \
+         add the line TERMINAL: yes before COMMAND:.\n\
+         Mark every value the user must supply as {{{{?name}}}}, or {{{{?name:path}}}} / {{{{?name:branch}}}} for \
+         file paths and git branches, adding =default when there is a sensible default \
+         (e.g. {{{{?file:path=README.md}}}}). Never invent example values like /path/to/file.\n\
+         If the query is too ambiguous to answer safely, respond instead with one line per question:\n\
+         CLARIFY: <question>\nCHOICES: <option> | <option>  (optional, after its question)",
+        shell_name, query.text
+    );
+    if avoid_privileges {
+        prompt.push_str(
+            "\nDo not use sudo, su, doas, pkexec or any other way of gaining root. Prefer a solution that \
+             works as the current user, e.g. installing into the home directory.",
+        );
+    }
+    if !query.answers.is_empty() {
+        prompt.push_str("\n\nThe user answered your clarifying questions:");
+        for answer in &query.answers {
+            prompt.push_str(&format!("\n- {} {}", answer.question, answer.answer));
+        }
+        prompt.push_str("\nUse these answers and respond with a command.");
+    }
+    if !query.feedback.is_empty() {
+        prompt.push_str("\n\nThe user rejected earlier suggestions:");
+        for feedback in &query.feedback {
+            prompt.push_str(&format!("\n- `{}`: {}", feedback.command, feedback.note));
+        }
+        prompt.push_str("\nSuggest a different command that addresses this feedback.");
+    }
+    prompt
+}
 
-    let client = reqwest::Client::new();
-    let mut headers = HeaderMap::new();
-    headers.insert(AUTHORIZATION, HeaderValue::from_str(&format!("Bearer {}", api_key))?);
-    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+fn parse_ai_response(response: &str) -> Result<Suggestion> {
+    let response = strip_outer_fence(&response.replace("\r\n", "\n"));
+    let lines: Vec<&str> = response.trim().lines().collect();
 
-    let shell_type = ShellType::detect();
-    let shell_name = shell_type.get_shell_name();
+    // A concrete command always wins over questions the model also asked.
+    if !lines.iter().any(|line| header_value(line, "COMMAND").is_some()) {
+        let clarifications = parse_clarifications(&lines);
+        if !clarifications.is_empty() {
+            return Ok(Suggestion::Clarify(clarifications));
+        }
+    }
 
-    let prompt = format!(
-        "Analyze this {} command result:\nCommand: {}\nStdout: {}\nStderr: {}\n\
-         Explain what happened and suggest improvements. Be specific and brief.",
-        shell_name, command, stdout, stderr
-    );
+    // Older prompts asked for `DANGEROUS: true/false`; still accept that
+    let risk = match lines.iter().find_map(|line| header_value(line, "RISK")) {
+        Some(value) => parse_risk(value),
+        None => lines.iter()
+            .find_map(|line| header_value(line, "DANGEROUS"))
+            .map(parse_dangerous_flag)
+            .ok_or_else(|| anyhow!("Could not find RISK line in response"))?,
+    };
+
+    let command_index = lines.iter()
+        .position(|line| header_value(line, "COMMAND").is_some())
+        .ok_or_else(|| anyhow!("Could not find COMMAND line in response"))?;
 
-    let response = client
-        .post("https://api.openai.com/v1/chat/completions")
-        .headers(headers)
-        .json(&serde_json::json!({
-            "model": &config.ai.model,
-            "max_tokens": config.ai.max_tokens,
-            "messages": [
-                {
-                    "role": "system",
-                    "content": "You are Spren, a helpful command-line assistant. Provide clear and concise explanations."
-                },
+    let command = extract_command(&lines, command_index)?;
+    validate_command(&command)?;
+    let terminal = lines.iter()
+        .find_map(|line| header_value(line, "TERMINAL"))
+        .is_some_and(|value| matches!(trim_value(value).to_ascii_lowercase().as_str(), "yes" | "true"));
+
+    Ok(Suggestion::Command { command, risk, terminal })
+}
+
+fn parse_clarifications(lines: &[&str]) -> Vec<Clarification> {
+    let mut clarifications: Vec<Clarification> = Vec::new();
+    for line in lines {
+        if let Some(question) = header_value(line, "CLARIFY") {
+            if !question.is_empty() {
+                clarifications.push(Clarification { question: question.to_string(), choices: Vec::new() });
+            }
+        } else if let Some(choices) = header_value(line, "CHOICES") {
+            if let Some(last) = clarifications.last_mut() {
+                last.choices = choices
+                    .split('|')
+                    .map(|choice| choice.trim().to_string())
+                    .filter(|choice| !choice.is_empty())
+                    .collect();
+            }
+        }
+    }
+    clarifications
+}
+
+/// Returns the text after `KEY:` if `line` is a protocol header for `key`.
+/// Tolerates markdown decoration such as `**COMMAND:**` or `- COMMAND:`.
+fn header_value<'a>(line: &'a str, key: &str) -> Option<&'a str> {
+    let line = line.trim_start().trim_start_matches(['-', '*', '#', '>', ' ']);
+    let head = line.get(..key.len())?;
+    if !head.eq_ignore_ascii_case(key) {
+        return None;
+    }
+    let rest = line[key.len()..].trim_start_matches('*');
+    let rest = rest.strip_prefix(':')?;
+    Some(rest.trim_start_matches('*').trim())
+}
+
+fn is_header(line: &str) -> bool {
+    ["RISK", "DANGEROUS", "TERMINAL", "COMMAND", "CLARIFY", "CHOICES"].iter().any(|key| header_value(line, key).is_some())
+}
+
+fn trim_value(value: &str) -> &str {
+    value.trim_matches(|c: char| c == '`' || c == '*' || c == '.' || c.is_whitespace())
+}
+
+/// An unrecognised level is treated as destructive so a malformed answer
+/// never lowers the safety level.
+fn parse_risk(value: &str) -> RiskLevel {
+    RiskLevel::from_label(trim_value(value)).unwrap_or(RiskLevel::Destructive)
+}
+
+/// Maps the legacy flag: "false"/"no" could still modify files, anything
+/// else is destructive.
+fn parse_dangerous_flag(value: &str) -> RiskLevel {
+    let value = trim_value(value);
+    if value.eq_ignore_ascii_case("false") || value.eq_ignore_ascii_case("no") {
+        RiskLevel::Modifying
+    } else {
+        RiskLevel::Destructive
+    }
+}
+
+/// Removes a fence wrapping the whole response, e.g. a model that answers the
+/// protocol inside a single ```text block.
+fn strip_outer_fence(response: &str) -> String {
+    let trimmed = response.trim();
+    let lines: Vec<&str> = trimmed.lines().collect();
+    if lines.len() >= 2
+        && is_fence(lines[0])
+        && is_fence(lines[lines.len() - 1])
+        && lines[1..lines.len() - 1].iter().any(|line| is_header(line))
+        && !lines[1..lines.len() - 1].iter().any(|line| is_fence(line))
+    {
+        return lines[1..lines.len() - 1].join("\n");
+    }
+    trimmed.to_string()
+}
+
+fn is_fence(line: &str) -> bool {
+    let line = line.trim();
+    line.starts_with("```") && !line[3..].contains('`')
+}
+
+/// Collects the command starting at the COMMAND header. A fenced block is taken
+/// verbatim; otherwise following lines are only consumed while the shell
+/// syntax is still open (heredoc, quote, continuation, loop body, ...), which
+/// keeps trailing chatter out of the command.
+fn extract_command(lines: &[&str], index: usize) -> Result<String> {
+    let inline = header_value(lines[index], "COMMAND").unwrap_or_default();
+    let rest = &lines[index + 1..];
+
+    if inline.is_empty() || is_fence(inline) {
+        // COMMAND:\n```bash\n...\n``` or COMMAND: ```bash\n...\n```
+        let body_start = if is_fence(inline) {
+            0
+        } else {
+            match rest.iter().position(|line| !line.trim().is_empty()) {
+                Some(i) if is_fence(rest[i]) => i + 1,
+                Some(i) => return collect_unfenced(&rest[i..]),
+                None => return Err(anyhow!("COMMAND line in response is empty")),
+            }
+        };
+        let body = &rest[body_start..];
+        let end = body.iter()
+            .position(|line| is_fence(line))
+            .ok_or_else(|| anyhow!("Unterminated code fence in COMMAND block"))?;
+        return Ok(body[..end].join("\n").trim_matches('\n').to_string());
+    }
+
+    if let Some(fenced) = inline.strip_prefix("```") {
+        // Single-line fence: COMMAND: ```ls -la```
+        if let Some(body) = fenced.strip_suffix("```") {
+            return Ok(strip_language_tag(body).trim().to_string());
+        }
+    }
+
+    let mut block = vec![inline];
+    block.extend_from_slice(rest);
+    collect_unfenced(&block)
+}
+
+fn collect_unfenced(lines: &[&str]) -> Result<String> {
+    let mut command = String::new();
+    for line in lines {
+        if !command.is_empty() {
+            if is_header(line) || is_fence(line) {
+                break;
+            }
+            command.push('\n');
+        }
+        command.push_str(line);
+        if scan_shell(&command).is_complete() {
+            break;
+        }
+    }
+    Ok(strip_inline_backticks(command.trim()).to_string())
+}
+
+fn strip_language_tag(body: &str) -> &str {
+    for tag in ["bash ", "sh ", "shell ", "zsh ", "powershell ", "cmd "] {
+        if let Some(rest) = body.strip_prefix(tag) {
+            return rest;
+        }
+    }
+    body
+}
+
+/// `ls -la` written as inline markdown code.
+fn strip_inline_backticks(command: &str) -> &str {
+    match command.strip_prefix('`').and_then(|c| c.strip_suffix('`')) {
+        Some(inner) if !inner.is_empty() && !inner.contains('`') => inner,
+        _ => command,
+    }
+}
+
+fn validate_command(command: &str) -> Result<()> {
+    if command.trim().is_empty() {
+        return Err(anyhow!("COMMAND line in response is empty"));
+    }
+    if command.trim() == "<command>" {
+        return Err(anyhow!("Response repeated the format template instead of a command"));
+    }
+    if let Some(c) = command.chars().find(|c| c.is_control() && *c != '\n' && *c != '\t') {
+        return Err(anyhow!("Command contains control character {:?}", c));
+    }
+    let state = scan_shell(command);
+    if !state.is_complete() {
+        return Err(anyhow!("Command in response is incomplete: {}", state.describe()));
+    }
+    Ok(())
+}
+
+/// Open constructs left over after lexing a (possibly partial) Bash command.
+#[derive(Debug, Default)]
+struct ShellScan {
+    quote: Option<char>,
+    trailing_escape: bool,
+    trailing_operator: bool,
+    parens: usize,
+    braces: usize,
+    heredocs: Vec<(String, bool)>,
+    blocks: Vec<&'static str>,
+}
+
+impl ShellScan {
+    fn is_complete(&self) -> bool {
+        self.quote.is_none()
+            && !self.trailing_escape
+            && !self.trailing_operator
+            && self.parens == 0
+            && self.braces == 0
+            && self.heredocs.is_empty()
+            && self.blocks.is_empty()
+    }
+
+    fn describe(&self) -> String {
+        if let Some(q) = self.quote {
+            format!("unterminated {} quote", q)
+        } else if let Some((delim, _)) = self.heredocs.first() {
+            format!("heredoc missing terminator '{}'", delim)
+        } else if let Some(kw) = self.blocks.last() {
+            format!("missing '{}'", kw)
+        } else if self.parens > 0 {
+            "unbalanced parentheses".to_string()
+        } else if self.braces > 0 {
+            "unbalanced braces".to_string()
+        } else {
+            "line continues past end of response".to_string()
+        }
+    }
+}
+
+fn is_number(word: &str) -> bool {
+    !word.is_empty() && word.chars().all(|c| c.is_ascii_digit())
+}
+
+/// Lightweight Bash lexer used to decide whether a command spans more lines.
+fn scan_shell(text: &str) -> ShellScan {
+    let mut state = ShellScan::default();
+    let mut lines = text.split('\n').peekable();
+
+    while let Some(line) = lines.next() {
+        let chars: Vec<char> = line.chars().collect();
+        let mut i = 0;
+        let mut word = String::new();
+        let mut command_position = state.quote.is_none() && !state.trailing_escape;
+        let mut pending_heredocs = Vec::new();
+        state.trailing_escape = false;
+
+        let flush = |word: &mut String, command_position: &mut bool, state: &mut ShellScan| {
+            if word.is_empty() {
+                return;
+            }
+            if *command_position {
+                match word.as_str() {
+                    "if" => state.blocks.push("fi"),
+                    "case" => state.blocks.push("esac"),
+                    "for" | "while" | "until" | "select" => state.blocks.push("done"),
+                    "fi" | "esac" | "done" if state.blocks.last() == Some(&word.as_str()) => {
+                        state.blocks.pop();
+                    }
+                    _ => {}
+                }
+                *command_position = matches!(
+                    word.as_str(),
+                    "then" | "do" | "else" | "elif" | "!" | "time" | "{" | "}" | "fi" | "done" | "esac"
+                );
+            }
+            state.trailing_operator = false;
+            word.clear();
+        };
+
+        while i < chars.len() {
+            let c = chars[i];
+            if let Some(q) = state.quote {
+                if c == '\\' && q != '\'' {
+                    i += 2;
+                    continue;
+                }
+                if c == q {
+                    state.quote = None;
+                }
+                word.push(c);
+                i += 1;
+                continue;
+            }
+            match c {
+                '\\' if i + 1 == chars.len() => {
+                    state.trailing_escape = true;
+                    i += 1;
+                }
+                '\\' => {
+                    word.push(c);
+                    if let Some(&n) = chars.get(i + 1) {
+                        word.push(n);
+                    }
+                    i += 2;
+                }
+                '\'' | '"' | '`' => {
+                    state.quote = Some(c);
+                    word.push(c);
+                    i += 1;
+                }
+                '#' if word.is_empty() => break,
+                ' ' | '\t' => {
+                    flush(&mut word, &mut command_position, &mut state);
+                    i += 1;
+                }
+                ';' | '&' | '|' => {
+                    flush(&mut word, &mut command_position, &mut state);
+                    // `;;` inside case arms and `&&`/`||` all return to command position
+                    while i < chars.len() && matches!(chars[i], ';' | '&' | '|') {
+                        i += 1;
+                    }
+                    command_position = true;
+                    state.trailing_operator = c != ';';
+                }
+                '(' => {
+                    flush(&mut word, &mut command_position, &mut state);
+                    state.parens += 1;
+                    command_position = true;
+                    i += 1;
+                }
+                ')' => {
+                    flush(&mut word, &mut command_position, &mut state);
+                    // Case patterns like `foo)` close nothing we opened.
+                    if state.parens > 0 && state.blocks.last() != Some(&"esac") {
+                        state.parens -= 1;
+                    }
+                    command_position = true;
+                    i += 1;
+                }
+                // `1<<2` inside $(( )) is a shift, not a heredoc
+                '<' if chars.get(i + 1) == Some(&'<')
+                    && chars.get(i + 2) != Some(&'<')
+                    && !is_number(&word) =>
                 {
-                    "role": "user",
-                    "content": prompt
+                    flush(&mut word, &mut command_position, &mut state);
+                    i += 2;
+                    let strip_tabs = chars.get(i) == Some(&'-');
+                    if strip_tabs {
+                        i += 1;
+                    }
+                    while i < chars.len() && chars[i].is_whitespace() {
+                        i += 1;
+                    }
+                    let mut delim = String::new();
+                    while i < chars.len() && !chars[i].is_whitespace() && !";&|<>()".contains(chars[i]) {
+                        if !matches!(chars[i], '\'' | '"' | '\\') {
+                            delim.push(chars[i]);
+                        }
+                        i += 1;
+                    }
+                    if !delim.is_empty() && !is_number(&delim) {
+                        pending_heredocs.push((delim, strip_tabs));
+                    }
                 }
-            ]
-        }))
-        .send()
-        .await?
-        .json::<OpenAIResponse>()
-        .await?;
+                '{' | '}' if word.is_empty() && chars.get(i + 1).is_none_or(|n| n.is_whitespace() || *n == ';') => {
+                    if c == '{' {
+                        state.braces += 1;
+                    } else {
+                        state.braces = state.braces.saturating_sub(1);
+                    }
+                    command_position = true;
+                    state.trailing_operator = false;
+                    i += 1;
+                }
+                _ => {
+                    word.push(c);
+                    i += 1;
+                }
+            }
+        }
+        if state.quote.is_none() {
+            flush(&mut word, &mut command_position, &mut state);
+        }
+
+        // Heredoc bodies start on the next line and run until the delimiter.
+        for (delim, strip_tabs) in pending_heredocs {
+            let mut closed = false;
+            for body in lines.by_ref() {
+                let body = if strip_tabs { body.trim_start_matches('\t') } else { body };
+                if body == delim {
+                    closed = true;
+                    break;
+                }
+            }
+            if !closed {
+                state.heredocs.push((delim, strip_tabs));
+            }
+        }
+    }
 
-    Ok(response.choices[0].message.content.trim().to_string())
+    state
 }
 
-fn parse_ai_response(response: &str) -> Result<(String, bool)> {
-    let lines: Vec<&str> = response.trim().split('\n').collect();
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
 
-    let dangerous_line = lines.iter()
-        .find(|line| line.to_lowercase().contains("dangerous"))
-        .ok_or_else(|| anyhow!("Could not find DANGEROUS line in response"))?;
+    fn parse(response: &str) -> (String, RiskLevel) {
+        match parse_ai_response(response).unwrap() {
+            Suggestion::Command { command, risk, .. } => (command, risk),
+            other => panic!("expected a command, got {:?}", other),
+        }
+    }
 
-    let command_line = lines.iter()
-        .find(|line| line.to_lowercase().contains("command"))
-        .ok_or_else(|| anyhow!("Could not find COMMAND line in response"))?;
+    #[test]
+    fn test_simple_response() {
+        assert_eq!(parse("RISK: read-only\nCOMMAND: ls -la"), ("ls -la".to_string(), RiskLevel::ReadOnly));
+        assert_eq!(parse("RISK: destructive\nCOMMAND: rm -rf /tmp/x"), ("rm -rf /tmp/x".to_string(), RiskLevel::Destructive));
+        assert_eq!(parse("RISK: System-Critical\nCOMMAND: reboot").1, RiskLevel::SystemCritical);
+    }
+
+    #[test]
+    fn test_terminal_header() {
+        let terminal = |response: &str| match parse_ai_response(response).unwrap() {
+            Suggestion::Command { terminal, .. } => terminal,
+            other => panic!("expected a command, got {:?}", other),
+        };
+        assert!(terminal("RISK: read-only\nTERMINAL: yes\nCOMMAND: htop"));
+        assert!(terminal("RISK: read-only\nCOMMAND: htop\n**TERMINAL:** Yes"));
+        assert!(!terminal("RISK: read-only\nCOMMAND: ls"));
+    }
+
+    #[test]
+    fn test_fenced_command_block() {
+        let response = "DANGEROUS: false\nCOMMAND:\n```bash\nfor f in *.txt; do\n  echo \"$f\"\ndone\n```\n";
+        assert_eq!(parse(response).0, "for f in *.txt; do\n  echo \"$f\"\ndone");
+    }
+
+    #[test]
+    fn test_fence_opened_on_command_line() {
+        let response = "DANGEROUS: false\nCOMMAND: ```sh\ngit status\n```";
+        assert_eq!(parse(response).0, "git status");
+    }
+
+    #[test]
+    fn test_single_line_fence_and_inline_code() {
+        assert_eq!(parse("DANGEROUS: false\nCOMMAND: ```bash ls -la```").0, "ls -la");
+        assert_eq!(parse("DANGEROUS: false\nCOMMAND: `ls -la`").0, "ls -la");
+    }
+
+    #[test]
+    fn test_whole_response_fenced() {
+        let response = "```\nDANGEROUS: false\nCOMMAND: df -h\n```";
+        assert_eq!(parse(response), ("df -h".to_string(), RiskLevel::Modifying));
+    }
 
-    let is_dangerous = dangerous_line.to_lowercase().contains("true");
-    let command = command_line
-        .replace("COMMAND:", "")
-        .replace("Command:", "")
-        .trim()
-        .to_string();
+    #[test]
+    fn test_heredoc() {
+        let response = "DANGEROUS: false\nCOMMAND: cat <<'EOF' > notes.txt\nfirst line\n\nthird line\nEOF\nThis writes notes.txt.";
+        assert_eq!(parse(response).0, "cat <<'EOF' > notes.txt\nfirst line\n\nthird line\nEOF");
+    }
+
+    #[test]
+    fn test_heredoc_with_tab_stripping() {
+        let response = "DANGEROUS: false\nCOMMAND: cat <<-END\n\thello\n\tEND";
+        assert_eq!(parse(response).0, "cat <<-END\n\thello\n\tEND");
+    }
+
+    #[test]
+    fn test_line_continuation() {
+        let response = "DANGEROUS: false\nCOMMAND: docker run \\\n  --rm \\\n  alpine echo hi\nRuns a throwaway container.";
+        assert_eq!(parse(response).0, "docker run \\\n  --rm \\\n  alpine echo hi");
+    }
+
+    #[test]
+    fn test_unfenced_loop() {
+        let response = "DANGEROUS: false\nCOMMAND: for f in *.log\ndo\n  gzip \"$f\"\ndone\nThat compresses every log.";
+        assert_eq!(parse(response).0, "for f in *.log\ndo\n  gzip \"$f\"\ndone");
+    }
+
+    #[test]
+    fn test_multiline_quote() {
+        let response = "DANGEROUS: false\nCOMMAND: git commit -m 'first\nsecond'";
+        assert_eq!(parse(response).0, "git commit -m 'first\nsecond'");
+    }
+
+    #[test]
+    fn test_trailing_pipe_continues() {
+        let response = "DANGEROUS: false\nCOMMAND: ps aux |\n  grep nginx";
+        assert_eq!(parse(response).0, "ps aux |\n  grep nginx");
+    }
+
+    #[test]
+    fn test_chatty_response() {
+        let response = "Sure! Here is the command you asked for.\n\n\
+                        DANGEROUS: false\n\
+                        COMMAND: du -sh *\n\n\
+                        This command shows the size of every entry.";
+        assert_eq!(parse(response), ("du -sh *".to_string(), RiskLevel::Modifying));
+    }
+
+    #[test]
+    fn test_markdown_decorated_headers() {
+        let response = "**DANGEROUS:** true\n**COMMAND:** `shred -u secret.txt`";
+        assert_eq!(parse(response), ("shred -u secret.txt".to_string(), RiskLevel::Destructive));
+    }
+
+    #[test]
+    fn test_command_order_and_case_insensitive() {
+        let response = "command: echo hi\ndangerous: FALSE";
+        assert_eq!(parse(response), ("echo hi".to_string(), RiskLevel::Modifying));
+    }
+
+    #[test]
+    fn test_crlf_line_endings() {
+        assert_eq!(parse("RISK: read-only\r\nCOMMAND: pwd\r\n"), ("pwd".to_string(), RiskLevel::ReadOnly));
+    }
+
+    #[test]
+    fn test_words_in_command_do_not_match_headers() {
+        // The old parser matched any line containing "command" or "dangerous".
+        let response = "DANGEROUS: false\nCOMMAND: echo 'this command is not dangerous: true'";
+        assert_eq!(parse(response), ("echo 'this command is not dangerous: true'".to_string(), RiskLevel::Modifying));
+    }
+
+    #[test]
+    fn test_legacy_dangerous_flag() {
+        assert_eq!(parse("DANGEROUS: maybe\nCOMMAND: ls").1, RiskLevel::Destructive);
+        assert_eq!(parse("DANGEROUS: yes\nCOMMAND: ls").1, RiskLevel::Destructive);
+        assert_eq!(parse("DANGEROUS: no\nCOMMAND: ls").1, RiskLevel::Modifying);
+        assert_eq!(parse("DANGEROUS: `false`.\nCOMMAND: ls").1, RiskLevel::Modifying);
+    }
+
+    #[test]
+    fn test_unknown_risk_is_destructive() {
+        assert_eq!(parse("RISK: low\nCOMMAND: ls").1, RiskLevel::Destructive);
+        assert_eq!(parse("**RISK:** `read-only`\nCOMMAND: ls").1, RiskLevel::ReadOnly);
+        // RISK wins over a stray legacy flag
+        assert_eq!(parse("RISK: read-only\nDANGEROUS: true\nCOMMAND: ls").1, RiskLevel::ReadOnly);
+    }
+
+    #[test]
+    fn test_arithmetic_shift_is_not_heredoc() {
+        assert_eq!(parse("DANGEROUS: false\nCOMMAND: echo $((1<<4))").0, "echo $((1<<4))");
+        assert_eq!(parse("DANGEROUS: false\nCOMMAND: echo $(( 1 << 4 ))").0, "echo $(( 1 << 4 ))");
+    }
+
+    #[test]
+    fn test_case_statement() {
+        let response = "DANGEROUS: false\nCOMMAND: case \"$1\" in\n  a) echo a ;;\n  *) echo other ;;\nesac";
+        assert_eq!(parse(response).0, "case \"$1\" in\n  a) echo a ;;\n  *) echo other ;;\nesac");
+    }
+
+    #[test]
+    fn test_missing_lines_are_errors() {
+        assert!(parse_ai_response("COMMAND: ls").is_err());
+        assert!(parse_ai_response("DANGEROUS: false").is_err());
+        assert!(parse_ai_response("").is_err());
+        assert!(parse_ai_response("I can't help with that.").is_err());
+    }
+
+    #[test]
+    fn test_empty_or_template_command_is_error() {
+        assert!(parse_ai_response("DANGEROUS: false\nCOMMAND:").is_err());
+        assert!(parse_ai_response("DANGEROUS: false\nCOMMAND:   \n\n").is_err());
+        assert!(parse_ai_response("DANGEROUS: false\nCOMMAND: <command>").is_err());
+    }
+
+    #[test]
+    fn test_unterminated_constructs_are_errors() {
+        assert!(parse_ai_response("DANGEROUS: false\nCOMMAND:\n```bash\nls").is_err());
+        assert!(parse_ai_response("DANGEROUS: false\nCOMMAND: cat <<EOF\nno end").is_err());
+        assert!(parse_ai_response("DANGEROUS: false\nCOMMAND: echo 'open").is_err());
+        assert!(parse_ai_response("DANGEROUS: false\nCOMMAND: for i in 1 2; do echo $i").is_err());
+    }
+
+    #[test]
+    fn test_control_characters_rejected() {
+        assert!(parse_ai_response("DANGEROUS: false\nCOMMAND: ls\u{1b}[2J").is_err());
+        assert!(parse_ai_response("DANGEROUS: false\nCOMMAND: ls\u{0}rm").is_err());
+    }
+
+    #[test]
+    fn test_adversarial_second_command_header_is_ignored() {
+        let response = "DANGEROUS: false\nCOMMAND: ls\nCOMMAND: rm -rf ~";
+        assert_eq!(parse(response).0, "ls");
+    }
+
+    #[test]
+    fn test_fenced_block_stops_at_closing_fence() {
+        let response = "DANGEROUS: false\nCOMMAND:\n```\nls\n```\nrm -rf ~";
+        assert_eq!(parse(response).0, "ls");
+    }
+
+    #[test]
+    fn test_clarification_questions() {
+        let response = "CLARIFY: Which directory holds the logs?\n\
+                        CHOICES: /var/log | ./logs\n\
+                        CLARIFY: How old is old?";
+        assert_eq!(
+            parse_ai_response(response).unwrap(),
+            Suggestion::Clarify(vec![
+                Clarification {
+                    question: "Which directory holds the logs?".to_string(),
+                    choices: vec!["/var/log".to_string(), "./logs".to_string()],
+                },
+                Clarification { question: "How old is old?".to_string(), choices: Vec::new() },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_command_wins_over_clarification() {
+        let response = "CLARIFY: Which logs?\nDANGEROUS: false\nCOMMAND: ls /var/log";
+        assert_eq!(parse(response).0, "ls /var/log");
+    }
+
+    #[test]
+    fn test_choices_without_question_ignored() {
+        assert!(parse_ai_response("CHOICES: a | b").is_err());
+        assert!(parse_ai_response("CLARIFY:").is_err());
+    }
+
+    #[test]
+    fn test_prompt_includes_answers() {
+        let mut query = Query::new("delete the old logs");
+        assert!(!command_prompt(&query, "Bash", false).contains("answered"));
+        query.answers.push(Answer { question: "Which logs?".to_string(), answer: "/var/log".to_string() });
+        let prompt = command_prompt(&query, "Bash", false);
+        assert!(prompt.contains("- Which logs? /var/log"));
+        assert!(prompt.contains("{{?name:path}}"));
+    }
+
+    #[test]
+    fn test_prompt_includes_feedback() {
+        let mut query = Query::new("find big files");
+        query.feedback.push(Feedback { command: "find . -size +1G".to_string(), note: "use fd instead".to_string() });
+        let prompt = command_prompt(&query, "Bash", false);
+        assert!(prompt.contains("- `find . -size +1G`: use fd instead"));
+        assert!(!prompt.contains("sudo"));
+        assert!(command_prompt(&query, "Bash", true).contains("Do not use sudo"));
+    }
 
-    Ok((command, is_dangerous))
-}
\ No newline at end of file
+    fn simple_command() -> impl Strategy<Value = String> {
+        prop::collection::vec("[a-z][a-z0-9_./-]{0,8}", 1..6).prop_map(|words| words.join(" "))
+    }
+
+    proptest! {
+        #[test]
+        fn prop_never_panics(response in "\\PC*") {
+            let _ = parse_ai_response(&response);
+        }
+
+        #[test]
+        fn prop_never_panics_on_protocol_noise(
+            lines in prop::collection::vec(
+                prop_oneof![
+                    Just("DANGEROUS: true".to_string()),
+                    Just("COMMAND:".to_string()),
+                    Just("```".to_string()),
+                    Just("```bash".to_string()),
+                    Just("EOF".to_string()),
+                    Just("cat <<EOF".to_string()),
+                    Just("\\".to_string()),
+                    "[ -~]{0,20}",
+                ],
+                0..12,
+            )
+        ) {
+            let _ = parse_ai_response(&lines.join("\n"));
+        }
+
+        #[test]
+        fn prop_round_trips_in_every_format(
+            command in simple_command(),
+            risk in prop_oneof![
+                Just(RiskLevel::ReadOnly),
+                Just(RiskLevel::Modifying),
+                Just(RiskLevel::Destructive),
+                Just(RiskLevel::SystemCritical),
+            ],
+            style in 0..5usize,
+            chatter in "[A-Z][a-z ]{0,30}\\.",
+        ) {
+            let body = match style {
+                0 => format!("RISK: {}\nCOMMAND: {}", risk.label(), command),
+                1 => format!("RISK: {}\nCOMMAND:\n```bash\n{}\n```", risk.label(), command),
+                2 => format!("{}\nRISK: {}\nCOMMAND: `{}`\n{}", chatter, risk.label(), command, chatter),
+                3 => format!("```\nRISK: {}\nCOMMAND: {}\n```", risk.label(), command),
+                _ => format!("COMMAND: {}\n\n{}\nRISK: {}", command, chatter, risk.label()),
+            };
+            prop_assert_eq!(parse(&body), (command, risk));
+        }
+
+        #[test]
+        fn prop_heredoc_body_preserved(
+            body in prop::collection::vec("[a-z ]{0,20}", 0..6),
+            chatter in "[A-Z][a-z ]{0,30}\\.",
+        ) {
+            let command = format!("cat <<'EOF'\n{}\nEOF", body.join("\n"));
+            let response = format!("DANGEROUS: false\nCOMMAND: {}\n{}", command, chatter);
+            prop_assert_eq!(parse(&response).0, command);
+        }
+    }
+}
@@ -0,0 +1,148 @@
+// src/challenge.rs
+//! Picks the word a user must type to run a high-risk command: the thing
+//! the command destroys when we can tell what that is, otherwise a short
+//! random word, so the answer is never muscle memory.
+
+use crate::config::RiskLevel;
+use crate::parser::{self, SimpleCommand};
+use crate::safety::{self, Assessment};
+use crate::shell::ShellType;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+/// Programs whose last operand is the file or device they change.
+const FILE_TARGETS: &[&str] = &[
+    "rm", "rmdir", "unlink", "shred", "truncate", "mv", "cp", "chmod", "chown", "chgrp", "wipefs", "fdisk",
+    "sfdisk", "parted", "mkswap", "losetup",
+];
+
+/// Tools whose first operand is a subcommand and later ones name resources.
+const RESOURCE_TOOLS: &[&str] = &[
+    "kubectl", "helm", "docker", "podman", "git", "terraform", "systemctl", "gcloud", "aws", "az", "heroku",
+    "npm", "cargo", "zfs", "lvremove", "vgremove", "virsh", "dropdb", "psql", "mysql",
+];
+
+const WORDS: &[&str] = &[
+    "amber", "basil", "cedar", "delta", "ember", "fjord", "glade", "harbor", "indigo", "juniper", "kelp",
+    "lumen", "maple", "nectar", "orchid", "pebble", "quartz", "raven", "sable", "thistle", "umber", "violet",
+    "willow", "yarrow", "zephyr", "cobalt", "marble", "saffron", "tundra", "walnut",
+];
+
+/// Targets shorter than this are too easy to type by reflex (`/`, `~`, `.`).
+const MIN_LENGTH: usize = 3;
+const MAX_LENGTH: usize = 48;
+
+/// The challenge for `command`: its target path or resource name if one can
+/// be read from the parsed command, else a random word.
+pub fn challenge(command: &str, shell: &ShellType, assessment: &Assessment) -> String {
+    if *shell != ShellType::Bash {
+        return random_word();
+    }
+    target(command, assessment).unwrap_or_else(random_word)
+}
+
+fn target(command: &str, assessment: &Assessment) -> Option<String> {
+    let script = parser::parse(command).ok()?;
+    let commands = script.commands();
+    let risky: Vec<&str> = assessment
+        .findings
+        .iter()
+        .filter(|f| f.risk >= RiskLevel::Destructive)
+        .filter_map(|f| f.segment.as_deref())
+        .collect();
+
+    // The command a destructive finding names, else the first one that writes
+    let cmd = commands
+        .iter()
+        .find(|cmd| risky.contains(&cmd.to_string().as_str()))
+        .or_else(|| commands.iter().find(|cmd| !safety::is_read_only(cmd)))?;
+    target_of(cmd).filter(|word| typable(word))
+}
+
+fn target_of(cmd: &SimpleCommand) -> Option<String> {
+    let name = cmd.name();
+    if name == "dd" {
+        return cmd.args.iter().find_map(|w| w.text.strip_prefix("of=")).map(str::to_string);
+    }
+    if FILE_TARGETS.contains(&name) || name.starts_with("mkfs") {
+        return cmd.operands().last().map(|w| w.text.clone());
+    }
+    if RESOURCE_TOOLS.contains(&name) {
+        // Skip the subcommand and anything that may be an option's value:
+        // `kubectl delete ns web --context prod` names `web`
+        let mut previous_is_option = false;
+        let mut candidates = Vec::new();
+        for word in &cmd.args {
+            let text = word.text.as_str();
+            if text.starts_with('-') {
+                previous_is_option = !text.contains('=');
+                continue;
+            }
+            if !previous_is_option {
+                candidates.push(text);
+            }
+            previous_is_option = false;
+        }
+        return candidates.get(1..).and_then(|rest| rest.last()).map(|text| text.to_string());
+    }
+    None
+}
+
+fn typable(word: &str) -> bool {
+    (MIN_LENGTH..=MAX_LENGTH).contains(&word.chars().count())
+        && !word.chars().any(|c| c.is_whitespace() || c.is_control() || "*?[$`'\"\\".contains(c))
+}
+
+fn random_word() -> String {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(crate::history::now());
+    WORDS[hasher.finish() as usize % WORDS.len()].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{RiskAction, RiskPolicy, SecurityConfig};
+    use crate::paths::Context;
+
+    fn challenge_for(command: &str) -> String {
+        let security = SecurityConfig {
+            dangerous_commands: Default::default(),
+            require_confirmation: true,
+            max_output_size: 1024,
+            allowed_directories: vec![],
+            disable_dangerous_commands: false,
+            risk_policy: RiskPolicy::default(),
+            outside_allowed_directories: RiskAction::Typed,
+            trial_before_run: false,
+            privileged_commands: Default::default(),
+            child_environment: Default::default(),
+            resource_limits: Default::default(),
+            command_timeout_secs: None,
+            disable_dangerous_locked: false,
+        };
+        let context = Context { cwd: "/home/me".into(), home: Some("/home/me".into()) };
+        let assessment = safety::analyze(command, &ShellType::Bash, &security, &context);
+        challenge(command, &ShellType::Bash, &assessment)
+    }
+
+    #[test]
+    fn test_targets() {
+        assert_eq!(challenge_for("ls && rm -rf build/cache"), "build/cache");
+        assert_eq!(challenge_for("sudo dd if=disk.img of=/dev/sdb bs=4M"), "/dev/sdb");
+        assert_eq!(challenge_for("sudo mkfs.ext4 /dev/sdb1"), "/dev/sdb1");
+        assert_eq!(challenge_for("kubectl delete ns web --context prod-eu"), "web");
+        assert_eq!(challenge_for("git push --force origin main"), "main");
+        assert_eq!(challenge_for("docker volume rm pgdata"), "pgdata");
+    }
+
+    #[test]
+    fn test_falls_back_to_random_word() {
+        for command in ["rm -rf /", "rm *.log", "terraform destroy", "echo 'x' | bash"] {
+            let word = challenge_for(command);
+            assert!(WORDS.contains(&word.as_str()), "{} -> {}", command, word);
+        }
+        let assessment = Assessment { risk: RiskLevel::Destructive, findings: vec![] };
+        assert!(WORDS.contains(&challenge("Remove-Item x", &ShellType::PowerShell, &assessment).as_str()));
+    }
+}
@@ -2,7 +2,7 @@ use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use dirs::home_dir;
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -11,6 +11,10 @@ pub struct Config {
     pub security: SecurityConfig,
     pub display: DisplayConfig,
     pub shell: ShellConfig,
+    #[serde(default)]
+    pub undo: UndoConfig,
+    #[serde(default)]
+    pub audit: AuditConfig,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -18,6 +22,10 @@ pub struct AIConfig {
     pub provider: AIProvider,
     pub anthropic_api_key: Option<String>,
     pub openai_api_key: Option<String>,
+    /// Prints the API key, e.g. `pass show anthropic`. Used when neither the
+    /// environment, a `.env` file nor the keyring has one.
+    #[serde(default)]
+    pub api_key_command: Option<String>,
     pub model: String,
     pub max_tokens: u32,
     pub temperature: f32,
@@ -34,9 +42,198 @@ pub enum AIProvider {
 pub struct SecurityConfig {
     pub dangerous_commands: HashSet<String>,
     pub require_confirmation: bool,
+    /// Bytes of each output stream kept for error analysis. Output is
+    /// always shown in full as it arrives.
     pub max_output_size: usize,
     pub allowed_directories: Vec<String>,
     pub disable_dangerous_commands: bool,
+    #[serde(default)]
+    pub risk_policy: RiskPolicy,
+    /// Required before running a command that writes outside `allowed_directories`.
+    #[serde(default = "default_outside_allowed_directories")]
+    pub outside_allowed_directories: RiskAction,
+    /// Try modifying commands in a sandbox before asking to run them (Linux only).
+    #[serde(default)]
+    pub trial_before_run: bool,
+    /// What to do about suggestions that use `sudo`, `su`, `doas` or `pkexec`.
+    #[serde(default)]
+    pub privileged_commands: PrivilegeMode,
+    /// Which environment variables suggested commands inherit.
+    #[serde(default)]
+    pub child_environment: ChildEnvironment,
+    #[serde(default)]
+    pub resource_limits: ResourceLimits,
+    /// Stops a command that is still running after this many seconds.
+    #[serde(default)]
+    pub command_timeout_secs: Option<u64>,
+    /// Set when the system config fixes `disable_dangerous_commands`.
+    #[serde(skip)]
+    pub disable_dangerous_locked: bool,
+}
+
+fn default_outside_allowed_directories() -> RiskAction {
+    RiskAction::Typed
+}
+
+/// How much damage a command can do, from least to most severe.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub enum RiskLevel {
+    ReadOnly,
+    Modifying,
+    Destructive,
+    SystemCritical,
+}
+
+impl RiskLevel {
+    pub fn label(&self) -> &'static str {
+        match self {
+            RiskLevel::ReadOnly => "read-only",
+            RiskLevel::Modifying => "modifying",
+            RiskLevel::Destructive => "destructive",
+            RiskLevel::SystemCritical => "system-critical",
+        }
+    }
+
+    pub fn from_label(label: &str) -> Option<Self> {
+        match label.trim().to_lowercase().replace(['_', ' '], "-").as_str() {
+            "read-only" | "readonly" => Some(RiskLevel::ReadOnly),
+            "modifying" => Some(RiskLevel::Modifying),
+            "destructive" => Some(RiskLevel::Destructive),
+            "system-critical" => Some(RiskLevel::SystemCritical),
+            _ => None,
+        }
+    }
+}
+
+/// How suggestions that need root are handled. They always need explicit
+/// confirmation, whatever `require_confirmation` says.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PrivilegeMode {
+    /// Suggest privileged commands when the task needs them.
+    #[default]
+    Allow,
+    /// Ask the model for solutions that run as the current user.
+    Avoid,
+    /// Remove `sudo`-style prefixes and report that root is needed.
+    Strip,
+}
+
+/// Limits applied to every command spren runs and everything it starts
+/// (Unix only). Unset fields keep the limit spren itself runs with.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ResourceLimits {
+    /// CPU time per process, in seconds.
+    pub cpu_seconds: Option<u64>,
+    /// Address space per process, in megabytes.
+    pub memory_mb: Option<u64>,
+    /// Open file descriptors per process.
+    pub open_files: Option<u64>,
+    /// Processes the user may have in total; the kernel counts all of them,
+    /// not just the command's, so leave headroom.
+    pub processes: Option<u64>,
+    /// Largest file a command may write, in megabytes.
+    pub file_size_mb: Option<u64>,
+}
+
+/// The environment suggested commands run with. spren's own API key
+/// variables are always removed, whatever the mode.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ChildEnvironment {
+    pub mode: EnvironmentMode,
+    /// Removed in `denylist` mode. Names or glob patterns, e.g. `*_TOKEN`.
+    pub deny: Vec<String>,
+    /// The only variables passed in `allowlist` mode. Names or glob patterns.
+    pub allow: Vec<String>,
+}
+
+impl Default for ChildEnvironment {
+    fn default() -> Self {
+        ChildEnvironment {
+            mode: EnvironmentMode::Denylist,
+            deny: Vec::new(),
+            allow: [
+                "PATH", "HOME", "USER", "LOGNAME", "SHELL", "TERM", "LANG", "LC_*", "TZ", "PWD", "TMPDIR",
+                "DISPLAY", "WAYLAND_DISPLAY", "XDG_RUNTIME_DIR", "SystemRoot", "windir", "COMSPEC", "PATHEXT",
+                "USERPROFILE", "APPDATA", "LOCALAPPDATA", "TEMP", "TMP",
+            ]
+            .iter()
+            .map(|name| name.to_string())
+            .collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EnvironmentMode {
+    /// Pass everything except `deny`.
+    #[default]
+    Denylist,
+    /// Pass only `allow`.
+    Allowlist,
+}
+
+/// What the REPL requires before running a command of a given risk level.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum RiskAction {
+    /// Run without asking, unless `require_confirmation` is set.
+    #[serde(alias = "allow")]
+    Auto,
+    /// Ask before running.
+    Confirm,
+    /// Ask, then make the user type the command's target (a path or
+    /// resource name) or, failing that, a random word.
+    Typed,
+    /// Never run.
+    #[serde(alias = "deny")]
+    Block,
+}
+
+impl RiskAction {
+    /// The word policy rules use for this action.
+    pub fn rule_label(&self) -> &'static str {
+        match self {
+            RiskAction::Auto => "allow",
+            RiskAction::Confirm => "confirm",
+            RiskAction::Typed => "typed",
+            RiskAction::Block => "deny",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RiskPolicy {
+    pub read_only: RiskAction,
+    pub modifying: RiskAction,
+    pub destructive: RiskAction,
+    pub system_critical: RiskAction,
+}
+
+impl Default for RiskPolicy {
+    fn default() -> Self {
+        RiskPolicy {
+            read_only: RiskAction::Auto,
+            modifying: RiskAction::Confirm,
+            destructive: RiskAction::Typed,
+            system_critical: RiskAction::Typed,
+        }
+    }
+}
+
+impl RiskPolicy {
+    pub fn action_for(&self, risk: RiskLevel) -> RiskAction {
+        match risk {
+            RiskLevel::ReadOnly => self.read_only,
+            RiskLevel::Modifying => self.modifying,
+            RiskLevel::Destructive => self.destructive,
+            RiskLevel::SystemCritical => self.system_critical,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -57,6 +254,55 @@ pub struct ShellConfig {
     pub enable_auto_correction: bool,
 }
 
+/// Snapshots taken before commands run, so `spren undo` can restore files.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UndoConfig {
+    pub enabled: bool,
+    /// Skip the snapshot when the files a command touches are larger than this.
+    pub max_snapshot_size: u64,
+    /// Oldest snapshots are dropped beyond these limits.
+    pub max_snapshots: usize,
+    pub max_store_size: u64,
+    pub max_age_days: u64,
+}
+
+impl Default for UndoConfig {
+    fn default() -> Self {
+        UndoConfig {
+            enabled: true,
+            max_snapshot_size: 100 * 1024 * 1024, // 100MB
+            max_snapshots: 50,
+            max_store_size: 500 * 1024 * 1024, // 500MB
+            max_age_days: 14,
+        }
+    }
+}
+
+/// The hash-chained log of every command spren suggested and what became of it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AuditConfig {
+    pub enabled: bool,
+    /// Defaults to `audit.jsonl` in the data directory.
+    pub path: Option<PathBuf>,
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self {
+        AuditConfig { enabled: true, path: None }
+    }
+}
+
+impl AuditConfig {
+    pub fn log_path(&self) -> Result<PathBuf> {
+        match &self.path {
+            Some(path) => Ok(path.clone()),
+            None => Ok(get_data_dir()?.join("audit.jsonl")),
+        }
+    }
+}
+
 impl Config {
     pub fn load(config_path: &PathBuf) -> Result<Self> {
         let config_str = fs::read_to_string(config_path)?;
@@ -74,6 +320,7 @@ impl Config {
                 provider: AIProvider::Anthropic,
                 anthropic_api_key: Some("your-anthropic-api-key-here".to_string()),
                 openai_api_key: Some("your-openai-api-key-here".to_string()),
+                api_key_command: None,
                 model: "".to_string(),
                 max_tokens: 150,
                 temperature: 0.7,
@@ -106,6 +353,14 @@ impl Config {
                 max_output_size: 1024 * 1024, // 1MB
                 allowed_directories: vec!["~".to_string(), "./".to_string()],
                 disable_dangerous_commands: false,
+                risk_policy: RiskPolicy::default(),
+                outside_allowed_directories: default_outside_allowed_directories(),
+                trial_before_run: false,
+                privileged_commands: PrivilegeMode::Allow,
+                child_environment: ChildEnvironment::default(),
+                resource_limits: ResourceLimits::default(),
+                command_timeout_secs: None,
+                disable_dangerous_locked: false,
             },
             display: DisplayConfig {
                 show_execution_time: true,
@@ -121,10 +376,43 @@ impl Config {
                 history_size: 1000,
                 enable_auto_correction: true,
             },
+            undo: UndoConfig::default(),
+            audit: AuditConfig::default(),
         };
 
         let toml_string = toml::to_string_pretty(&default_config)?;
         fs::write(config_path, toml_string)?;
+        // It may hold an API key, so only the owner should read it
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(config_path, fs::Permissions::from_mode(0o600))?;
+        }
+        Ok(())
+    }
+
+    /// Applies settings from the administrator's system config, which the user
+    /// config cannot override. A missing file changes nothing; an unreadable
+    /// one is an error so a broken lock never silently unlocks.
+    pub fn apply_system_config(&mut self, system_path: &Path) -> Result<()> {
+        if !system_path.exists() {
+            return Ok(());
+        }
+        let system_str = fs::read_to_string(system_path)
+            .map_err(|e| anyhow::anyhow!("Could not read system config {:?}: {}", system_path, e))?;
+        let system: SystemConfig = toml::from_str(&system_str)
+            .map_err(|e| anyhow::anyhow!("Invalid system config {:?}: {}", system_path, e))?;
+
+        if let Some(disable) = system.security.disable_dangerous_commands {
+            self.security.disable_dangerous_commands = disable;
+            self.security.disable_dangerous_locked = true;
+        }
+        if let Some(enabled) = system.audit.enabled {
+            self.audit.enabled = enabled;
+        }
+        if let Some(path) = system.audit.path {
+            self.audit.path = Some(path);
+        }
         Ok(())
     }
 
@@ -135,11 +423,58 @@ impl Config {
     }
 }
 
+/// Settings an administrator can lock in the system config.
+#[derive(Debug, Default, Deserialize)]
+struct SystemConfig {
+    #[serde(default)]
+    security: SystemSecurityConfig,
+    #[serde(default)]
+    audit: SystemAuditConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct SystemSecurityConfig {
+    disable_dangerous_commands: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct SystemAuditConfig {
+    enabled: Option<bool>,
+    path: Option<PathBuf>,
+}
+
+pub fn get_system_config_path() -> PathBuf {
+    if cfg!(windows) {
+        let program_data = std::env::var("ProgramData").unwrap_or_else(|_| "C:\\ProgramData".to_string());
+        PathBuf::from(program_data).join("spren").join("config.toml")
+    } else {
+        PathBuf::from("/etc/spren/config.toml")
+    }
+}
+
+/// Policy rule files, the administrator's first so their rules win.
+pub fn get_policy_paths() -> Result<Vec<PathBuf>> {
+    let system = get_system_config_path().with_file_name("policy.toml");
+    let user = get_config_path()?.with_file_name("policy.toml");
+    Ok(vec![system, user])
+}
+
 pub fn get_config_path() -> Result<PathBuf> {
     let home = home_dir().ok_or_else(|| anyhow::anyhow!("Could not find home directory"))?;
     Ok(home.join(".config").join("spren").join("config.toml"))
 }
 
+/// Where spren keeps history and undo snapshots.
+pub fn get_data_dir() -> Result<PathBuf> {
+    match dirs::data_dir() {
+        Some(dir) => Ok(dir.join("spren")),
+        None => {
+            let home = home_dir().ok_or_else(|| anyhow::anyhow!("Could not find home directory"))?;
+            Ok(home.join(".local").join("share").join("spren"))
+        }
+    }
+}
+
 // Helper function to merge user config with defaults
 pub fn merge_with_defaults(user_config: Config) -> Config {
     // Implementation would merge any missing fields from default config
@@ -152,6 +487,21 @@ mod tests {
     use super::*;
     use tempfile::tempdir;
 
+    /// Drops a `[table]` and its keys, as in a config written by an older version.
+    fn remove_section(config: &str, header: &str) -> String {
+        let mut skipping = false;
+        let mut kept = Vec::new();
+        for line in config.lines() {
+            if line.starts_with('[') {
+                skipping = line.trim() == header;
+            }
+            if !skipping {
+                kept.push(line);
+            }
+        }
+        kept.join("\n")
+    }
+
     #[test]
     fn test_config_creation() -> Result<()> {
         let temp_dir = tempdir()?;
@@ -180,4 +530,58 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_system_config_locks_dangerous_commands() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let config_path = temp_dir.path().join("config.toml");
+        let system_path = temp_dir.path().join("system.toml");
+
+        Config::create_default(&config_path)?;
+        let mut config = Config::load(&config_path)?;
+
+        // No system config: the user's setting stands
+        config.apply_system_config(&system_path)?;
+        assert!(!config.security.disable_dangerous_commands);
+        assert!(!config.security.disable_dangerous_locked);
+
+        fs::write(&system_path, "[security]\ndisable_dangerous_commands = true\n")?;
+        config.apply_system_config(&system_path)?;
+        assert!(config.security.disable_dangerous_commands);
+        assert!(config.security.disable_dangerous_locked);
+
+        // The lock is not written back to the user config
+        assert!(!toml::to_string(&config)?.contains("locked"));
+
+        // Administrators can also force the audit log on and choose where it goes
+        config.audit.enabled = false;
+        fs::write(&system_path, "[audit]\nenabled = true\npath = \"/var/log/spren/audit.jsonl\"\n")?;
+        config.apply_system_config(&system_path)?;
+        assert!(config.audit.enabled);
+        assert_eq!(config.audit.log_path()?, PathBuf::from("/var/log/spren/audit.jsonl"));
+
+        fs::write(&system_path, "[security]\ndisable_dangerous_commands = \"yes\"\n")?;
+        assert!(config.apply_system_config(&system_path).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_risk_policy() -> Result<()> {
+        let temp_dir = tempdir()?;
+        let config_path = temp_dir.path().join("config.toml");
+
+        Config::create_default(&config_path)?;
+        let config_str = fs::read_to_string(&config_path)?;
+        assert!(config_str.contains("system_critical = \"typed\""));
+
+        // Configs written before risk levels existed still load with defaults
+        let config: Config = toml::from_str(&remove_section(&config_str, "[security.risk_policy]"))?;
+        assert_eq!(config.security.risk_policy.action_for(RiskLevel::ReadOnly), RiskAction::Auto);
+        assert_eq!(config.security.risk_policy.action_for(RiskLevel::SystemCritical), RiskAction::Typed);
+
+        assert!(RiskLevel::ReadOnly < RiskLevel::SystemCritical);
+        assert_eq!(RiskLevel::from_label("System_Critical"), Some(RiskLevel::SystemCritical));
+        Ok(())
+    }
 }
\ No newline at end of file
@@ -0,0 +1,198 @@
+// src/credentials.rs
+//! Resolves the API key for the configured provider. Sources are tried in
+//! order: the environment, a `.env` file, the secret-service keyring,
+//! `api_key_command`, and last the plaintext key in `config.toml`.
+
+use crate::config::{AIConfig, AIProvider};
+use anyhow::{anyhow, Result};
+use colored::*;
+use std::path::Path;
+use std::process::Command;
+
+/// Where a key came from, shown in verbose mode.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Source {
+    Environment,
+    DotEnv,
+    Keyring,
+    Command,
+    ConfigFile,
+}
+
+impl Source {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Source::Environment => "the environment",
+            Source::DotEnv => "a .env file",
+            Source::Keyring => "the keyring",
+            Source::Command => "api_key_command",
+            Source::ConfigFile => "config.toml",
+        }
+    }
+}
+
+/// Fills in the active provider's key from the first source that has one.
+/// Returns where it came from, or `None` if no source had a key.
+pub fn resolve(ai: &mut AIConfig, config_path: &Path) -> Result<Option<Source>> {
+    let (var, provider) = match ai.provider {
+        AIProvider::Anthropic => ("ANTHROPIC_API_KEY", "anthropic"),
+        AIProvider::OpenAI => ("OPENAI_API_KEY", "openai"),
+    };
+    let stored = match ai.provider {
+        AIProvider::Anthropic => ai.anthropic_api_key.take(),
+        AIProvider::OpenAI => ai.openai_api_key.take(),
+    }
+    .filter(|key| !is_placeholder(key));
+    if stored.is_some() {
+        warn_if_exposed(config_path);
+    }
+
+    let mut resolved = std::env::var(var).ok().filter(|key| !key.trim().is_empty()).map(|key| (key, Source::Environment));
+    if resolved.is_none() {
+        resolved = from_dotenv(var, config_path).map(|key| (key, Source::DotEnv));
+    }
+    if resolved.is_none() {
+        resolved = from_keyring(provider).map(|key| (key, Source::Keyring));
+    }
+    if resolved.is_none() {
+        if let Some(command) = &ai.api_key_command {
+            resolved = Some((from_command(command)?, Source::Command));
+        }
+    }
+    if resolved.is_none() {
+        resolved = stored.map(|key| (key, Source::ConfigFile));
+    }
+
+    let source = resolved.as_ref().map(|(_, source)| *source);
+    let key = resolved.map(|(key, _)| key);
+    match ai.provider {
+        AIProvider::Anthropic => ai.anthropic_api_key = key,
+        AIProvider::OpenAI => ai.openai_api_key = key,
+    }
+    Ok(source)
+}
+
+/// The value written by `create_default`, which is not a real key.
+fn is_placeholder(key: &str) -> bool {
+    key.trim().is_empty() || key.starts_with("your-")
+}
+
+/// `var` from a `.env` in the current directory or one of its parents, then
+/// from one next to the config file. The process environment is not changed,
+/// so the key never reaches the commands spren runs.
+// The iterator functions are deprecated in favour of ones that load the file
+// into the process environment, which is exactly what must not happen here.
+#[allow(deprecated)]
+fn from_dotenv(var: &str, config_path: &Path) -> Option<String> {
+    let nearest = dotenv::from_filename_iter(".env").ok();
+    let beside_config = config_path.parent().and_then(|dir| dotenv::from_path_iter(dir.join(".env")).ok());
+    nearest
+        .into_iter()
+        .chain(beside_config)
+        .find_map(|iter| iter.flatten().find(|(name, _)| name == var).map(|(_, value)| value))
+        .filter(|key| !key.trim().is_empty())
+}
+
+/// `secret-tool lookup service spren provider <provider>`, if `secret-tool`
+/// is installed and has an entry.
+fn from_keyring(provider: &str) -> Option<String> {
+    if !cfg!(target_os = "linux") {
+        return None;
+    }
+    let output = Command::new("secret-tool")
+        .args(["lookup", "service", "spren", "provider", provider])
+        .output()
+        .ok()?;
+    let key = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (output.status.success() && !key.is_empty()).then_some(key)
+}
+
+/// Runs `api_key_command` (e.g. `pass show anthropic`) and takes the first
+/// line of its output as the key.
+fn from_command(command: &str) -> Result<String> {
+    let output = if cfg!(windows) {
+        Command::new("cmd").args(["/C", command]).output()
+    } else {
+        Command::new("sh").args(["-c", command]).output()
+    }
+    .map_err(|e| anyhow!("Could not run api_key_command `{}`: {}", command, e))?;
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "api_key_command `{}` failed: {}",
+            command,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let key = stdout.lines().next().unwrap_or("").trim();
+    if key.is_empty() {
+        return Err(anyhow!("api_key_command `{}` printed no key", command));
+    }
+    Ok(key.to_string())
+}
+
+/// Warns when other users can read the file holding a plaintext key.
+fn warn_if_exposed(config_path: &Path) {
+    if is_world_readable(config_path) {
+        println!(
+            "{} {:?} holds a plaintext API key and is readable by every user. Run `chmod 600 {}` or move the \
+             key to ANTHROPIC_API_KEY/OPENAI_API_KEY, a .env file, the keyring or api_key_command.",
+            "Warning:".yellow().bold(),
+            config_path,
+            config_path.display()
+        );
+    }
+}
+
+#[cfg(unix)]
+fn is_world_readable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::metadata(path).is_ok_and(|meta| meta.permissions().mode() & 0o004 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_world_readable(_path: &Path) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_dotenv_beside_config() {
+        let dir = tempdir().unwrap();
+        fs::write(dir.path().join(".env"), "# keys\nOTHER=1\nSPREN_TEST_KEY=\"sk-from-dotenv\"\n").unwrap();
+        let config_path = dir.path().join("config.toml");
+        assert_eq!(from_dotenv("SPREN_TEST_KEY", &config_path).as_deref(), Some("sk-from-dotenv"));
+        assert_eq!(from_dotenv("SPREN_MISSING_KEY", &config_path), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_command_and_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        assert_eq!(from_command("printf 'sk-from-command\\nignored'").unwrap(), "sk-from-command");
+        assert!(from_command("exit 3").is_err());
+        assert!(from_command("true").is_err());
+
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(&path, "").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(is_world_readable(&path));
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        assert!(!is_world_readable(&path));
+    }
+
+    #[test]
+    fn test_placeholder_is_not_a_key() {
+        assert!(is_placeholder("your-anthropic-api-key-here"));
+        assert!(is_placeholder(" "));
+        assert!(!is_placeholder("sk-ant-api03-abc"));
+    }
+}
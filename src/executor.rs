@@ -1,16 +1,464 @@
 // src/executor.rs
 use anyhow::Result;
-use std::process::Command;
+use std::ffi::OsString;
+use std::fmt;
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use crate::config::{ChildEnvironment, EnvironmentMode, ResourceLimits, SecurityConfig};
+use crate::interrupt;
 use crate::shell::ShellType;
 
+/// Where spren reads its own API keys from. Suggested commands never see
+/// them, whatever `child_environment` says.
+const SPREN_CREDENTIALS: &[&str] = &["ANTHROPIC_API_KEY", "OPENAI_API_KEY"];
+
+/// How long a stopped command gets to exit before it is killed.
+const GRACE_PERIOD: Duration = Duration::from_secs(2);
+
+/// How long output already written gets to arrive after the shell exits.
+/// Background jobs can hold the pipes open indefinitely, so we stop
+/// reading then rather than waiting for them.
+const DRAIN_PERIOD: Duration = Duration::from_millis(250);
+
 pub struct CommandOutput {
     pub stdout: String,
     pub stderr: String,
     pub success: bool,
+    /// `None` when the process was killed by a signal.
+    pub exit_code: Option<i32>,
+    /// The resource limit that stopped the command, when one did.
+    pub limit: Option<Limit>,
+    /// Set when spren stopped the command itself.
+    pub stopped: Option<Stopped>,
+    /// Set when more output was shown than `max_output_size` let us keep.
+    pub truncated: bool,
+}
+
+/// Why spren stopped a command before it finished.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stopped {
+    Interrupted,
+    TimedOut(u64),
+}
+
+impl fmt::Display for Stopped {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stopped::Interrupted => write!(f, "interrupted"),
+            Stopped::TimedOut(secs) => write!(f, "timed out after {}s (security.command_timeout_secs)", secs),
+        }
+    }
+}
+
+/// A `security.resource_limits` entry a command ran into.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Limit {
+    Cpu(u64),
+    Memory(u64),
+    OpenFiles(u64),
+    Processes(u64),
+    FileSize(u64),
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Limit::Cpu(value) => write!(f, "ran out of CPU time (cpu_seconds = {})", value),
+            Limit::Memory(value) => write!(f, "ran out of memory (memory_mb = {})", value),
+            Limit::OpenFiles(value) => write!(f, "opened too many files (open_files = {})", value),
+            Limit::Processes(value) => write!(f, "started too many processes (processes = {})", value),
+            Limit::FileSize(value) => write!(f, "wrote a file that was too large (file_size_mb = {})", value),
+        }
+    }
 }
 
-pub async fn execute_command(command: &str) -> Result<CommandOutput> {
+/// Runs `command` in its own process group, so that Ctrl-C and the timeout
+/// reach everything it starts, and waits for it to finish or be stopped.
+/// Its output is shown as it arrives; the returned `CommandOutput` keeps up
+/// to `max_output_size` bytes of each stream.
+pub async fn execute_command(command: &str, security: &SecurityConfig) -> Result<CommandOutput> {
     let shell_type = ShellType::detect();
+    run(shell_command(command, &shell_type, security), &shell_type, security).await
+}
+
+/// Runs a command built by `shell_command` the way `execute_command` does,
+/// for callers that need to adjust it first.
+pub async fn run(mut cmd: Command, shell_type: &ShellType, security: &SecurityConfig) -> Result<CommandOutput> {
+    cmd.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped());
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut cmd, 0);
+
+    let mut interrupt = interrupt::guard();
+    let cpu_before = children_cpu();
+    let mut child = tokio::process::Command::from(cmd).kill_on_drop(true).spawn()?;
+    let (stop_reading, reading_stopped) = tokio::sync::oneshot::channel();
+    let mut streamed = tokio::spawn(stream(
+        child.stdout.take(),
+        child.stderr.take(),
+        security.max_output_size,
+        reading_stopped,
+    ));
+
+    let timeout = security.command_timeout_secs;
+    let deadline = sleep_for(timeout);
+    tokio::pin!(deadline);
+    let stopped = tokio::select! {
+        status = child.wait() => {
+            status?;
+            None
+        }
+        _ = interrupt.interrupted() => Some(Stopped::Interrupted),
+        _ = &mut deadline => timeout.map(Stopped::TimedOut),
+    };
+    if let Some(stopped) = stopped {
+        stop(&mut child, stopped).await;
+    }
+
+    let status = child.wait().await?;
+    let cpu = children_cpu().saturating_sub(cpu_before);
+    let timed_out = matches!(stopped, Some(Stopped::TimedOut(_)));
+    let drained = tokio::select! {
+        captured = &mut streamed => Some(captured?),
+        _ = tokio::time::sleep(DRAIN_PERIOD) => None,
+        _ = interrupt.interrupted() => None,
+        _ = &mut deadline, if !timed_out => None,
+    };
+    let captured = match drained {
+        Some(captured) => captured,
+        None => {
+            let _ = stop_reading.send(());
+            streamed.await?
+        }
+    };
+    Ok(finish(status, captured, stopped, cpu, shell_type, security))
+}
+
+/// Runs an interactive or full-screen `command` on a pseudo-terminal, with
+/// spren's own terminal in raw mode relaying keys, output and size changes.
+/// Without a terminal to hand over it runs like any other command.
+pub async fn execute_in_terminal(command: &str, security: &SecurityConfig) -> Result<CommandOutput> {
+    use std::io::IsTerminal;
+    if !std::io::stdin().is_terminal() || !std::io::stdout().is_terminal() {
+        return execute_command(command, security).await;
+    }
+    #[cfg(unix)]
+    return pty::run(command, security).await;
+
+    // The console is handed over by letting the command inherit it
+    #[cfg(not(unix))]
+    {
+        let shell_type = ShellType::detect();
+        let mut cmd = shell_command(command, &shell_type, security);
+        cmd.stdin(Stdio::inherit()).stdout(Stdio::inherit()).stderr(Stdio::inherit());
+        let status = tokio::process::Command::from(cmd).status().await?;
+        Ok(finish(status, Captured::default(), None, Duration::ZERO, &shell_type, security))
+    }
+}
+
+/// Builds the `CommandOutput` for a command that has exited after using
+/// `cpu` of processor time.
+fn finish(
+    status: std::process::ExitStatus,
+    captured: Captured,
+    stopped: Option<Stopped>,
+    cpu: Duration,
+    shell_type: &ShellType,
+    security: &SecurityConfig,
+) -> CommandOutput {
+    let output = Output { status, stdout: captured.stdout, stderr: captured.stderr };
+    let mut result = collect_output(&output, shell_type, &security.resource_limits, cpu);
+    if captured.truncated {
+        // Tell whoever reads the capture, including the model, it is partial
+        let marker = format!("[output truncated after {} bytes]", security.max_output_size);
+        for (text, full) in [(&mut result.stdout, captured.stdout_full), (&mut result.stderr, captured.stderr_full)] {
+            if full {
+                text.push('\n');
+                text.push_str(&marker);
+            }
+        }
+        result.truncated = true;
+    }
+    if stopped.is_some() {
+        // Its signal says nothing about resource limits
+        result.limit = None;
+        result.stopped = stopped;
+    }
+    result
+}
+
+/// What `stream` kept of a command's output.
+#[derive(Default)]
+struct Captured {
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    stdout_full: bool,
+    stderr_full: bool,
+    truncated: bool,
+}
+
+/// Copies stdout and stderr to the terminal as they arrive, in the order
+/// they arrive, keeping the first `limit` bytes of each. Stops at EOF on
+/// both, or when told to.
+async fn stream(
+    stdout: Option<impl AsyncRead + Unpin>,
+    stderr: Option<impl AsyncRead + Unpin>,
+    limit: usize,
+    mut stop: tokio::sync::oneshot::Receiver<()>,
+) -> Captured {
+    let (mut stdout, mut stderr) = (stdout, stderr);
+    let (mut stdout_open, mut stderr_open) = (stdout.is_some(), stderr.is_some());
+    let mut stdout_buffer = [0u8; 8192];
+    let mut stderr_buffer = [0u8; 8192];
+    let mut captured = Captured::default();
+
+    while stdout_open || stderr_open {
+        tokio::select! {
+            read = read_from(&mut stdout, &mut stdout_buffer), if stdout_open => match read {
+                Ok(n) if n > 0 => {
+                    let mut terminal = std::io::stdout().lock();
+                    let _ = terminal.write_all(&stdout_buffer[..n]).and_then(|_| terminal.flush());
+                    captured.stdout_full |= keep(&mut captured.stdout, &stdout_buffer[..n], limit);
+                }
+                _ => stdout_open = false,
+            },
+            read = read_from(&mut stderr, &mut stderr_buffer), if stderr_open => match read {
+                Ok(n) if n > 0 => {
+                    let mut terminal = std::io::stderr().lock();
+                    let _ = terminal.write_all(&stderr_buffer[..n]).and_then(|_| terminal.flush());
+                    captured.stderr_full |= keep(&mut captured.stderr, &stderr_buffer[..n], limit);
+                }
+                _ => stderr_open = false,
+            },
+            _ = &mut stop => break,
+        }
+    }
+    captured.truncated = captured.stdout_full || captured.stderr_full;
+    captured
+}
+
+async fn read_from(pipe: &mut Option<impl AsyncRead + Unpin>, buffer: &mut [u8]) -> std::io::Result<usize> {
+    match pipe {
+        Some(pipe) => pipe.read(buffer).await,
+        None => Ok(0),
+    }
+}
+
+/// Appends as much of `chunk` as fits under `limit`. Returns true if some
+/// of it did not fit.
+fn keep(capture: &mut Vec<u8>, chunk: &[u8], limit: usize) -> bool {
+    let room = limit.saturating_sub(capture.len());
+    capture.extend_from_slice(&chunk[..chunk.len().min(room)]);
+    chunk.len() > room
+}
+
+async fn sleep_for(secs: Option<u64>) {
+    match secs {
+        Some(secs) => tokio::time::sleep(Duration::from_secs(secs)).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Sends the command's process group SIGINT (Ctrl-C) or SIGTERM (timeout),
+/// then SIGKILL if it is still running after the grace period.
+async fn stop(child: &mut tokio::process::Child, stopped: Stopped) {
+    #[cfg(unix)]
+    if let Some(pid) = child.id() {
+        let signal = match stopped {
+            Stopped::Interrupted => libc::SIGINT,
+            Stopped::TimedOut(_) => libc::SIGTERM,
+        };
+        // SAFETY: kill has no memory-safety preconditions; the negative pid
+        // names the group the child leads.
+        unsafe { libc::kill(-(pid as libc::pid_t), signal) };
+        if tokio::time::timeout(GRACE_PERIOD, child.wait()).await.is_ok() {
+            return;
+        }
+        // SAFETY: as above.
+        unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGKILL) };
+        return;
+    }
+    #[cfg(not(unix))]
+    let _ = stopped;
+    let _ = child.start_kill();
+}
+
+#[cfg(unix)]
+mod pty {
+    use super::*;
+    use std::fs::File;
+    use std::io::Read;
+    use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+    use std::os::unix::process::CommandExt;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use tokio::signal::unix::{signal, SignalKind};
+
+    pub async fn run(command: &str, security: &SecurityConfig) -> Result<CommandOutput> {
+        let shell_type = ShellType::detect();
+        let (master, slave) = open(&window_size())?;
+
+        let mut cmd = shell_command(command, &shell_type, security);
+        cmd.stdin(slave.try_clone()?).stdout(slave.try_clone()?).stderr(slave);
+        // SAFETY: setsid and ioctl are async-signal-safe.
+        unsafe {
+            cmd.pre_exec(|| {
+                // A session of its own, with the pty as its controlling
+                // terminal, so Ctrl-C and job control work inside it
+                if libc::setsid() == -1 || libc::ioctl(0, libc::TIOCSCTTY as _, 0) == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let mut interrupt = interrupt::guard();
+        let cpu_before = children_cpu();
+        let mut spawner = tokio::process::Command::from(cmd);
+        let mut child = spawner.kill_on_drop(true).spawn()?;
+        // Our copies of the slave must close for the output relay to see EOF
+        drop(spawner);
+
+        let raw_mode = RawMode::enable()?;
+        let done = Arc::new(AtomicBool::new(false));
+        let input = tokio::task::spawn_blocking({
+            let (master, done) = (master.try_clone()?, done.clone());
+            move || relay_input(master, &done)
+        });
+        let output = tokio::task::spawn_blocking({
+            let (master, done, limit) = (master.try_clone()?, done.clone(), security.max_output_size);
+            move || relay_output(master, &done, limit)
+        });
+        let mut resized = signal(SignalKind::window_change())?;
+
+        let timeout = security.command_timeout_secs;
+        let deadline = sleep_for(timeout);
+        tokio::pin!(deadline);
+        let stopped = loop {
+            tokio::select! {
+                status = child.wait() => {
+                    status?;
+                    break None;
+                }
+                _ = interrupt.interrupted() => break Some(Stopped::Interrupted),
+                _ = &mut deadline => break timeout.map(Stopped::TimedOut),
+                _ = resized.recv() => resize(&master, &window_size()),
+            }
+        };
+        if let Some(stopped) = stopped {
+            stop(&mut child, stopped).await;
+        }
+
+        let status = child.wait().await?;
+        let cpu = children_cpu().saturating_sub(cpu_before);
+        done.store(true, Ordering::Relaxed);
+        input.await?;
+        let captured = output.await?;
+        drop(raw_mode);
+        Ok(finish(status, captured, stopped, cpu, &shell_type, security))
+    }
+
+    fn open(size: &libc::winsize) -> Result<(File, File)> {
+        let (mut master, mut slave) = (0, 0);
+        // SAFETY: openpty writes the two descriptors; the name and termios
+        // arguments may be null.
+        let opened = unsafe { libc::openpty(&mut master, &mut slave, std::ptr::null_mut(), std::ptr::null(), size) };
+        if opened != 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        for fd in [master, slave] {
+            // SAFETY: fd was just opened and is ours.
+            unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+        }
+        // SAFETY: both descriptors are open and owned by nothing else.
+        Ok(unsafe { (File::from_raw_fd(master), File::from_raw_fd(slave)) })
+    }
+
+    fn window_size() -> libc::winsize {
+        let (cols, rows) = crossterm::terminal::size().unwrap_or((80, 24));
+        libc::winsize { ws_row: rows, ws_col: cols, ws_xpixel: 0, ws_ypixel: 0 }
+    }
+
+    /// Passes a new size on; the kernel then sends the program SIGWINCH.
+    fn resize(master: &File, size: &libc::winsize) {
+        // SAFETY: TIOCSWINSZ only reads `size`.
+        unsafe { libc::ioctl(master.as_raw_fd(), libc::TIOCSWINSZ, size) };
+    }
+
+    /// Waits up to 50ms for `fd` to have something to read.
+    fn readable(fd: RawFd) -> bool {
+        let mut poll = libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+        // SAFETY: `poll` is a single valid pollfd.
+        unsafe { libc::poll(&mut poll, 1, 50) > 0 }
+    }
+
+    /// Copies keystrokes to the program until it exits. Polling rather than
+    /// blocking in `read` keeps the next line at the prompt out of its hands.
+    fn relay_input(mut master: File, done: &AtomicBool) {
+        let mut stdin = std::io::stdin().lock();
+        let mut buffer = [0u8; 1024];
+        while !done.load(Ordering::Relaxed) {
+            if !readable(libc::STDIN_FILENO) {
+                continue;
+            }
+            match stdin.read(&mut buffer) {
+                Ok(n) if n > 0 => {
+                    if master.write_all(&buffer[..n]).is_err() {
+                        return;
+                    }
+                }
+                _ => return,
+            }
+        }
+    }
+
+    /// Copies the program's screen to ours, keeping the first `limit` bytes.
+    fn relay_output(mut master: File, done: &AtomicBool, limit: usize) -> Captured {
+        let mut captured = Captured::default();
+        let mut buffer = [0u8; 8192];
+        loop {
+            if !readable(master.as_raw_fd()) {
+                // Once it has exited, stop when there is nothing left to show
+                if done.load(Ordering::Relaxed) {
+                    break;
+                }
+                continue;
+            }
+            // Reading fails with EIO once every process has closed the pty
+            match master.read(&mut buffer) {
+                Ok(n) if n > 0 => {
+                    let mut terminal = std::io::stdout().lock();
+                    let _ = terminal.write_all(&buffer[..n]).and_then(|_| terminal.flush());
+                    captured.stdout_full |= keep(&mut captured.stdout, &buffer[..n], limit);
+                }
+                _ => break,
+            }
+        }
+        captured.truncated = captured.stdout_full;
+        captured
+    }
+
+    /// Raw mode for as long as it is held: keys go to the program unchanged,
+    /// Ctrl-C included.
+    struct RawMode;
+
+    impl RawMode {
+        fn enable() -> Result<RawMode> {
+            crossterm::terminal::enable_raw_mode()?;
+            Ok(RawMode)
+        }
+    }
+
+    impl Drop for RawMode {
+        fn drop(&mut self) {
+            let _ = crossterm::terminal::disable_raw_mode();
+        }
+    }
+}
+
+/// Builds the process that runs `command` in the user's shell, with a
+/// filtered environment and the configured resource limits.
+pub fn shell_command(command: &str, shell_type: &ShellType, security: &SecurityConfig) -> Command {
     let (shell, args) = shell_type.get_shell_command();
 
     let formatted_command = match shell_type {
@@ -35,9 +483,85 @@ pub async fn execute_command(command: &str) -> Result<CommandOutput> {
 
     let mut cmd = Command::new(shell);
     cmd.args(args).arg(&formatted_command);
+    cmd.env_clear().envs(child_environment(std::env::vars_os(), &security.child_environment));
+    #[cfg(unix)]
+    apply_limits(&mut cmd, &security.resource_limits);
+    cmd
+}
+
+/// Sets the limits in the child before it execs, so the shell and
+/// everything it starts inherit them.
+#[cfg(unix)]
+fn apply_limits(cmd: &mut Command, limits: &ResourceLimits) {
+    use std::os::unix::process::CommandExt;
+
+    const MB: u64 = 1024 * 1024;
+    let wanted = [
+        // One second of grace between SIGXCPU and SIGKILL
+        (libc::RLIMIT_CPU, limits.cpu_seconds, 1),
+        (libc::RLIMIT_AS, limits.memory_mb.map(|mb| mb * MB), 0),
+        (libc::RLIMIT_NOFILE, limits.open_files, 0),
+        (libc::RLIMIT_NPROC, limits.processes, 0),
+        (libc::RLIMIT_FSIZE, limits.file_size_mb.map(|mb| mb * MB), 0),
+    ];
 
-    let output = cmd.output()?;
+    // Work out the values here; the child may only make system calls
+    let mut rlimits = Vec::new();
+    for (resource, value, grace) in wanted {
+        let Some(value) = value else { continue };
+        let mut current = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+        // SAFETY: `current` is a valid rlimit for getrlimit to fill in.
+        if unsafe { libc::getrlimit(resource, &mut current) } != 0 {
+            continue;
+        }
+        // An unprivileged process cannot raise its hard limit
+        let hard = (value.saturating_add(grace) as libc::rlim_t).min(current.rlim_max);
+        let soft = (value as libc::rlim_t).min(hard);
+        rlimits.push((resource, libc::rlimit { rlim_cur: soft, rlim_max: hard }));
+    }
+    if rlimits.is_empty() {
+        return;
+    }
 
+    // SAFETY: setrlimit is async-signal-safe and only reads `rlimits`,
+    // which was built before the fork.
+    unsafe {
+        cmd.pre_exec(move || {
+            for (resource, limit) in &rlimits {
+                if libc::setrlimit(*resource, limit) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+}
+
+/// The variables from `vars` a spawned command may see.
+fn child_environment(
+    vars: impl Iterator<Item = (OsString, OsString)>,
+    environment: &ChildEnvironment,
+) -> Vec<(OsString, OsString)> {
+    let matches = |patterns: &[String], name: &str| {
+        patterns.iter().any(|pattern| {
+            glob::Pattern::new(pattern).map_or(pattern == name, |pattern| pattern.matches(name))
+        })
+    };
+    vars.filter(|(name, _)| {
+        let name = name.to_string_lossy();
+        if SPREN_CREDENTIALS.contains(&name.as_ref()) {
+            return false;
+        }
+        match environment.mode {
+            EnvironmentMode::Denylist => !matches(&environment.deny, &name),
+            EnvironmentMode::Allowlist => matches(&environment.allow, &name),
+        }
+    })
+    .collect()
+}
+
+/// Turns raw process output into what we show the user.
+pub fn collect_output(output: &Output, shell_type: &ShellType, limits: &ResourceLimits, cpu: Duration) -> CommandOutput {
     let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
     let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
 
@@ -54,9 +578,196 @@ pub async fn execute_command(command: &str) -> Result<CommandOutput> {
         _ => output.status.success()
     };
 
-    Ok(CommandOutput {
+    CommandOutput {
         stdout: stdout.trim().to_string(),
         stderr: stderr.trim().to_string(),
-        success
-    })
-}
\ No newline at end of file
+        success,
+        exit_code: output.status.code(),
+        limit: limit_hit(output, &stderr, limits, cpu),
+        stopped: None,
+        truncated: false,
+    }
+}
+
+/// Which configured limit, if any, explains how the command ended: the
+/// limit's own signal, reported directly or by the shell, or the error
+/// (`ENOMEM`, `EMFILE`, `EFBIG`, or `EAGAIN` from bash's `fork`) a program
+/// prints when a limit refuses it.
+/// A SIGKILL only counts as the CPU limit once the command has used that
+/// much CPU time, since the OOM killer and `kill -9` send it too.
+fn limit_hit(output: &Output, stderr: &str, limits: &ResourceLimits, cpu: Duration) -> Option<Limit> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        // A shell reports a child killed by signal N as exit status 128 + N
+        let signal = output.status.signal().or_else(|| output.status.code().filter(|code| *code > 128).map(|code| code - 128));
+        match signal {
+            Some(libc::SIGXCPU) => return limits.cpu_seconds.map(Limit::Cpu),
+            Some(libc::SIGKILL) => {
+                if let Some(secs) = limits.cpu_seconds.filter(|secs| cpu >= Duration::from_secs(*secs)) {
+                    return Some(Limit::Cpu(secs));
+                }
+            }
+            Some(libc::SIGXFSZ) => return limits.file_size_mb.map(Limit::FileSize),
+            _ => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = cpu;
+    if output.status.success() {
+        return None;
+    }
+    let stderr = stderr.to_lowercase();
+    if stderr.contains("cannot allocate memory") {
+        return limits.memory_mb.map(Limit::Memory);
+    }
+    if stderr.contains("too many open files") {
+        return limits.open_files.map(Limit::OpenFiles);
+    }
+    if stderr.contains("fork: ") && stderr.contains("resource temporarily unavailable") {
+        return limits.processes.map(Limit::Processes);
+    }
+    if stderr.contains("file too large") {
+        return limits.file_size_mb.map(Limit::FileSize);
+    }
+    None
+}
+
+/// Processor time used so far by every child we have waited for, and the
+/// children they waited for in turn.
+fn children_cpu() -> Duration {
+    #[cfg(unix)]
+    {
+        // SAFETY: `usage` is a valid rusage for getrusage to fill in.
+        let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+        if unsafe { libc::getrusage(libc::RUSAGE_CHILDREN, &mut usage) } != 0 {
+            return Duration::ZERO;
+        }
+        let time = |t: libc::timeval| Duration::new(t.tv_sec as u64, t.tv_usec as u32 * 1000);
+        time(usage.ru_utime) + time(usage.ru_stime)
+    }
+    #[cfg(not(unix))]
+    Duration::ZERO
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(environment: &ChildEnvironment) -> Vec<String> {
+        let vars = ["PATH", "HOME", "LC_ALL", "ANTHROPIC_API_KEY", "OPENAI_API_KEY", "GITHUB_TOKEN", "EDITOR"]
+            .iter()
+            .map(|name| (OsString::from(name), OsString::from("x")));
+        child_environment(vars, environment)
+            .into_iter()
+            .map(|(name, _)| name.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_limits_reported() {
+        let mut security: SecurityConfig = toml::from_str(
+            "dangerous_commands = []\nrequire_confirmation = true\nmax_output_size = 1024\n\
+             allowed_directories = []\ndisable_dangerous_commands = false",
+        )
+        .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let run = |command: &str, security: &SecurityConfig| {
+            let before = children_cpu();
+            let output = shell_command(command, &ShellType::Bash, security).current_dir(dir.path()).output().unwrap();
+            collect_output(&output, &ShellType::Bash, &security.resource_limits, children_cpu().saturating_sub(before))
+        };
+
+        let write = "head -c 2000000 /dev/zero > big";
+        assert_eq!(run(write, &security).limit, None);
+
+        security.resource_limits.file_size_mb = Some(1);
+        let output = run(write, &security);
+        assert_eq!(output.limit, Some(Limit::FileSize(1)));
+        assert!(!output.success);
+
+        security.resource_limits.cpu_seconds = Some(1);
+        let output = run("while :; do :; done", &security);
+        assert_eq!(output.limit, Some(Limit::Cpu(1)));
+        assert!(output.limit.unwrap().to_string().contains("cpu_seconds = 1"));
+
+        // Killed for other reasons, or failing with a similar message, is not a limit
+        assert_eq!(run("kill -9 $$", &security).limit, None);
+        assert_eq!(run("exit 137", &security).limit, None);
+        security.resource_limits.memory_mb = Some(4096);
+        assert_eq!(run("echo 'fork: out of memory' >&2; exit 1", &security).limit, None);
+        assert_eq!(run("echo 'fork: Resource temporarily unavailable' >&2; exit 1", &security).limit, None);
+        security.resource_limits.processes = Some(1000);
+        let output = run("echo 'bash: fork: retry: Resource temporarily unavailable' >&2; exit 1", &security);
+        assert_eq!(output.limit, Some(Limit::Processes(1000)));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_timeout_stops_process_group() {
+        let mut security: SecurityConfig = toml::from_str(
+            "dangerous_commands = []\nrequire_confirmation = true\nmax_output_size = 1024\n\
+             allowed_directories = []\ndisable_dangerous_commands = false\ncommand_timeout_secs = 1",
+        )
+        .unwrap();
+        let start = std::time::Instant::now();
+        // The backgrounded sleep holds the pipes open unless the group is killed
+        let output = execute_command("echo started; sleep 30 & sleep 30", &security).await.unwrap();
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert_eq!(output.stopped, Some(Stopped::TimedOut(1)));
+        assert_eq!(output.stdout, "started");
+        assert!(!output.success);
+
+        security.command_timeout_secs = None;
+        let output = execute_command("echo done", &security).await.unwrap();
+        assert_eq!((output.stdout.as_str(), output.stopped), ("done", None));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_background_jobs_do_not_hold_the_prompt() {
+        let security: SecurityConfig = toml::from_str(
+            "dangerous_commands = []\nrequire_confirmation = true\nmax_output_size = 1024\n\
+             allowed_directories = []\ndisable_dangerous_commands = false",
+        )
+        .unwrap();
+        let start = std::time::Instant::now();
+        let output = execute_command("echo hi; sleep 5 &", &security).await.unwrap();
+        assert!(start.elapsed() < Duration::from_secs(2));
+        assert_eq!(output.stdout, "hi");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_output_capped() {
+        let security: SecurityConfig = toml::from_str(
+            "dangerous_commands = []\nrequire_confirmation = true\nmax_output_size = 10\n\
+             allowed_directories = []\ndisable_dangerous_commands = false",
+        )
+        .unwrap();
+        let output = execute_command("printf 0123456789abcdef; printf oops >&2; exit 1", &security).await.unwrap();
+        assert_eq!(output.stdout, "0123456789\n[output truncated after 10 bytes]");
+        assert_eq!(output.stderr, "oops");
+        assert!(output.truncated);
+
+        let output = execute_command("printf short", &security).await.unwrap();
+        assert_eq!((output.stdout.as_str(), output.truncated), ("short", false));
+    }
+
+    #[test]
+    fn test_child_environment() {
+        let mut environment = ChildEnvironment::default();
+        assert_eq!(names(&environment), vec!["PATH", "HOME", "LC_ALL", "GITHUB_TOKEN", "EDITOR"]);
+
+        environment.deny = vec!["*_TOKEN".to_string()];
+        assert_eq!(names(&environment), vec!["PATH", "HOME", "LC_ALL", "EDITOR"]);
+
+        environment.mode = EnvironmentMode::Allowlist;
+        assert_eq!(names(&environment), vec!["PATH", "HOME", "LC_ALL"]);
+
+        // spren's own keys stay out even when allowed explicitly
+        environment.allow.push("ANTHROPIC_API_KEY".to_string());
+        assert_eq!(names(&environment), vec!["PATH", "HOME", "LC_ALL"]);
+    }
+}
@@ -0,0 +1,104 @@
+// src/history.rs
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// One command spren ran, as recorded in `history.jsonl`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Entry {
+    pub id: u64,
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+    pub cwd: PathBuf,
+    pub query: String,
+    pub command: String,
+    pub success: bool,
+    /// Whether `spren undo <id>` has files to restore.
+    pub snapshot: bool,
+}
+
+/// Append-only command history, one JSON object per line.
+pub struct History {
+    path: PathBuf,
+}
+
+impl History {
+    pub fn open(data_dir: &Path) -> Self {
+        History { path: data_dir.join("history.jsonl") }
+    }
+
+    /// Entries oldest first. Lines that do not parse are skipped.
+    pub fn entries(&self) -> Result<Vec<Entry>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        let contents = fs::read_to_string(&self.path)?;
+        Ok(contents.lines().filter_map(|line| serde_json::from_str(line).ok()).collect())
+    }
+
+    /// The id the next command will be recorded under.
+    pub fn next_id(&self) -> Result<u64> {
+        Ok(self.entries()?.last().map_or(1, |entry| entry.id + 1))
+    }
+
+    /// Records `entry`, dropping the oldest entries beyond `limit`.
+    pub fn append(&self, entry: &Entry, limit: usize) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        writeln!(file, "{}", serde_json::to_string(entry)?)?;
+        drop(file);
+
+        let entries = self.entries()?;
+        if entries.len() > limit {
+            let kept: Vec<String> = entries[entries.len() - limit..]
+                .iter()
+                .map(serde_json::to_string)
+                .collect::<Result<_, _>>()?;
+            fs::write(&self.path, kept.join("\n") + "\n")?;
+        }
+        Ok(())
+    }
+}
+
+pub fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn entry(id: u64) -> Entry {
+        Entry {
+            id,
+            timestamp: 1_700_000_000 + id,
+            cwd: PathBuf::from("/tmp"),
+            query: "list files".to_string(),
+            command: "ls".to_string(),
+            success: true,
+            snapshot: false,
+        }
+    }
+
+    #[test]
+    fn test_append_and_trim() {
+        let dir = tempdir().unwrap();
+        let history = History::open(dir.path());
+        assert_eq!(history.next_id().unwrap(), 1);
+
+        for id in 1..=4 {
+            history.append(&entry(id), 3).unwrap();
+        }
+        let ids: Vec<u64> = history.entries().unwrap().iter().map(|e| e.id).collect();
+        assert_eq!(ids, vec![2, 3, 4]);
+        assert_eq!(history.next_id().unwrap(), 5);
+    }
+}
@@ -0,0 +1,151 @@
+// src/interactive.rs
+//! Recognises commands that need a terminal of their own: editors, pagers,
+//! live monitors, remote shells and REPLs. These run on a pseudo-terminal
+//! instead of with piped output.
+
+use crate::parser::{self, SimpleCommand};
+use crate::shell::ShellType;
+
+/// Programs that always take over the terminal.
+const FULL_SCREEN: &[&str] = &[
+    "vi", "vim", "nvim", "view", "vimdiff", "nano", "pico", "micro", "joe", "emacs", "less", "more", "most",
+    "man", "info", "top", "htop", "btop", "atop", "iotop", "nmon", "glances", "watch", "tmux", "screen",
+    "mosh", "telnet", "ftp", "sftp", "fzf", "ranger", "nnn", "mc", "vifm", "tig", "lazygit", "ncdu", "nmtui",
+    "alsamixer", "visudo", "vipw", "vigr", "passwd",
+];
+
+/// Programs that start an interactive session when given nothing to run.
+const REPLS: &[&str] = &[
+    "python", "python3", "ipython", "node", "irb", "ghci", "lua", "R", "bc", "psql", "mysql", "sqlite3",
+    "redis-cli", "mongosh", "bash", "sh", "zsh", "fish", "su", "sudo", "doas",
+];
+
+/// Options that give a REPL something to run, so it exits by itself.
+const REPL_SCRIPT_OPTIONS: &[&str] = &["-c", "-e", "-f", "-m", "--command", "--execute", "--file", "--eval"];
+
+/// `ssh` options that take a value.
+const SSH_VALUE_OPTIONS: &[&str] = &[
+    "-b", "-c", "-D", "-E", "-e", "-F", "-I", "-i", "-J", "-L", "-l", "-m", "-O", "-o", "-p", "-Q", "-R", "-S",
+    "-W", "-w",
+];
+
+/// Whether `command` should run on a terminal of its own.
+pub fn needs_terminal(command: &str, shell: &ShellType) -> bool {
+    if *shell != ShellType::Bash {
+        return false;
+    }
+    let Ok(script) = parser::parse(command) else {
+        return false;
+    };
+    script.commands().iter().any(|cmd| is_interactive(cmd))
+}
+
+fn is_interactive(cmd: &SimpleCommand) -> bool {
+    let name = cmd.name();
+    if FULL_SCREEN.contains(&name) {
+        return true;
+    }
+    // `sudo -i` has no program of its own
+    let name = if name.is_empty() { cmd.wrappers.last().map(String::as_str).unwrap_or("") } else { name };
+    match name {
+        "ssh" => cmd.has_short_flag('t') || ssh_operands(cmd) <= 1,
+        "crontab" | "edquota" => cmd.has_short_flag('e'),
+        "docker" | "podman" | "kubectl" => {
+            cmd.operands().next().is_some_and(|sub| matches!(sub.text.as_str(), "exec" | "run" | "attach"))
+                && cmd.has_short_flag('t')
+                && cmd.has_short_flag('i')
+        }
+        "git" => {
+            let sub = cmd.operands().next().map(|w| w.text.as_str());
+            match sub {
+                Some("commit") | Some("merge") => !gives_message(cmd),
+                Some("tag") => cmd.has_short_flag('a') && !gives_message(cmd),
+                Some("rebase") => cmd.has_short_flag('i') || cmd.has_long_flag("interactive"),
+                Some("add") | Some("checkout") | Some("reset") | Some("stash") => {
+                    cmd.has_short_flag('p') || cmd.has_long_flag("patch")
+                }
+                _ => false,
+            }
+        }
+        _ if REPLS.contains(&name) => {
+            cmd.operands().next().is_none() && !REPL_SCRIPT_OPTIONS.iter().any(|o| has_option(cmd, o))
+        }
+        _ => false,
+    }
+}
+
+/// Whether a git command has its message already, so no editor opens.
+fn gives_message(cmd: &SimpleCommand) -> bool {
+    ['m', 'F', 'C'].iter().any(|flag| cmd.has_short_flag(*flag))
+        || ["message", "file", "no-edit"].iter().any(|flag| cmd.has_long_flag(flag))
+}
+
+fn has_option(cmd: &SimpleCommand, option: &str) -> bool {
+    match option.strip_prefix("--") {
+        Some(long) => cmd.has_long_flag(long),
+        None => cmd.has_short_flag(option.trim_start_matches('-').chars().next().unwrap_or('-')),
+    }
+}
+
+/// The destination plus any remote command: `ssh -i key host` has one.
+fn ssh_operands(cmd: &SimpleCommand) -> usize {
+    let mut count = 0;
+    let mut skip_value = false;
+    for word in &cmd.args {
+        let text = word.text.as_str();
+        if skip_value {
+            skip_value = false;
+        } else if text.starts_with('-') && count == 0 {
+            skip_value = SSH_VALUE_OPTIONS.contains(&text);
+        } else {
+            count += 1;
+        }
+    }
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn needs(command: &str) -> bool {
+        needs_terminal(command, &ShellType::Bash)
+    }
+
+    #[test]
+    fn test_interactive_programs() {
+        for command in [
+            "sudo vim /etc/nginx/nginx.conf",
+            "ps aux | less",
+            "htop",
+            "ssh -i ~/.ssh/id_ed25519 bastion",
+            "ssh -t bastion top",
+            "docker exec -it web sh",
+            "python3",
+            "sudo -i",
+            "git commit",
+            "git rebase -i HEAD~3",
+            "crontab -e",
+        ] {
+            assert!(needs(command), "{}", command);
+        }
+    }
+
+    #[test]
+    fn test_batch_programs() {
+        for command in [
+            "ls -la",
+            "ssh bastion uptime",
+            "python3 script.py",
+            "psql -c 'select 1'",
+            "git commit -m 'fix'",
+            "git commit --no-edit",
+            "docker exec web ls",
+            "crontab -l",
+            "bash -c 'echo hi'",
+        ] {
+            assert!(!needs(command), "{}", command);
+        }
+        assert!(!needs_terminal("vim x", &ShellType::PowerShell));
+    }
+}
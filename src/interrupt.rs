@@ -0,0 +1,138 @@
+// src/interrupt.rs
+//! Ctrl-C handling. At the prompt it quits spren, as it always has; while a
+//! command or request is running it goes to that instead, which stops it
+//! and returns to the prompt.
+
+use anyhow::{anyhow, Result};
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use crossterm::{cursor, terminal};
+use std::future::Future;
+use std::io::{self, IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+
+const SPINNER: &[char] = &['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+
+static INTERRUPTS: OnceLock<broadcast::Sender<()>> = OnceLock::new();
+
+fn interrupts() -> &'static broadcast::Sender<()> {
+    INTERRUPTS.get_or_init(|| broadcast::channel(1).0)
+}
+
+/// Takes over Ctrl-C for the rest of the session.
+pub fn install() {
+    tokio::spawn(async {
+        while tokio::signal::ctrl_c().await.is_ok() {
+            // Nothing is listening, so we are at the prompt
+            if interrupts().send(()).is_err() {
+                println!();
+                std::process::exit(130);
+            }
+        }
+    });
+}
+
+/// Claims Ctrl-C for as long as it is held.
+pub struct Guard(broadcast::Receiver<()>);
+
+pub fn guard() -> Guard {
+    Guard(interrupts().subscribe())
+}
+
+impl Guard {
+    /// Resolves at the next Ctrl-C.
+    pub async fn interrupted(&mut self) {
+        let _ = self.0.recv().await;
+    }
+}
+
+/// Waits for `request` behind a spinner showing the elapsed time. Esc or
+/// Ctrl-C abandons it and returns an error instead.
+pub async fn cancellable<T>(label: &str, request: impl Future<Output = Result<T>>) -> Result<T> {
+    let mut interrupt = guard();
+    let mut keys = Keys::watch();
+    let show_spinner = io::stdout().is_terminal();
+    let start = Instant::now();
+    let mut ticker = tokio::time::interval(Duration::from_millis(100));
+    let mut frame = 0;
+    tokio::pin!(request);
+
+    let result = loop {
+        tokio::select! {
+            result = &mut request => break Some(result),
+            _ = interrupt.interrupted() => break None,
+            _ = keys.cancelled() => break None,
+            _ = ticker.tick(), if show_spinner => {
+                let hint = if keys.thread.is_some() { "Esc or Ctrl-C" } else { "Ctrl-C" };
+                print!("\r{} {} {:.1}s ({} to cancel)", SPINNER[frame % SPINNER.len()], label, start.elapsed().as_secs_f32(), hint);
+                let _ = io::stdout().flush();
+                frame += 1;
+            }
+        }
+    };
+    keys.finish().await;
+    if show_spinner {
+        let _ = crossterm::execute!(io::stdout(), cursor::MoveToColumn(0), terminal::Clear(terminal::ClearType::CurrentLine));
+    }
+    result.unwrap_or_else(|| Err(anyhow!("Cancelled after {:.1}s", start.elapsed().as_secs_f32())))
+}
+
+/// Reads keys in raw mode while a request runs. In raw mode Ctrl-C is a key
+/// rather than a signal, so it is watched for here too.
+struct Keys {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<bool>>,
+}
+
+impl Keys {
+    fn watch() -> Keys {
+        let stop = Arc::new(AtomicBool::new(false));
+        if !io::stdin().is_terminal() || terminal::enable_raw_mode().is_err() {
+            return Keys { stop, thread: None };
+        }
+        let stopped = stop.clone();
+        let thread = tokio::task::spawn_blocking(move || {
+            while !stopped.load(Ordering::Relaxed) {
+                if !event::poll(Duration::from_millis(50)).unwrap_or(false) {
+                    continue;
+                }
+                match event::read() {
+                    Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => {
+                        let ctrl_c = key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+                        if key.code == KeyCode::Esc || ctrl_c {
+                            return true;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(_) => return false,
+                }
+            }
+            false
+        });
+        Keys { stop, thread: Some(thread) }
+    }
+
+    /// Resolves when Esc or Ctrl-C is pressed.
+    async fn cancelled(&mut self) {
+        let Some(thread) = self.thread.as_mut() else {
+            return std::future::pending().await;
+        };
+        let pressed = thread.await.unwrap_or(false);
+        self.thread = None;
+        if !pressed {
+            std::future::pending::<()>().await;
+        }
+    }
+
+    /// Stops reading before anything else reads stdin, and leaves raw mode.
+    async fn finish(mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.await;
+        }
+        let _ = terminal::disable_raw_mode();
+    }
+}
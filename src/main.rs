@@ -1,15 +1,86 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use clap::{Parser, Subcommand};
 use colored::*;
 use std::io::{self, Write};
 use std::time::Instant;
 
+use config::{RiskAction, RiskLevel};
+
 mod ai;
+mod audit;
+mod challenge;
 mod config;
+mod credentials;
 mod executor;
+mod history;
+mod interactive;
+mod interrupt;
+mod menu;
+mod parser;
+mod paths;
+mod placeholder;
+mod policy;
+mod preview;
+mod privilege;
+mod rce;
+mod sandbox;
+mod secrets;
+mod safety;
 mod shell;
+mod undo;
+
+/// An AI-powered shell assistant. Run without a subcommand for the prompt.
+#[derive(Parser)]
+#[command(name = "spren", version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Restore the files a command changed from the snapshot taken before it ran
+    Undo {
+        /// History id of the command to undo; the most recent snapshot if omitted
+        id: Option<u64>,
+        /// List the snapshots that can be undone
+        #[arg(long)]
+        list: bool,
+    },
+    /// Check commands against the policy rules files
+    Policy {
+        #[command(subcommand)]
+        action: PolicyAction,
+    },
+    /// Work with the audit log of suggested and executed commands
+    Audit {
+        #[command(subcommand)]
+        action: AuditAction,
+    },
+}
+
+#[derive(Subcommand)]
+enum PolicyAction {
+    /// Show which rules match a command and what spren would require
+    Test {
+        /// The command line to check, quoted
+        command: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum AuditAction {
+    /// Check that no entry has been modified, removed or reordered
+    Verify,
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    if let Some(Command::Undo { id, list }) = cli.command {
+        return undo_command(id, list);
+    }
+
     // Load or create config
     let config_path = config::get_config_path()?;
     if !config_path.exists() {
@@ -19,12 +90,28 @@ async fn main() -> Result<()> {
         return Ok(());
     }
 
-    let config = config::Config::load(&config_path)?;
+    let mut config = config::Config::load(&config_path)?;
+    config.apply_system_config(&config::get_system_config_path())?;
+    if let Some(source) = credentials::resolve(&mut config.ai, &config_path)? {
+        if config.display.verbose_mode {
+            println!("Using the API key from {}", source.label());
+        }
+    }
+    let policy = policy::Policy::load(&config::get_policy_paths()?)?;
+    match cli.command {
+        Some(Command::Audit { action: AuditAction::Verify }) => return audit_verify(&config),
+        Some(Command::Policy { action: PolicyAction::Test { command } }) => {
+            policy_test(&command, &config, &policy);
+            return Ok(());
+        }
+        _ => {}
+    }
     let shell_type = shell::ShellType::detect();
 
     println!("{}", "Spren - Your AI Shell Assistant".green().bold());
     println!("Shell Type: {}", format!("{:?}", shell_type).blue());
     println!("Type 'exit' to quit\n");
+    interrupt::install();
 
     loop {
         print!("spren> ");
@@ -38,7 +125,7 @@ async fn main() -> Result<()> {
             break;
         }
 
-        match process_query(query, &config).await {
+        match process_query(query, &config, &policy).await {
             Ok(_) => continue,
             Err(e) => eprintln!("{}: {}", "Error".red().bold(), e),
         }
@@ -47,72 +134,525 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-async fn process_query(query: &str, config: &config::Config) -> Result<()> {
-    // Get command suggestion from AI
-    let (command, is_dangerous) = ai::get_command_suggestion(query, &config).await?;
+/// With `privileged_commands = "strip"`, drops `sudo`-style prefixes from a
+/// Bash suggestion and tells the user root was needed.
+fn strip_privileges(command: String, config: &config::Config) -> String {
+    if config.security.privileged_commands != config::PrivilegeMode::Strip
+        || !matches!(shell::ShellType::detect(), shell::ShellType::Bash)
+    {
+        return command;
+    }
+    match privilege::strip(&command) {
+        Some(stripped) => {
+            println!(
+                "\n{} The suggestion needs root; spren removed the privilege escalation from:\n  {}\nRun it yourself with sudo if you intend to.",
+                "Note:".yellow().bold(),
+                command
+            );
+            stripped
+        }
+        None => command,
+    }
+}
+
+/// How many rounds of clarifying questions we answer before giving up.
+const MAX_CLARIFICATION_ROUNDS: usize = 3;
 
-    println!("\n{}", "Suggested command:".blue().bold());
-    if is_dangerous {
-        println!("{} {}", command, "[DANGEROUS]".red().bold());
-        println!("\n{}", "This command has been identified as potentially dangerous.".yellow());
-        if !config.security.require_confirmation {
-            return Ok(());
+/// Gets a command suggestion from the AI, answering its questions if it has any.
+/// Also returns whether the model says the command needs a terminal.
+async fn suggest(query: &mut ai::Query, config: &config::Config) -> Result<(String, RiskLevel, bool)> {
+    let mut rounds = 0;
+    loop {
+        match ai::get_command_suggestion(query, config).await? {
+            ai::Suggestion::Command { command, risk, terminal } => {
+                return Ok((strip_privileges(command, config), risk, terminal))
+            }
+            ai::Suggestion::Clarify(questions) => {
+                rounds += 1;
+                if rounds > MAX_CLARIFICATION_ROUNDS {
+                    return Err(anyhow!("Still ambiguous after {} rounds of questions; try rephrasing", MAX_CLARIFICATION_ROUNDS));
+                }
+                query.answers.extend(ask_clarifications(&questions)?);
+            }
         }
-    } else {
-        println!("{}", command);
     }
+}
 
-    if config.security.require_confirmation {
-        print!("\nExecute? [y/N] ");
-        io::stdout().flush()?;
+async fn process_query(query: &str, config: &config::Config, policy: &policy::Policy) -> Result<()> {
+    let mut query = ai::Query::new(query);
+    let (mut command, mut ai_risk, mut ai_terminal) = suggest(&mut query, config).await?;
+    let shell_type = shell::ShellType::detect();
+    let mut tried: Option<String> = None;
+    let mut suggested = command.clone();
+    let mut edits = Vec::new();
+    let mut risk_record;
 
-        let mut response = String::new();
-        io::stdin().read_line(&mut response)?;
+    let decision = loop {
+        // Fill in values the model could not know, refusing to run with any left
+        if !placeholder::find_placeholders(&command).is_empty() {
+            println!("\n{}", "Suggested command:".blue().bold());
+            println!("{}", command);
+            command = placeholder::fill_placeholders(&command)?;
+        }
 
-        if response.trim().to_lowercase() != "y" {
-            return Ok(());
+        // The local analyzer is authoritative alongside the model's own rating,
+        // and policy rules have the last word on the commands they match
+        let context = paths::Context::current();
+        let assessment = safety::analyze(&command, &shell_type, &config.security, &context);
+        let risk = ai_risk.max(assessment.risk);
+        let verdict = policy.evaluate(&command, &context);
+        let action = assessment.decide(risk, &config.security.risk_policy, &verdict);
+        let alternative = assessment.findings.iter().find_map(|f| f.alternative.clone());
+        show_suggestion(&command, risk, ai_risk, &assessment);
+        show_policy_matches(&verdict);
+        risk_record = audit::RiskRecord {
+            ai: ai_risk,
+            local: assessment.risk,
+            overall: risk,
+            required: action,
+            findings: assessment.findings.iter().map(|f| f.to_string()).collect(),
+        };
+
+        // Hard block: never run, whatever the user answers
+        if config.security.disable_dangerous_commands && risk >= RiskLevel::Destructive {
+            print_blocked_command(&command, config.security.disable_dangerous_locked);
+            break audit::Decision::Blocked;
+        }
+
+        match action {
+            RiskAction::Block => {
+                println!(
+                    "\n{}",
+                    "Not running: blocked by the security policy.".red().bold()
+                );
+                break audit::Decision::Blocked;
+            }
+            RiskAction::Auto if !config.security.require_confirmation => break audit::Decision::Auto,
+            _ => {}
+        }
+
+        // Show what a destructive command would touch before asking
+        if matches!(shell_type, shell::ShellType::Bash) {
+            show_previews(&preview::preview(&command, &paths::Context::current()));
+        }
+
+        if config.security.trial_before_run
+            && risk >= RiskLevel::Modifying
+            && tried.as_deref() != Some(command.as_str())
+        {
+            show_trial(&command, config).await;
+            tried = Some(command.clone());
+        }
+
+        match menu::prompt_action(alternative.is_some())? {
+            menu::Action::Run => {
+                if action != RiskAction::Typed {
+                    break audit::Decision::Confirmed;
+                }
+                let word = challenge::challenge(&command, &shell_type, &assessment);
+                if menu::confirm_typed(risk.label(), &word)? {
+                    break audit::Decision::Typed;
+                }
+                println!("Confirmation did not match; not running.");
+                break audit::Decision::Cancelled;
+            }
+            menu::Action::Edit => {
+                command = menu::edit_command(&command)?;
+                edits.push(command.clone());
+            }
+            menu::Action::Explain => {
+                let explanation = ai::get_explanation(&command, config).await?;
+                println!("\n{}", "Explanation:".blue().bold());
+                println!("{}", explanation);
+            }
+            menu::Action::Try => {
+                show_trial(&command, config).await;
+                tried = Some(command.clone());
+            }
+            menu::Action::Safer => match alternative {
+                Some(alternative) => {
+                    command = alternative;
+                    edits.push(command.clone());
+                }
+                None => println!("No safer alternative for this command."),
+            },
+            menu::Action::Regenerate => {
+                let note = menu::read_feedback()?;
+                query.feedback.push(ai::Feedback { command: command.clone(), note });
+                (command, ai_risk, ai_terminal) = suggest(&mut query, config).await?;
+                suggested = command.clone();
+                edits.clear();
+            }
+            menu::Action::Copy => {
+                menu::copy_command(&command)?;
+                break audit::Decision::Copied;
+            }
+            menu::Action::Cancel => break audit::Decision::Cancelled,
+        }
+        if command.is_empty() {
+            break audit::Decision::Cancelled;
         }
+    };
+
+    let mut record = audit::Record {
+        timestamp: history::now(),
+        user: audit::current_user(),
+        host: audit::current_host(),
+        cwd: paths::Context::current().cwd,
+        query: query.text.clone(),
+        provider: format!("{:?}", config.ai.provider).to_lowercase(),
+        model: config.ai.model.clone(),
+        suggested_command: suggested,
+        edits,
+        command: command.clone(),
+        risk: risk_record,
+        decision,
+        exit_code: None,
+        success: None,
+        duration_ms: None,
+    };
+    if !decision.runs() {
+        return write_audit(config, &record);
     }
 
+    // Save what the command is about to change so `spren undo` can put it back
+    let data_dir = config::get_data_dir()?;
+    let history = history::History::open(&data_dir);
+    let id = history.next_id()?;
+    let timestamp = history::now();
+    let context = paths::Context::current();
+    let snapshot = if config.undo.enabled && matches!(shell_type, shell::ShellType::Bash) {
+        let store = undo::SnapshotStore::open(&data_dir);
+        store.snapshot(id, timestamp, &command, &context, &config.undo).unwrap_or_else(|e| {
+            println!("{}: not saving an undo snapshot: {}", "Note".yellow().bold(), e);
+            None
+        })
+    } else {
+        None
+    };
+
+    // The command's output streams straight to the terminal; interactive
+    // programs get a terminal of their own
+    println!();
     let start_time = Instant::now();
-    match executor::execute_command(&command).await {
+    let result = if ai_terminal || interactive::needs_terminal(&command, &shell_type) {
+        executor::execute_in_terminal(&command, &config.security).await
+    } else {
+        executor::execute_command(&command, &config.security).await
+    };
+    record.duration_ms = Some(start_time.elapsed().as_millis() as u64);
+    let success = match result {
         Ok(output) => {
+            record.exit_code = output.exit_code;
+            if let Some(stopped) = output.stopped {
+                println!("{}: the command {}", "Stopped".yellow().bold(), stopped);
+            }
+            if let Some(limit) = output.limit {
+                println!(
+                    "{}: the command {} and was stopped (security.resource_limits)",
+                    "Limit".red().bold(),
+                    limit
+                );
+            }
+            if output.truncated {
+                println!(
+                    "{}: only the first {} bytes of output were kept for analysis (security.max_output_size)",
+                    "Note".yellow().bold(),
+                    config.security.max_output_size
+                );
+            }
             if config.display.show_execution_time {
                 println!("\nExecution time: {:?}", start_time.elapsed());
             }
 
-            if !output.stdout.is_empty() {
-                println!("\n{}", output.stdout);
-            }
-
-            if !output.stderr.is_empty() {
-                if output.success {
-                    // Command succeeded but had stderr output
-                    println!("{}: {}", "Note".yellow().bold(), output.stderr);
-                } else {
-                    // Command failed
-                    println!("{}: {}", "Error".red().bold(), output.stderr);
+            if !output.success {
+                if let Some(code) = output.exit_code.filter(|code| *code != 0) {
+                    println!("{}: exit code {}", "Error".red().bold(), code);
+                }
 
-                    // Get error analysis and suggestion
+                // Get error analysis and suggestion, unless the user stopped it
+                if !output.stderr.is_empty() && output.stopped != Some(executor::Stopped::Interrupted) {
                     if let Ok(suggestion) = ai::get_error_suggestion(
                         &command,
                         &output.stdout,
                         &output.stderr,
-                        &config
+                        config
                     ).await {
                         println!("\n{}", "Suggestion:".yellow().bold());
                         println!("{}", suggestion);
                     }
                 }
             }
+            output.success
         }
         Err(e) => {
             println!("\n{}: {}", "System Error".red().bold(), e);
+            false
+        }
+    };
+
+    let entry = history::Entry {
+        id,
+        timestamp,
+        cwd: context.cwd,
+        query: secrets::redact(&query.text),
+        command: secrets::redact(&command),
+        success,
+        snapshot: snapshot.is_some(),
+    };
+    history.append(&entry, config.shell.history_size)?;
+    record.success = Some(success);
+    write_audit(config, &record)?;
+    if let Some(snapshot) = snapshot {
+        println!(
+            "Saved {} path(s) as #{}; run `spren undo {}` to restore them.",
+            snapshot.paths.len(),
+            id,
+            id
+        );
+    }
+
+    Ok(())
+}
+fn show_suggestion(command: &str, risk: RiskLevel, ai_risk: RiskLevel, assessment: &safety::Assessment) {
+    let label = format!("[{}]", risk.label().to_uppercase());
+    let label = match risk {
+        RiskLevel::ReadOnly => label.green(),
+        RiskLevel::Modifying => label.blue(),
+        RiskLevel::Destructive => label.yellow().bold(),
+        RiskLevel::SystemCritical => label.red().bold(),
+    };
+
+    println!("\n{}", "Suggested command:".blue().bold());
+    println!("{} {}", command, label);
+
+    if risk >= RiskLevel::Destructive {
+        println!("\n{}", "This command has been identified as potentially dangerous.".yellow());
+        if ai_risk >= RiskLevel::Destructive {
+            println!("  {} rated {} by the AI", "-".yellow(), ai_risk.label());
+        }
+    }
+    for finding in assessment.findings.iter().filter(|f| !f.is_privilege_escalation()) {
+        println!("  {} {}", "-".yellow(), finding);
+    }
+    let root: Vec<&safety::Finding> = assessment.runs_as_root().collect();
+    if !root.is_empty() {
+        println!("\n{}", "Runs as another user:".red().bold());
+        for finding in root {
+            println!("  {} {} ({})", "#".red(), finding.segment.as_deref().unwrap_or_default(), finding.reason);
+        }
+    }
+    if let Some(alternative) = assessment.findings.iter().find_map(|f| f.alternative.as_deref()) {
+        println!("\n{} {}", "Safer alternative:".green().bold(), alternative);
+    }
+}
+
+fn show_policy_matches(verdict: &policy::Verdict) {
+    for matched in &verdict.matches {
+        let reason = matched.reason.as_deref().map(|r| format!(": {}", r)).unwrap_or_default();
+        println!(
+            "  {} policy {} `{}` ({}){}",
+            "-".yellow(),
+            matched.action.rule_label(),
+            matched.command,
+            matched.rule,
+            reason
+        );
+    }
+}
+
+/// `spren policy test`: shows the rules a command matches and the result.
+fn policy_test(command: &str, config: &config::Config, policy: &policy::Policy) {
+    let context = paths::Context::current();
+    let verdict = policy.evaluate(command, &context);
+    if verdict.matches.is_empty() {
+        println!("No policy rule matches.");
+    }
+    show_policy_matches(&verdict);
+    if !verdict.matches.is_empty() && !verdict.all_covered {
+        println!("Commands without a matching rule fall back to the risk policy.");
+    }
+
+    let assessment = safety::analyze(command, &shell::ShellType::detect(), &config.security, &context);
+    let action = assessment.decide(assessment.risk, &config.security.risk_policy, &verdict);
+    println!(
+        "\nResult: {} (local risk {}; the model's rating may raise it)",
+        action.rule_label().bold(),
+        assessment.risk.label()
+    );
+}
+
+fn show_previews(previews: &[preview::Preview]) {
+    if previews.is_empty() {
+        return;
+    }
+    println!("\n{}", "Preview (nothing has run yet):".blue().bold());
+    for preview in previews {
+        println!("{}", preview);
+    }
+}
+
+/// Runs the command in a sandbox and shows what it would have done.
+async fn show_trial(command: &str, config: &config::Config) {
+    println!("\n{}", "Trying the command in a sandbox...".blue().bold());
+    let cwd = std::env::current_dir().unwrap_or_else(|_| std::path::PathBuf::from("."));
+    let trial = match sandbox::trial_run(command, &cwd, &config.security).await {
+        Ok(trial) => trial,
+        Err(e) => {
+            println!("{} {}", "Trial run failed:".red(), e);
+            return;
+        }
+    };
+
+    // Its output was shown as it ran
+    if let Some(stopped) = trial.output.stopped {
+        println!("{}: the trial {}", "Stopped".yellow().bold(), stopped);
+    }
+    if trial.changes.is_empty() {
+        println!("No files under {} would change.", cwd.display());
+    } else {
+        println!("Changes under {} (nothing real has changed yet):", cwd.display());
+        for change in &trial.changes {
+            println!("  {}", change);
+        }
+    }
+    if !trial.output.success && trial.output.stopped.is_none() {
+        println!("{}", "The command failed in the sandbox.".yellow());
+    }
+    for note in &trial.notes {
+        println!("{}: {}", "Note".yellow().bold(), note);
+    }
+}
+
+fn print_blocked_command(command: &str, locked: bool) {
+    let source = if locked { "by your administrator" } else { "in your config" };
+    println!(
+        "\n{}",
+        format!("Running dangerous commands from spren is disabled {}.", source).red().bold()
+    );
+    println!("If you are sure, copy the command and run it yourself:\n");
+    println!("{}", command);
+}
+
+fn write_audit(config: &config::Config, record: &audit::Record) -> Result<()> {
+    if !config.audit.enabled {
+        return Ok(());
+    }
+    let path = config.audit.log_path()?;
+    audit::AuditLog::open(&path)
+        .append(record)
+        .map_err(|e| anyhow!("Could not write the audit log {:?}: {}", path, e))?;
+    Ok(())
+}
+
+/// `spren audit verify`: checks the hash chain from the first entry.
+fn audit_verify(config: &config::Config) -> Result<()> {
+    let path = config.audit.log_path()?;
+    let verification = audit::AuditLog::open(&path).verify()?;
+    for problem in &verification.problems {
+        println!("{} {}", "✗".red(), problem);
+    }
+    if !verification.problems.is_empty() {
+        return Err(anyhow!("Audit log {:?} failed verification", path));
+    }
+    println!(
+        "{} {} entries verified in {:?}",
+        "✓".green(),
+        verification.entries,
+        path
+    );
+    println!("Head: {}", verification.head);
+    Ok(())
+}
+
+/// `spren undo`: restores a snapshot after showing what will change.
+fn undo_command(id: Option<u64>, list: bool) -> Result<()> {
+    let store = undo::SnapshotStore::open(&config::get_data_dir()?);
+    let snapshots = store.list()?;
+
+    if list {
+        if snapshots.is_empty() {
+            println!("No snapshots to undo.");
         }
+        for snapshot in &snapshots {
+            println!(
+                "#{:<5} {:<16} {}  ({} saved, in {})",
+                snapshot.id,
+                describe_age(history::now().saturating_sub(snapshot.timestamp)),
+                snapshot.command,
+                snapshot.saved_count(),
+                snapshot.cwd.display()
+            );
+        }
+        return Ok(());
+    }
+
+    let manifest = match id {
+        Some(id) => store.load(id)?,
+        None => snapshots.last().cloned().ok_or_else(|| anyhow!("No snapshots to undo"))?,
+    };
+    println!("{} #{}: {}", "Undo".blue().bold(), manifest.id, manifest.command);
+    for saved in &manifest.paths {
+        let verb = if saved.saved == undo::Saved::Absent { "remove " } else { "restore" };
+        println!("  {} {}", verb, saved.path.display());
+    }
+    print!("\nRestore these paths? [y/N] ");
+    io::stdout().flush()?;
+    let mut reply = String::new();
+    io::stdin().read_line(&mut reply)?;
+    if !matches!(reply.trim().to_lowercase().as_str(), "y" | "yes") {
+        return Ok(());
     }
 
+    let problems = store.restore(&manifest)?;
+    if problems.is_empty() {
+        println!("Restored #{}.", manifest.id);
+    } else {
+        println!("{}", "Some paths were not restored; the snapshot is kept:".yellow());
+        for problem in problems {
+            println!("  {}", problem);
+        }
+    }
     Ok(())
 }
+
+fn describe_age(seconds: u64) -> String {
+    match seconds {
+        0..=59 => "just now".to_string(),
+        60..=3599 => format!("{} min ago", seconds / 60),
+        3600..=86399 => format!("{} h ago", seconds / 3600),
+        _ => format!("{} days ago", seconds / 86400),
+    }
+}
+
+fn ask_clarifications(questions: &[ai::Clarification]) -> Result<Vec<ai::Answer>> {
+    println!("\n{}", "Spren needs more details:".blue().bold());
+    let mut answers = Vec::new();
+
+    for clarification in questions {
+        println!("\n{}", clarification.question);
+        for (i, choice) in clarification.choices.iter().enumerate() {
+            println!("  {}) {}", i + 1, choice);
+        }
+        print!("> ");
+        io::stdout().flush()?;
+
+        let mut response = String::new();
+        io::stdin().read_line(&mut response)?;
+        let response = response.trim();
+
+        // A number picks from the listed choices, anything else is free text
+        let answer = match response.parse::<usize>() {
+            Ok(n) if n >= 1 && n <= clarification.choices.len() => clarification.choices[n - 1].clone(),
+            _ if response.is_empty() => "no preference".to_string(),
+            _ => response.to_string(),
+        };
+        answers.push(ai::Answer { question: clarification.question.clone(), answer });
+    }
+
+    Ok(answers)
+}
+
 #[derive(Debug, serde::Deserialize)]
 struct GithubRelease {
     tag_name: String,
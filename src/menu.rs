@@ -0,0 +1,117 @@
+// src/menu.rs
+use anyhow::Result;
+use colored::*;
+use rustyline::DefaultEditor;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+
+/// What the user wants to do with a suggested command.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    Run,
+    Edit,
+    Explain,
+    Try,
+    /// Switch to the safer alternative a local rule suggested.
+    Safer,
+    Regenerate,
+    Copy,
+    Cancel,
+}
+
+impl Action {
+    /// Maps a menu reply to an action. Anything unrecognised cancels, so a
+    /// stray keypress never runs a command.
+    pub fn from_reply(reply: &str) -> Self {
+        match reply.trim().to_lowercase().as_str() {
+            "r" | "run" | "y" | "yes" => Action::Run,
+            "e" | "edit" => Action::Edit,
+            "x" | "explain" => Action::Explain,
+            "t" | "try" => Action::Try,
+            "s" | "safer" => Action::Safer,
+            "g" | "regenerate" => Action::Regenerate,
+            "c" | "copy" => Action::Copy,
+            _ => Action::Cancel,
+        }
+    }
+}
+
+/// Asks what to do next. `[s]afer` is only offered when there is a safer
+/// alternative to switch to.
+pub fn prompt_action(safer: bool) -> Result<Action> {
+    let safer = if safer { format!("[{}]afer  ", "s".green().bold()) } else { String::new() };
+    print!(
+        "\n[{}]un  [{}]dit  e[{}]plain  [{}]ry  {}re[{}]enerate  [{}]opy  [{}]ancel ",
+        "r".green().bold(),
+        "e".bold(),
+        "x".bold(),
+        "t".bold(),
+        safer,
+        "g".bold(),
+        "c".bold(),
+        "N".bold()
+    );
+    io::stdout().flush()?;
+
+    let mut reply = String::new();
+    io::stdin().read_line(&mut reply)?;
+    Ok(Action::from_reply(&reply))
+}
+
+/// Opens the command in a pre-filled line editor.
+pub fn edit_command(command: &str) -> Result<String> {
+    let mut editor = DefaultEditor::new()?;
+    let edited = editor.readline_with_initial("edit> ", (command, ""))?;
+    Ok(edited.trim().to_string())
+}
+
+/// Makes the user type `word` exactly before a high-risk command runs.
+pub fn confirm_typed(risk_label: &str, word: &str) -> Result<bool> {
+    print!("Type '{}' to run this {} command: ", word.bold(), risk_label);
+    io::stdout().flush()?;
+
+    let mut reply = String::new();
+    io::stdin().read_line(&mut reply)?;
+    Ok(reply.trim() == word)
+}
+
+pub fn read_feedback() -> Result<String> {
+    let mut editor = DefaultEditor::new()?;
+    Ok(editor.readline("What should be different? ")?.trim().to_string())
+}
+
+/// Prints the bare command to stdout or appends it to a file.
+pub fn copy_command(command: &str) -> Result<()> {
+    let mut editor = DefaultEditor::new()?;
+    let target = editor.readline("Copy to file (empty for stdout): ")?;
+    let target = target.trim();
+
+    if target.is_empty() {
+        println!("{}", command);
+        return Ok(());
+    }
+
+    let mut file = OpenOptions::new().create(true).append(true).open(target)?;
+    writeln!(file, "{}", command)?;
+    println!("Appended to {}", target);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_action_replies() {
+        assert_eq!(Action::from_reply("r\n"), Action::Run);
+        assert_eq!(Action::from_reply("Y"), Action::Run);
+        assert_eq!(Action::from_reply("e"), Action::Edit);
+        assert_eq!(Action::from_reply("x"), Action::Explain);
+        assert_eq!(Action::from_reply("t"), Action::Try);
+        assert_eq!(Action::from_reply("s"), Action::Safer);
+        assert_eq!(Action::from_reply("g"), Action::Regenerate);
+        assert_eq!(Action::from_reply("copy"), Action::Copy);
+        assert_eq!(Action::from_reply(""), Action::Cancel);
+        assert_eq!(Action::from_reply("rm"), Action::Cancel);
+    }
+}
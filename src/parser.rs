@@ -0,0 +1,1232 @@
+// src/parser.rs
+//! A Bash/POSIX shell parser that is just deep enough for safety analysis.
+//!
+//! It splits a command line into pipelines and simple commands, resolves
+//! quoting, collects redirections, strips environment prefixes and wrapper
+//! programs like `sudo` or `xargs`, and recursively parses command
+//! substitutions and `sh -c` / `su -c` / `eval` strings. It never expands
+//! variables or globs; that is left to the callers that need it.
+
+use anyhow::{anyhow, Result};
+use std::fmt;
+
+/// A complete command line: every pipeline joined by `;`, `&&`, `||`, `&` or
+/// newlines. The connectors themselves are not needed for analysis.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Script {
+    pub pipelines: Vec<Pipeline>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Pipeline {
+    pub stages: Vec<Stage>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stage {
+    Command(SimpleCommand),
+    /// A `( ... )` subshell or `{ ...; }` group. Redirections applied to the
+    /// group are carried by a trailing program-less command in `body`.
+    Group(Script),
+}
+
+/// One program invocation after quote removal.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SimpleCommand {
+    /// `NAME=value` prefixes, e.g. `LC_ALL=C sort`.
+    pub env: Vec<(String, String)>,
+    /// Programs that run `program` on our behalf, outermost first, e.g. `["sudo", "xargs"]`.
+    pub wrappers: Vec<String>,
+    /// The source text from each of `wrappers` to the end of the command,
+    /// options included, e.g. `sudo -u app nice -n 5 make` and `nice -n 5 make`.
+    pub wrapper_sources: Vec<String>,
+    /// Empty for a bare redirection or assignment such as `> file`.
+    pub program: String,
+    pub args: Vec<Word>,
+    pub redirects: Vec<Redirect>,
+    /// Scripts run as part of this command: `$(...)`, backticks, `<(...)`,
+    /// `sh -c '...'` and `eval` arguments.
+    pub nested: Vec<Script>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Word {
+    /// The word with quotes removed. Substitutions are kept as written.
+    pub text: String,
+    /// Whether the word contains unquoted glob characters.
+    pub glob: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Redirect {
+    pub fd: Option<u32>,
+    pub op: String,
+    pub target: String,
+    /// The body of a `<<` heredoc.
+    pub body: Option<String>,
+}
+
+impl Redirect {
+    /// Whether the redirection creates or writes to `target`.
+    pub fn writes(&self) -> bool {
+        matches!(self.op.as_str(), ">" | ">>" | ">|" | "&>" | "&>>" | "<>")
+    }
+}
+
+impl Script {
+    /// Every simple command in the script, including those inside groups and
+    /// nested scripts, in source order.
+    pub fn commands(&self) -> Vec<&SimpleCommand> {
+        let mut commands = Vec::new();
+        self.collect(&mut commands);
+        commands
+    }
+
+    fn collect<'a>(&'a self, out: &mut Vec<&'a SimpleCommand>) {
+        for pipeline in &self.pipelines {
+            for stage in &pipeline.stages {
+                match stage {
+                    Stage::Command(command) => {
+                        out.push(command);
+                        for nested in &command.nested {
+                            nested.collect(out);
+                        }
+                    }
+                    Stage::Group(body) => body.collect(out),
+                }
+            }
+        }
+    }
+}
+
+impl SimpleCommand {
+    /// The program's base name, so `/usr/bin/rm` and `rm` compare equal.
+    pub fn name(&self) -> &str {
+        self.program.rsplit('/').next().unwrap_or(&self.program)
+    }
+
+    /// Whether a short option like `-r` is set, alone or in a cluster like `-rf`.
+    pub fn has_short_flag(&self, flag: char) -> bool {
+        self.options().any(|arg| {
+            !arg.starts_with("--")
+                && arg.len() > 1
+                && arg[1..].chars().all(|c| c.is_ascii_alphanumeric())
+                && arg[1..].contains(flag)
+        })
+    }
+
+    /// Whether `--flag` or `--flag=value` is present.
+    pub fn has_long_flag(&self, flag: &str) -> bool {
+        self.options().any(|arg| {
+            arg.strip_prefix("--")
+                .is_some_and(|rest| rest == flag || rest.starts_with(&format!("{}=", flag)))
+        })
+    }
+
+    /// Arguments that start with `-`, up to a `--` terminator.
+    fn options(&self) -> impl Iterator<Item = &str> {
+        self.args
+            .iter()
+            .map(|w| w.text.as_str())
+            .take_while(|arg| *arg != "--")
+            .filter(|arg| arg.starts_with('-') && *arg != "-")
+    }
+
+    /// Arguments that are not options. Everything after `--` is an operand.
+    pub fn operands(&self) -> impl Iterator<Item = &Word> {
+        let mut after_terminator = false;
+        self.args.iter().filter(move |w| {
+            if after_terminator {
+                return true;
+            }
+            if w.text == "--" {
+                after_terminator = true;
+                return false;
+            }
+            !w.text.starts_with('-') || w.text == "-"
+        })
+    }
+}
+
+impl fmt::Display for SimpleCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts: Vec<String> = self.env.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+        parts.extend(self.wrappers.iter().cloned());
+        if !self.program.is_empty() {
+            parts.push(self.program.clone());
+        }
+        parts.extend(self.args.iter().map(|w| w.text.clone()));
+        for redirect in &self.redirects {
+            let fd = redirect.fd.map(|fd| fd.to_string()).unwrap_or_default();
+            parts.push(format!("{}{} {}", fd, redirect.op, redirect.target));
+        }
+        write!(f, "{}", parts.join(" "))
+    }
+}
+
+pub fn parse(input: &str) -> Result<Script> {
+    let mut lexer = Lexer::new(input);
+    let tokens = lexer.tokenize()?;
+    let mut parser = Parser {
+        tokens,
+        spans: lexer.spans,
+        source: lexer.chars,
+        pos: 0,
+        heredocs: lexer.heredoc_bodies,
+        case_depth: 0,
+        expect_pattern: false,
+    };
+    let script = parser.script(None)?;
+    if parser.pos < parser.tokens.len() {
+        return Err(anyhow!("unexpected {:?}", parser.tokens[parser.pos]));
+    }
+    Ok(script)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(Word, Vec<Script>),
+    Op(&'static str),
+    Redirect { fd: Option<u32>, op: &'static str },
+    Heredoc { fd: Option<u32>, delimiter: String, index: usize },
+    Newline,
+}
+
+const OPERATORS: &[&str] = &["&&", "||", ";;", "|&", ";", "&", "|", "(", ")"];
+const REDIRECTS: &[&str] = &["&>>", "&>", "<<<", ">>", ">|", ">&", "<&", "<>", ">", "<"];
+
+struct Lexer {
+    chars: Vec<char>,
+    pos: usize,
+    pending_heredocs: Vec<(String, bool)>,
+    heredoc_bodies: Vec<String>,
+    /// Where each token starts and ends in `chars`.
+    spans: Vec<(usize, usize)>,
+}
+
+impl Lexer {
+    fn new(input: &str) -> Self {
+        Lexer { chars: input.chars().collect(), pos: 0, pending_heredocs: Vec::new(), heredoc_bodies: Vec::new(), spans: Vec::new() }
+    }
+
+    fn peek(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.pos + offset).copied()
+    }
+
+    fn starts_with(&self, s: &str) -> bool {
+        s.chars().enumerate().all(|(i, c)| self.peek(i) == Some(c))
+    }
+
+    fn tokenize(&mut self) -> Result<Vec<Token>> {
+        let mut tokens = Vec::new();
+        while let Some(c) = self.peek(0) {
+            match c {
+                ' ' | '\t' | '\r' => self.pos += 1,
+                '\\' if self.peek(1) == Some('\n') => self.pos += 2,
+                '#' => {
+                    while self.peek(0).is_some_and(|c| c != '\n') {
+                        self.pos += 1;
+                    }
+                }
+                '\n' => {
+                    self.spans.push((self.pos, self.pos + 1));
+                    self.pos += 1;
+                    self.read_heredoc_bodies()?;
+                    tokens.push(Token::Newline);
+                }
+                _ => {
+                    let start = self.pos;
+                    tokens.push(self.token()?);
+                    self.spans.push((start, self.pos));
+                }
+            }
+        }
+        if let Some((delimiter, _)) = self.pending_heredocs.first() {
+            return Err(anyhow!("heredoc missing terminator '{}'", delimiter));
+        }
+        Ok(tokens)
+    }
+
+    fn token(&mut self) -> Result<Token> {
+        // Optional fd number directly before a redirection, e.g. `2>`
+        let digits = self.chars[self.pos..].iter().take_while(|c| c.is_ascii_digit()).count();
+        let after_digits = self.pos + digits;
+        let is_redirect_at = |i: usize| {
+            matches!(self.chars.get(i), Some('<') | Some('>')) && self.chars.get(i + 1) != Some(&'(')
+        };
+
+        if is_redirect_at(after_digits) || (digits == 0 && self.starts_with("&>")) {
+            let fd = if digits > 0 {
+                Some(self.chars[self.pos..after_digits].iter().collect::<String>().parse()?)
+            } else {
+                None
+            };
+            self.pos = after_digits;
+            if self.starts_with("<<") && !self.starts_with("<<<") {
+                self.pos += 2;
+                let strip_tabs = self.peek(0) == Some('-');
+                if strip_tabs {
+                    self.pos += 1;
+                }
+                while matches!(self.peek(0), Some(' ') | Some('\t')) {
+                    self.pos += 1;
+                }
+                let (word, _) = self.word()?;
+                if word.text.is_empty() {
+                    return Err(anyhow!("heredoc without a delimiter"));
+                }
+                self.pending_heredocs.push((word.text.clone(), strip_tabs));
+                let index = self.heredoc_bodies.len() + self.pending_heredocs.len() - 1;
+                return Ok(Token::Heredoc { fd, delimiter: word.text, index });
+            }
+            for op in REDIRECTS {
+                if self.starts_with(op) {
+                    self.pos += op.len();
+                    return Ok(Token::Redirect { fd, op });
+                }
+            }
+        }
+
+        for op in OPERATORS {
+            if self.starts_with(op) {
+                self.pos += op.len();
+                return Ok(Token::Op(op));
+            }
+        }
+
+        let (word, nested) = self.word()?;
+        Ok(Token::Word(word, nested))
+    }
+
+    fn read_heredoc_bodies(&mut self) -> Result<()> {
+        for (delimiter, strip_tabs) in std::mem::take(&mut self.pending_heredocs) {
+            let mut body = String::new();
+            loop {
+                if self.pos >= self.chars.len() {
+                    return Err(anyhow!("heredoc missing terminator '{}'", delimiter));
+                }
+                let end = self.chars[self.pos..].iter().position(|c| *c == '\n').map_or(self.chars.len(), |i| self.pos + i);
+                let line: String = self.chars[self.pos..end].iter().collect();
+                self.pos = (end + 1).min(self.chars.len());
+                let line = if strip_tabs { line.trim_start_matches('\t').to_string() } else { line };
+                if line == delimiter {
+                    break;
+                }
+                body.push_str(&line);
+                body.push('\n');
+            }
+            self.heredoc_bodies.push(body);
+        }
+        Ok(())
+    }
+
+    fn word(&mut self) -> Result<(Word, Vec<Script>)> {
+        let mut text = String::new();
+        let mut glob = false;
+        let mut nested = Vec::new();
+
+        while let Some(c) = self.peek(0) {
+            match c {
+                ' ' | '\t' | '\n' | '\r' | ';' | '&' | '|' | ')' => break,
+                '(' => break,
+                '<' | '>' if self.peek(1) == Some('(') => {
+                    self.pos += 2;
+                    let inner = self.balanced(')')?;
+                    nested.push(parse(&inner)?);
+                    text.push(c);
+                    text.push('(');
+                    text.push_str(&inner);
+                    text.push(')');
+                }
+                '<' | '>' => break,
+                '\\' => {
+                    match self.peek(1) {
+                        Some('\n') => {}
+                        Some(n) => text.push(n),
+                        None => {}
+                    }
+                    self.pos += 2;
+                }
+                '\'' => {
+                    self.pos += 1;
+                    let end = self.chars[self.pos..]
+                        .iter()
+                        .position(|c| *c == '\'')
+                        .ok_or_else(|| anyhow!("unterminated ' quote"))?;
+                    text.extend(&self.chars[self.pos..self.pos + end]);
+                    self.pos += end + 1;
+                }
+                '"' => {
+                    self.pos += 1;
+                    self.double_quoted(&mut text, &mut nested)?;
+                }
+                '$' if self.peek(1) == Some('\'') => {
+                    self.pos += 2;
+                    self.ansi_c_quoted(&mut text)?;
+                }
+                '$' | '`' => self.substitution(&mut text, &mut nested)?,
+                '*' | '?' | '[' => {
+                    glob = true;
+                    text.push(c);
+                    self.pos += 1;
+                }
+                _ => {
+                    text.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+
+        Ok((Word { text, glob }, nested))
+    }
+
+    fn double_quoted(&mut self, text: &mut String, nested: &mut Vec<Script>) -> Result<()> {
+        loop {
+            match self.peek(0) {
+                None => return Err(anyhow!("unterminated \" quote")),
+                Some('"') => {
+                    self.pos += 1;
+                    return Ok(());
+                }
+                Some('\\') => {
+                    match self.peek(1) {
+                        Some('\n') => {}
+                        Some(n @ ('$' | '`' | '"' | '\\')) => text.push(n),
+                        Some(n) => {
+                            text.push('\\');
+                            text.push(n);
+                        }
+                        None => return Err(anyhow!("unterminated \" quote")),
+                    }
+                    self.pos += 2;
+                }
+                Some('$') | Some('`') => self.substitution(text, nested)?,
+                Some(c) => {
+                    text.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+    }
+
+    fn ansi_c_quoted(&mut self, text: &mut String) -> Result<()> {
+        loop {
+            match self.peek(0) {
+                None => return Err(anyhow!("unterminated $' quote")),
+                Some('\'') => {
+                    self.pos += 1;
+                    return Ok(());
+                }
+                Some('\\') => {
+                    let escaped = match self.peek(1) {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some('e') | Some('E') => '\u{1b}',
+                        Some('0') => '\0',
+                        Some(c) => c,
+                        None => return Err(anyhow!("unterminated $' quote")),
+                    };
+                    text.push(escaped);
+                    self.pos += 2;
+                }
+                Some(c) => {
+                    text.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+    }
+
+    /// Handles `$(...)`, `$((...))`, `${...}`, backticks and plain `$var`,
+    /// keeping the text as written and parsing command substitutions.
+    fn substitution(&mut self, text: &mut String, nested: &mut Vec<Script>) -> Result<()> {
+        if self.peek(0) == Some('`') {
+            self.pos += 1;
+            let mut inner = String::new();
+            loop {
+                match self.peek(0) {
+                    None => return Err(anyhow!("unterminated ` quote")),
+                    Some('`') => break,
+                    Some('\\') if matches!(self.peek(1), Some('`') | Some('\\') | Some('$')) => {
+                        inner.push(self.chars[self.pos + 1]);
+                        self.pos += 2;
+                    }
+                    Some(c) => {
+                        inner.push(c);
+                        self.pos += 1;
+                    }
+                }
+            }
+            self.pos += 1;
+            nested.push(parse(&inner)?);
+            text.push('`');
+            text.push_str(&inner);
+            text.push('`');
+            return Ok(());
+        }
+
+        if self.starts_with("$((") {
+            self.pos += 3;
+            let inner = self.balanced(')')?;
+            if self.peek(0) != Some(')') {
+                return Err(anyhow!("unterminated $(( arithmetic"));
+            }
+            self.pos += 1;
+            nested.extend(substitutions_in(&inner)?);
+            text.push_str(&format!("$(({}))", inner));
+        } else if self.starts_with("$(") {
+            self.pos += 2;
+            let inner = self.balanced(')')?;
+            nested.push(parse(&inner)?);
+            text.push_str(&format!("$({})", inner));
+        } else if self.starts_with("${") {
+            self.pos += 2;
+            let inner = self.balanced('}')?;
+            nested.extend(substitutions_in(&inner)?);
+            text.push_str(&format!("${{{}}}", inner));
+        } else {
+            text.push('$');
+            self.pos += 1;
+        }
+        Ok(())
+    }
+
+    /// Reads up to the `close` that balances an already consumed opener,
+    /// respecting quotes and nesting, and returns the text in between.
+    fn balanced(&mut self, close: char) -> Result<String> {
+        let open = if close == ')' { '(' } else { '{' };
+        let start = self.pos;
+        let mut depth = 1;
+        while let Some(c) = self.peek(0) {
+            match c {
+                '\\' => self.pos += 1,
+                '\'' => {
+                    self.pos += 1;
+                    while self.peek(0).is_some_and(|c| c != '\'') {
+                        self.pos += 1;
+                    }
+                }
+                '"' => {
+                    self.pos += 1;
+                    while let Some(c) = self.peek(0) {
+                        if c == '\\' {
+                            self.pos += 1;
+                        } else if c == '"' {
+                            break;
+                        }
+                        self.pos += 1;
+                    }
+                }
+                c if c == open => depth += 1,
+                c if c == close => {
+                    depth -= 1;
+                    if depth == 0 {
+                        let inner: String = self.chars[start..self.pos].iter().collect();
+                        self.pos += 1;
+                        return Ok(inner);
+                    }
+                }
+                _ => {}
+            }
+            self.pos += 1;
+        }
+        Err(anyhow!("missing closing '{}'", close))
+    }
+}
+
+/// The command substitutions inside `$((...))` or `${...}`, which are not
+/// commands themselves but can contain some, e.g. `${X:-$(rm -rf ~)}`.
+fn substitutions_in(text: &str) -> Result<Vec<Script>> {
+    let mut lexer = Lexer::new(text);
+    let mut scratch = String::new();
+    let mut nested = Vec::new();
+    while let Some(c) = lexer.peek(0) {
+        match c {
+            '$' | '`' => lexer.substitution(&mut scratch, &mut nested)?,
+            '"' => {
+                lexer.pos += 1;
+                lexer.double_quoted(&mut scratch, &mut nested)?;
+            }
+            '\'' => {
+                lexer.pos += 1;
+                while lexer.peek(0).is_some_and(|c| c != '\'') {
+                    lexer.pos += 1;
+                }
+                lexer.pos += 1;
+            }
+            '\\' => lexer.pos += 2,
+            _ => lexer.pos += 1,
+        }
+    }
+    Ok(nested)
+}
+
+/// The quoting in effect at some point in a command.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Quoting {
+    Unquoted,
+    Single,
+    /// `$'...'`, where backslash escapes are interpreted.
+    AnsiC,
+    Double,
+}
+
+/// The quoting in effect at the end of `prefix`, the start of a command up to
+/// some point inside it. `$(...)` and backticks start afresh even inside
+/// double quotes, so `"$(cat ` ends unquoted.
+pub fn quoting_at_end(prefix: &str) -> Quoting {
+    #[derive(PartialEq)]
+    enum Context {
+        Quoted(Quoting),
+        Paren,
+        Backtick,
+    }
+    let mut stack: Vec<Context> = Vec::new();
+    let mut chars = prefix.chars().peekable();
+    while let Some(c) = chars.next() {
+        match stack.last() {
+            Some(Context::Quoted(Quoting::Single)) => {
+                if c == '\'' {
+                    stack.pop();
+                }
+            }
+            Some(Context::Quoted(Quoting::AnsiC)) => match c {
+                '\\' => {
+                    chars.next();
+                }
+                '\'' => {
+                    stack.pop();
+                }
+                _ => {}
+            },
+            Some(Context::Quoted(_)) => match c {
+                '\\' => {
+                    chars.next();
+                }
+                '"' => {
+                    stack.pop();
+                }
+                '`' => stack.push(Context::Backtick),
+                '$' if chars.peek() == Some(&'(') => {
+                    chars.next();
+                    stack.push(Context::Paren);
+                }
+                _ => {}
+            },
+            _ => match c {
+                '\\' => {
+                    chars.next();
+                }
+                '\'' => stack.push(Context::Quoted(Quoting::Single)),
+                '"' => stack.push(Context::Quoted(Quoting::Double)),
+                '$' if chars.peek() == Some(&'\'') => {
+                    chars.next();
+                    stack.push(Context::Quoted(Quoting::AnsiC));
+                }
+                '$' if chars.peek() == Some(&'(') => {
+                    chars.next();
+                    stack.push(Context::Paren);
+                }
+                '(' => stack.push(Context::Paren),
+                ')' if stack.last() == Some(&Context::Paren) => {
+                    stack.pop();
+                }
+                '`' if stack.last() == Some(&Context::Backtick) => {
+                    stack.pop();
+                }
+                '`' => stack.push(Context::Backtick),
+                _ => {}
+            },
+        }
+    }
+    match stack.last() {
+        Some(Context::Quoted(quoting)) => *quoting,
+        _ => Quoting::Unquoted,
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    /// Where each token starts and ends in `source`.
+    spans: Vec<(usize, usize)>,
+    source: Vec<char>,
+    pos: usize,
+    heredocs: Vec<String>,
+    /// How many `case` clauses we are inside.
+    case_depth: usize,
+    /// Whether the next words are a `case` pattern such as `a|b)`.
+    expect_pattern: bool,
+}
+
+/// Reserved words that introduce a command rather than being one.
+const PREFIX_KEYWORDS: &[&str] = &["!", "if", "then", "else", "elif", "do", "while", "until", "time", "fi", "done", "esac"];
+/// Reserved words whose clause is a header, not a command. Substitutions in
+/// it still run.
+const HEADER_KEYWORDS: &[&str] = &["for", "select", "case", "function", "in"];
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn is_word(&self, text: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(w, _)) if w.text == text)
+    }
+
+    fn script(&mut self, terminator: Option<&str>) -> Result<Script> {
+        let mut script = Script::default();
+        loop {
+            match self.peek() {
+                None => break,
+                Some(Token::Op(";;")) => {
+                    self.pos += 1;
+                    self.expect_pattern = self.case_depth > 0;
+                }
+                Some(Token::Newline) | Some(Token::Op(";")) | Some(Token::Op("&")) | Some(Token::Op("&&"))
+                | Some(Token::Op("||")) => self.pos += 1,
+                Some(Token::Op(")")) if terminator == Some(")") => break,
+                // Stray `)` from case patterns like `a) cmd ;;`
+                Some(Token::Op(")")) => self.pos += 1,
+                _ if terminator == Some("}") && self.is_word("}") => break,
+                _ if self.expect_pattern => {
+                    if let Some(stage) = self.case_pattern()? {
+                        script.pipelines.push(Pipeline { stages: vec![stage] });
+                    }
+                }
+                _ => {
+                    let pipeline = self.pipeline(terminator)?;
+                    if !pipeline.stages.is_empty() {
+                        script.pipelines.push(pipeline);
+                    }
+                }
+            }
+        }
+        Ok(script)
+    }
+
+    /// Skips a `case` pattern such as `a|b)` or `(*.log)`, keeping any
+    /// substitutions in it.
+    fn case_pattern(&mut self) -> Result<Option<Stage>> {
+        self.expect_pattern = false;
+        if self.is_word("esac") {
+            return Ok(None);
+        }
+        if self.peek() == Some(&Token::Op("(")) {
+            self.pos += 1;
+        }
+        let mut pattern = SimpleCommand::default();
+        loop {
+            match self.peek().cloned() {
+                Some(Token::Word(_, nested)) => {
+                    self.pos += 1;
+                    pattern.nested.extend(nested);
+                }
+                Some(Token::Op("|")) => self.pos += 1,
+                Some(Token::Op(")")) => {
+                    self.pos += 1;
+                    break;
+                }
+                _ => return Err(anyhow!("unterminated case pattern")),
+            }
+        }
+        Ok((!pattern.nested.is_empty()).then_some(Stage::Command(pattern)))
+    }
+
+    /// The words of a `for`, `select`, `case` or `function` header. None of
+    /// them is a program, but substitutions in them run.
+    fn header(&mut self, words: Vec<Word>, mut command: SimpleCommand) -> Result<Option<Stage>> {
+        if words.first().is_some_and(|w| w.text == "case") {
+            self.case_depth += 1;
+            // `case $x in a) cmd ;;` puts the first pattern on the same line
+            let patterns = words.iter().position(|w| w.text == "in").map_or(0, |i| words.len() - i - 1);
+            if patterns == 0 {
+                self.expect_pattern = true;
+            } else {
+                // The rest of an `a|b)` pattern
+                while self.peek() == Some(&Token::Op("|")) {
+                    self.pos += 1;
+                    if let Some(Token::Word(_, nested)) = self.peek().cloned() {
+                        self.pos += 1;
+                        command.nested.extend(nested);
+                    }
+                }
+                if self.peek() != Some(&Token::Op(")")) {
+                    return Err(anyhow!("unterminated case pattern"));
+                }
+                self.pos += 1;
+            }
+        }
+        if command.nested.is_empty() && command.redirects.is_empty() {
+            return Ok(None);
+        }
+        command.env.clear();
+        Ok(Some(Stage::Command(command)))
+    }
+
+    fn pipeline(&mut self, terminator: Option<&str>) -> Result<Pipeline> {
+        let mut pipeline = Pipeline::default();
+        loop {
+            if let Some(stage) = self.stage(terminator)? {
+                pipeline.stages.push(stage);
+            }
+            match self.peek() {
+                Some(Token::Op("|")) | Some(Token::Op("|&")) => {
+                    self.pos += 1;
+                    while self.peek() == Some(&Token::Newline) {
+                        self.pos += 1;
+                    }
+                }
+                _ => return Ok(pipeline),
+            }
+        }
+    }
+
+    fn stage(&mut self, terminator: Option<&str>) -> Result<Option<Stage>> {
+        if self.peek() == Some(&Token::Op("(")) {
+            self.pos += 1;
+            let body = self.script(Some(")"))?;
+            if self.peek() != Some(&Token::Op(")")) {
+                return Err(anyhow!("missing closing ')'"));
+            }
+            self.pos += 1;
+            return Ok(Some(self.group(body)?));
+        }
+        if self.is_word("{") {
+            self.pos += 1;
+            let body = self.script(Some("}"))?;
+            if !self.is_word("}") {
+                return Err(anyhow!("missing closing '}}'"));
+            }
+            self.pos += 1;
+            return Ok(Some(self.group(body)?));
+        }
+        self.simple(terminator)
+    }
+
+    /// Attaches redirections that follow a group to a program-less command.
+    fn group(&mut self, mut body: Script) -> Result<Stage> {
+        let mut trailer = SimpleCommand::default();
+        while let Some(redirect) = self.redirect()? {
+            trailer.redirects.push(redirect);
+        }
+        if !trailer.redirects.is_empty() {
+            body.pipelines.push(Pipeline { stages: vec![Stage::Command(trailer)] });
+        }
+        Ok(Stage::Group(body))
+    }
+
+    fn redirect(&mut self) -> Result<Option<Redirect>> {
+        match self.peek().cloned() {
+            Some(Token::Redirect { fd, op }) => {
+                self.pos += 1;
+                match self.peek().cloned() {
+                    Some(Token::Word(target, _)) => {
+                        self.pos += 1;
+                        Ok(Some(Redirect { fd, op: op.to_string(), target: target.text, body: None }))
+                    }
+                    _ => Err(anyhow!("missing target after '{}'", op)),
+                }
+            }
+            Some(Token::Heredoc { fd, delimiter, index }) => {
+                self.pos += 1;
+                Ok(Some(Redirect {
+                    fd,
+                    op: "<<".to_string(),
+                    target: delimiter,
+                    body: self.heredocs.get(index).cloned(),
+                }))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn simple(&mut self, terminator: Option<&str>) -> Result<Option<Stage>> {
+        let mut words: Vec<Word> = Vec::new();
+        let mut starts: Vec<usize> = Vec::new();
+        let mut command = SimpleCommand::default();
+        let first_token = self.pos;
+
+        loop {
+            if let Some(redirect) = self.redirect()? {
+                command.redirects.push(redirect);
+                continue;
+            }
+            match self.peek().cloned() {
+                Some(Token::Word(word, nested)) => {
+                    if terminator == Some("}") && word.text == "}" && words.is_empty() {
+                        break;
+                    }
+                    // `function f { ...; }`: the body is a group of its own
+                    if word.text == "{" && words.len() == 2 && words[0].text == "function" {
+                        break;
+                    }
+                    starts.push(self.spans[self.pos].0);
+                    self.pos += 1;
+                    command.nested.extend(nested);
+                    words.push(word);
+                }
+                _ => break,
+            }
+        }
+
+        // `f() { ...; }` defines a function; its body follows as a group
+        if words.len() == 1 && self.peek() == Some(&Token::Op("(")) && self.tokens.get(self.pos + 1) == Some(&Token::Op(")")) {
+            self.pos += 2;
+            return Ok(None);
+        }
+
+        let end = if self.pos > first_token { self.spans[self.pos - 1].1 } else { 0 };
+        let mut words = words.into_iter().peekable();
+        let mut keywords = 0;
+        while let Some(word) = words.peek() {
+            if PREFIX_KEYWORDS.contains(&word.text.as_str()) {
+                if word.text == "esac" {
+                    self.case_depth = self.case_depth.saturating_sub(1);
+                    self.expect_pattern = false;
+                }
+                words.next();
+                keywords += 1;
+            } else if HEADER_KEYWORDS.contains(&word.text.as_str()) {
+                return self.header(words.collect(), command);
+            } else {
+                break;
+            }
+        }
+
+        let mut words: Vec<Word> = words.collect();
+        let assignments = words.iter().take_while(|w| is_assignment(&w.text)).count();
+        for word in words.drain(..assignments) {
+            let (name, value) = word.text.split_once('=').unwrap_or((&word.text, ""));
+            command.env.push((name.to_string(), value.to_string()));
+        }
+
+        let mut offsets = Vec::new();
+        let words = unwrap_wrappers(words, &mut command.wrappers, &mut offsets);
+        command.wrapper_sources = offsets
+            .iter()
+            .map(|offset| self.source[starts[keywords + assignments + offset]..end].iter().collect())
+            .collect();
+        let mut words = words.into_iter();
+        if let Some(program) = words.next() {
+            command.program = program.text;
+        }
+        command.args = words.collect();
+
+        if command.program.is_empty() && command.redirects.is_empty() && command.env.is_empty() {
+            return Ok(None);
+        }
+
+        nested_code(&mut command)?;
+        Ok(Some(Stage::Command(command)))
+    }
+}
+
+/// Parses the arguments `command` runs as code: `sh -c` and `eval` strings
+/// and `find -exec` commands.
+fn nested_code(command: &mut SimpleCommand) -> Result<()> {
+    let shells = ["sh", "bash", "dash", "zsh", "ksh", "su"];
+    if shells.contains(&command.name()) {
+        let is_c_flag = |w: &Word| w.text.starts_with('-') && !w.text.starts_with("--") && w.text.ends_with('c');
+        if let Some(index) = command.args.iter().position(is_c_flag) {
+            if let Some(code) = command.args.get(index + 1) {
+                command.nested.push(parse(&code.text)?);
+            }
+        }
+    } else if command.name() == "eval" {
+        let code = command.args.iter().map(|w| w.text.as_str()).collect::<Vec<_>>().join(" ");
+        command.nested.push(parse(&code)?);
+    } else if command.name() == "find" {
+        // `-exec rm {} \;` and `-exec rm {} +` run a command on the matches
+        let mut bodies = Vec::new();
+        let mut args = command.args.iter();
+        while let Some(arg) = args.next() {
+            if matches!(arg.text.as_str(), "-exec" | "-execdir" | "-ok" | "-okdir") {
+                bodies.push(args.by_ref().take_while(|w| w.text != ";" && w.text != "+").cloned().collect::<Vec<_>>());
+            }
+        }
+        for body in bodies {
+            let mut inner = SimpleCommand::default();
+            let mut words = unwrap_wrappers(body, &mut inner.wrappers, &mut Vec::new()).into_iter();
+            let Some(program) = words.next() else { continue };
+            inner.program = program.text;
+            inner.args = words.collect();
+            nested_code(&mut inner)?;
+            command.nested.push(Script { pipelines: vec![Pipeline { stages: vec![Stage::Command(inner)] }] });
+        }
+    }
+    Ok(())
+}
+
+fn is_assignment(word: &str) -> bool {
+    match word.split_once('=') {
+        Some((name, _)) => {
+            !name.is_empty()
+                && name.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        None => false,
+    }
+}
+
+/// Wrappers and the options of theirs that take a separate value.
+const WRAPPERS: &[(&str, &[&str])] = &[
+    ("sudo", &["-u", "-g", "-C", "-D", "-h", "-p", "-r", "-t", "-U", "-T", "--user", "--group"]),
+    ("doas", &["-u", "-C"]),
+    ("pkexec", &["--user"]),
+    ("env", &["-u", "-C", "-S", "--unset", "--chdir"]),
+    ("nice", &["-n", "--adjustment"]),
+    ("ionice", &["-c", "-n", "-p", "--class", "--classdata"]),
+    ("timeout", &["-s", "-k", "--signal", "--kill-after"]),
+    ("xargs", &["-I", "-i", "-n", "-P", "-d", "-L", "-l", "-a", "-E", "-e", "-s", "--max-args", "--max-procs", "--delimiter", "--arg-file", "--replace"]),
+    ("nohup", &[]),
+    ("time", &["-f", "-o", "--format", "--output"]),
+    ("exec", &["-a"]),
+    ("command", &[]),
+    ("builtin", &[]),
+    ("stdbuf", &["-i", "-o", "-e"]),
+    ("chroot", &[]),
+    ("watch", &["-n", "-d", "--interval"]),
+    ("strace", &["-o", "-e", "-p", "-s"]),
+    ("unbuffer", &[]),
+];
+
+/// Peels wrapper programs off the front of `words`, recording them and the
+/// index in `words` each one started at, and returns the words of the
+/// program that actually runs.
+fn unwrap_wrappers(mut words: Vec<Word>, wrappers: &mut Vec<String>, offsets: &mut Vec<usize>) -> Vec<Word> {
+    let mut consumed = 0;
+    loop {
+        let Some(first) = words.first() else { return words };
+        let name = first.text.rsplit('/').next().unwrap_or(&first.text).to_string();
+        let Some((_, value_options)) = WRAPPERS.iter().find(|(wrapper, _)| *wrapper == name) else {
+            return words;
+        };
+
+        let mut i = 1;
+        while let Some(word) = words.get(i) {
+            let text = word.text.as_str();
+            if text == "--" {
+                i += 1;
+                break;
+            }
+            if name == "env" && is_assignment(text) {
+                i += 1;
+                continue;
+            }
+            if !text.starts_with('-') || text == "-" {
+                break;
+            }
+            i += if value_options.contains(&text) { 2 } else { 1 };
+        }
+        // `timeout 10 cmd`, `chroot /mnt cmd`: one positional before the program
+        if matches!(name.as_str(), "timeout" | "chroot") {
+            i += 1;
+        }
+
+        if i >= words.len() {
+            // A wrapper with nothing to run is itself the program: `sudo -l`
+            return words;
+        }
+        wrappers.push(name);
+        offsets.push(consumed);
+        consumed += i;
+        words.drain(..i);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn commands(input: &str) -> Vec<SimpleCommand> {
+        parse(input).unwrap().commands().into_iter().cloned().collect()
+    }
+
+    fn programs(input: &str) -> Vec<String> {
+        commands(input).into_iter().map(|c| c.program).collect()
+    }
+
+    fn args(command: &SimpleCommand) -> Vec<&str> {
+        command.args.iter().map(|w| w.text.as_str()).collect()
+    }
+
+    #[test]
+    fn test_simple_command() {
+        let cmds = commands("ls -la /tmp");
+        assert_eq!(cmds.len(), 1);
+        assert_eq!(cmds[0].program, "ls");
+        assert_eq!(args(&cmds[0]), vec!["-la", "/tmp"]);
+    }
+
+    #[test]
+    fn test_lists_and_pipelines() {
+        assert_eq!(programs("a && b || c; d & e | f |& g"), vec!["a", "b", "c", "d", "e", "f", "g"]);
+        let script = parse("ps aux | grep x | wc -l").unwrap();
+        assert_eq!(script.pipelines.len(), 1);
+        assert_eq!(script.pipelines[0].stages.len(), 3);
+    }
+
+    #[test]
+    fn test_quoting() {
+        let cmds = commands(r#"echo 'a b' "c $HOME" d\ e $'x\ty'"#);
+        assert_eq!(args(&cmds[0]), vec!["a b", "c $HOME", "d e", "x\ty"]);
+    }
+
+    #[test]
+    fn test_whitespace_and_flag_variants() {
+        for input in ["rm  -rf /", "rm -r -f /", "rm -fr /", "rm --recursive --force /", "rm -rf -- /"] {
+            let cmd = &commands(input)[0];
+            assert_eq!(cmd.name(), "rm");
+            assert!(cmd.has_short_flag('r') || cmd.has_long_flag("recursive"), "{}", input);
+            assert_eq!(cmd.operands().map(|w| w.text.as_str()).collect::<Vec<_>>(), vec!["/"]);
+        }
+    }
+
+    #[test]
+    fn test_redirections() {
+        let cmd = &commands("sort <in.txt >out.txt 2>>err.log &>/dev/null")[0];
+        assert_eq!(cmd.program, "sort");
+        assert!(cmd.args.is_empty());
+        let redirects: Vec<(Option<u32>, &str, &str)> =
+            cmd.redirects.iter().map(|r| (r.fd, r.op.as_str(), r.target.as_str())).collect();
+        assert_eq!(
+            redirects,
+            vec![(None, "<", "in.txt"), (None, ">", "out.txt"), (Some(2), ">>", "err.log"), (None, "&>", "/dev/null")]
+        );
+        assert!(cmd.redirects[1].writes());
+        assert!(!cmd.redirects[0].writes());
+    }
+
+    #[test]
+    fn test_bare_redirection() {
+        let cmd = &commands("> /dev/sda")[0];
+        assert!(cmd.program.is_empty());
+        assert_eq!(cmd.redirects[0].target, "/dev/sda");
+    }
+
+    #[test]
+    fn test_heredoc() {
+        let cmd = &commands("cat <<'EOF' > notes.txt\nline one\nEOF\necho done")[0];
+        assert_eq!(cmd.redirects[0].op, "<<");
+        assert_eq!(cmd.redirects[0].body.as_deref(), Some("line one\n"));
+        assert_eq!(cmd.redirects[1].target, "notes.txt");
+        assert_eq!(programs("cat <<EOF\nrm -rf /\nEOF\necho done"), vec!["cat", "echo"]);
+    }
+
+    #[test]
+    fn test_env_prefix_and_wrappers() {
+        let cmd = &commands("FOO=1 BAR=2 sudo -u root -E nice -n 5 rm -rf /tmp/x")[0];
+        assert_eq!(cmd.env, vec![("FOO".to_string(), "1".to_string()), ("BAR".to_string(), "2".to_string())]);
+        assert_eq!(cmd.wrappers, vec!["sudo", "nice"]);
+        assert_eq!(cmd.program, "rm");
+        assert_eq!(cmd.wrapper_sources, vec!["sudo -u root -E nice -n 5 rm -rf /tmp/x", "nice -n 5 rm -rf /tmp/x"]);
+
+        let cmd = &commands("if true; then sudo tee '/etc/a b' > /dev/null; fi")[1];
+        assert_eq!(cmd.wrapper_sources, vec!["sudo tee '/etc/a b' > /dev/null"]);
+
+        let cmd = &commands("find . -name '*.tmp' | xargs -0 -n 10 rm -f")[1];
+        assert_eq!(cmd.wrappers, vec!["xargs"]);
+        assert_eq!(cmd.program, "rm");
+
+        let cmd = &commands("env -i PATH=/bin timeout 5 /usr/bin/dd if=a of=b")[0];
+        assert_eq!(cmd.wrappers, vec!["env", "timeout"]);
+        assert_eq!(cmd.name(), "dd");
+
+        assert_eq!(commands("sudo -l")[0].program, "sudo");
+    }
+
+    #[test]
+    fn test_subshells_and_groups() {
+        assert_eq!(programs("(cd /tmp && rm x) ; { echo a; echo b; } > out"), vec!["cd", "rm", "echo", "echo", ""]);
+        let script = parse("(curl x) | sh").unwrap();
+        assert!(matches!(script.pipelines[0].stages[0], Stage::Group(_)));
+    }
+
+    #[test]
+    fn test_command_substitution() {
+        assert_eq!(programs("echo $(whoami) `date` \"$(rm -rf ~)\""), vec!["echo", "whoami", "date", "rm"]);
+        // eval re-parses its argument, so the download shows up twice
+        assert_eq!(programs("eval \"$(curl -s https://x)\""), vec!["eval", "curl", "$(curl -s https://x)", "curl"]);
+        assert_eq!(programs("diff <(ls a) <(ls b)"), vec!["diff", "ls", "ls"]);
+        assert_eq!(programs("su - root -c 'rm -f /x'"), vec!["su", "rm"]);
+        assert_eq!(programs("echo $((1 << 4)) ${HOME}"), vec!["echo"]);
+    }
+
+    #[test]
+    fn test_shell_c_strings() {
+        assert_eq!(programs("bash -c 'rm -rf /'"), vec!["bash", "rm"]);
+        assert_eq!(programs("sudo sh -c \"echo hi > /etc/motd\""), vec!["sh", "echo"]);
+    }
+
+    #[test]
+    fn test_compound_commands() {
+        assert_eq!(programs("for f in *.log; do gzip \"$f\"; done"), vec!["gzip"]);
+        assert_eq!(programs("if [ -f x ]; then rm x; else touch x; fi"), vec!["[", "rm", "touch"]);
+        // The loop's input redirection is kept on a program-less command
+        assert_eq!(programs("while read l; do echo \"$l\"; done < file"), vec!["read", "echo", ""]);
+        assert_eq!(programs("case $1 in\n  a) rm a ;;\n  *) ls ;;\nesac"), vec!["rm", "ls"]);
+        assert_eq!(programs("case $1 in a|b) rm a ;; (*) ls ;; esac; echo"), vec!["rm", "ls", "echo"]);
+    }
+
+    #[test]
+    fn test_headers_keep_substitutions() {
+        assert_eq!(programs("case $(rm -rf /) in *) ;; esac"), vec!["", "rm"]);
+        assert_eq!(programs("case x in $(rm -rf /)) ls ;; esac"), vec!["", "rm", "ls"]);
+        assert_eq!(programs("for f in $(rm -rf /); do echo \"$f\"; done"), vec!["", "rm", "echo"]);
+        assert_eq!(programs("select x in `reboot`; do break; done"), vec!["", "reboot", "break"]);
+        assert_eq!(programs("function f { rm -rf /; }; f"), vec!["rm", "f"]);
+        assert_eq!(programs("function f() { rm -rf /; }"), vec!["rm"]);
+        assert_eq!(programs("f() { rm -rf /; }; f"), vec!["rm", "f"]);
+    }
+
+    #[test]
+    fn test_substitutions_in_expansions() {
+        assert_eq!(programs("echo $(( $(rm -rf /) ))"), vec!["echo", "rm"]);
+        assert_eq!(programs("echo \"${X:-$(rm -rf ~)}\" ${Y:-`reboot`}"), vec!["echo", "rm", "reboot"]);
+        assert_eq!(programs("echo $(( ${N:-$(id -u)} + 1 ))"), vec!["echo", "id"]);
+    }
+
+    #[test]
+    fn test_find_exec() {
+        assert_eq!(programs("find / -name '*.bak' -exec rm -rf {} +"), vec!["find", "rm"]);
+        assert_eq!(programs("find . -execdir sh -c 'shred \"$1\"' _ {} \\; -print"), vec!["find", "sh", "shred"]);
+        let cmds = commands("find . -ok sudo rm {} \\;");
+        assert_eq!((cmds[1].wrappers.clone(), args(&cmds[1])), (vec!["sudo".to_string()], vec!["{}"]));
+    }
+
+    #[test]
+    fn test_quoting_at_end() {
+        assert_eq!(quoting_at_end("cat "), Quoting::Unquoted);
+        assert_eq!(quoting_at_end("echo 'a"), Quoting::Single);
+        assert_eq!(quoting_at_end(r#"echo "a'b"#), Quoting::Double);
+        assert_eq!(quoting_at_end(r#"echo "a""#), Quoting::Unquoted);
+        assert_eq!(quoting_at_end(r#"echo "a\"b"#), Quoting::Double);
+        assert_eq!(quoting_at_end(r"echo $'a\'b"), Quoting::AnsiC);
+        assert_eq!(quoting_at_end(r#"echo "$(cat "#), Quoting::Unquoted);
+        assert_eq!(quoting_at_end(r#"echo "$(cat) "#), Quoting::Double);
+        assert_eq!(quoting_at_end(r#"echo "`cat '"#), Quoting::Single);
+        assert_eq!(quoting_at_end(r"echo \'"), Quoting::Unquoted);
+    }
+
+    #[test]
+    fn test_globs() {
+        let cmd = &commands("rm *.log 'b*' c")[0];
+        assert_eq!(cmd.args.iter().map(|w| w.glob).collect::<Vec<_>>(), vec![true, false, false]);
+    }
+
+    #[test]
+    fn test_not_confused_by_substrings() {
+        assert_eq!(programs("git add . && git diff --stat | odd"), vec!["git", "git", "odd"]);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(parse("echo 'open").is_err());
+        assert!(parse("echo \"open").is_err());
+        assert!(parse("echo $(open").is_err());
+        assert!(parse("(echo").is_err());
+        assert!(parse("cat <<EOF\nno end").is_err());
+        assert!(parse("echo >").is_err());
+    }
+}
@@ -0,0 +1,376 @@
+// src/paths.rs
+use crate::parser::{Pipeline, Script, SimpleCommand, Stage, Word};
+use crate::safety;
+use std::path::{Component, Path, PathBuf};
+
+/// A file system path a command is predicted to read or write.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TouchedPath {
+    /// The word as written in the command, e.g. `~/notes/*.md`.
+    pub raw: String,
+    /// Absolute, lexically normalised path. Globs are kept unexpanded.
+    pub path: PathBuf,
+    pub write: bool,
+    pub glob: bool,
+    /// The word cannot be resolved before the command runs, so `path` is
+    /// just the word as written.
+    pub unresolved: bool,
+}
+
+/// Where relative paths and `~` resolve to.
+#[derive(Debug, Clone)]
+pub struct Context {
+    /// Empty once a `cd` goes somewhere we cannot know statically.
+    pub cwd: PathBuf,
+    pub home: Option<PathBuf>,
+}
+
+impl Context {
+    pub fn current() -> Self {
+        Context {
+            cwd: std::env::current_dir().unwrap_or_else(|_| PathBuf::from("/")),
+            home: dirs::home_dir(),
+        }
+    }
+
+    /// Resolves a word to an absolute path, or `None` if it is not a local
+    /// path we can know statically (variables, `~user`, URLs, remote
+    /// `host:path`, or relative paths after an unknown `cd`).
+    pub fn resolve(&self, word: &str) -> Option<PathBuf> {
+        let expanded = self.expand_home(word)?;
+        if expanded.is_empty()
+            || expanded == "-"
+            || expanded.contains('$')
+            || expanded.contains('`')
+            || expanded.starts_with('~')
+            || expanded.contains("://")
+            || is_remote(&expanded)
+        {
+            return None;
+        }
+        if !Path::new(&expanded).is_absolute() && self.cwd.as_os_str().is_empty() {
+            return None;
+        }
+        Some(normalize(&self.cwd.join(expanded)))
+    }
+
+    /// Whether `word` is a local path that only the shell can resolve, so it
+    /// could be anywhere. URLs, remote paths and `-` are not local.
+    pub fn is_unresolvable(&self, word: &str) -> bool {
+        self.resolve(word).is_none()
+            && (word.contains(['$', '`'])
+                || !(word.is_empty() || word == "-" || word.contains("://") || is_remote(word)))
+    }
+
+    fn expand_home(&self, word: &str) -> Option<String> {
+        for prefix in ["~", "$HOME", "${HOME}"] {
+            if let Some(rest) = word.strip_prefix(prefix) {
+                if rest.is_empty() || rest.starts_with('/') {
+                    let home = self.home.as_ref()?;
+                    return Some(format!("{}{}", home.display(), rest));
+                }
+            }
+        }
+        Some(word.to_string())
+    }
+}
+
+/// `host:path` and `user@host:path` arguments to scp/rsync.
+fn is_remote(word: &str) -> bool {
+    match word.split_once(':') {
+        Some((host, _)) => !host.is_empty() && !host.contains('/'),
+        None => false,
+    }
+}
+
+/// Removes `.` and `..` components without touching the file system.
+pub fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                out.pop();
+            }
+            other => out.push(other.as_os_str()),
+        }
+    }
+    out
+}
+
+/// Resolves symlinks in the longest existing prefix of `path`, so a link
+/// inside an allowed directory cannot hide a target outside it.
+pub fn canonicalize_existing(path: &Path) -> PathBuf {
+    let mut existing = path.to_path_buf();
+    let mut rest = Vec::new();
+    while !existing.exists() {
+        match (existing.file_name().map(|n| n.to_os_string()), existing.parent()) {
+            (Some(name), Some(parent)) => {
+                rest.push(name);
+                existing = parent.to_path_buf();
+            }
+            _ => return path.to_path_buf(),
+        }
+    }
+    let mut resolved = existing.canonicalize().unwrap_or(existing);
+    for name in rest.into_iter().rev() {
+        resolved.push(name);
+    }
+    resolved
+}
+
+/// The part of a glob path before the first component with glob characters.
+/// Every match of the glob lies inside this directory.
+pub fn glob_base(path: &Path) -> PathBuf {
+    let mut base = PathBuf::new();
+    for component in path.components() {
+        let text = component.as_os_str().to_string_lossy();
+        if text.contains(['*', '?', '[']) {
+            break;
+        }
+        base.push(component.as_os_str());
+    }
+    base
+}
+
+/// Predicts the paths every command in `script` reads and writes, following
+/// `cd` so `cd /etc && rm hosts` resolves to `/etc/hosts`.
+pub fn touched_paths(script: &Script, context: &Context) -> Vec<TouchedPath> {
+    let mut touched = Vec::new();
+    walk_commands(script, context, &mut |cmd, _, context| command_paths(cmd, context, &mut touched));
+    touched
+}
+
+/// Calls `visit` for every simple command with its pipeline and the directory
+/// it will run in.
+/// `cd` and `pushd` update the directory for the commands after them but are
+/// not visited themselves; subshells and substitutions get their own copy.
+pub fn walk_commands(
+    script: &Script,
+    context: &Context,
+    visit: &mut impl FnMut(&SimpleCommand, &Pipeline, &Context),
+) {
+    let mut context = context.clone();
+    for pipeline in &script.pipelines {
+        for stage in &pipeline.stages {
+            match stage {
+                Stage::Command(cmd) => {
+                    for nested in &cmd.nested {
+                        walk_commands(nested, &context, visit);
+                    }
+                    if cmd.name() == "cd" || cmd.name() == "pushd" {
+                        let target = cmd.operands().next().map(|w| w.text.as_str()).unwrap_or("~");
+                        context.cwd = context.resolve(target).unwrap_or_default();
+                        continue;
+                    }
+                    visit(cmd, pipeline, &context);
+                }
+                Stage::Group(body) => walk_commands(body, &context, visit),
+            }
+        }
+    }
+}
+
+/// Programs whose operands are all files.
+const FILE_PROGRAMS: &[&str] = &[
+    "rm", "rmdir", "unlink", "shred", "touch", "mkdir", "truncate", "tee", "mv", "chmod", "chown", "chgrp",
+    "sed", "cp", "install", "ln", "rsync", "scp",
+];
+
+fn command_paths(cmd: &SimpleCommand, context: &Context, touched: &mut Vec<TouchedPath>) {
+    let mut push = |word: &str, glob: bool, write: bool| match context.resolve(word) {
+        Some(path) => touched.push(TouchedPath { raw: word.to_string(), path, write, glob, unresolved: false }),
+        None if context.is_unresolvable(word) => {
+            touched.push(TouchedPath { raw: word.to_string(), path: PathBuf::from(word), write, glob, unresolved: true })
+        }
+        None => {}
+    };
+
+    for redirect in &cmd.redirects {
+        if matches!(redirect.op.as_str(), "<<" | "<<<" | ">&" | "<&") {
+            continue;
+        }
+        push(&redirect.target, false, redirect.writes());
+    }
+
+    let read_only = safety::is_read_only(cmd);
+    let name = cmd.name();
+    let operands: Vec<&Word> = cmd.operands().collect();
+
+    if FILE_PROGRAMS.contains(&name) {
+        let skip = match name {
+            "chmod" | "chown" | "chgrp" if !cmd.has_long_flag("reference") => 1,
+            "sed" if !cmd.has_short_flag('e') && !cmd.has_short_flag('f') => 1,
+            _ => 0,
+        };
+        let files = &operands[skip.min(operands.len())..];
+        let copies = matches!(name, "cp" | "install" | "ln" | "rsync" | "scp");
+        let sed_reads = name == "sed" && !cmd.has_short_flag('i') && !cmd.has_long_flag("in-place");
+        for (i, word) in files.iter().enumerate() {
+            // Copies only write their destination
+            let write = !sed_reads && (!copies || i + 1 == files.len());
+            push(&word.text, word.glob, write);
+        }
+        return;
+    }
+
+    match name {
+        "dd" => {
+            for word in &operands {
+                if let Some(path) = word.text.strip_prefix("of=") {
+                    push(path, false, true);
+                } else if let Some(path) = word.text.strip_prefix("if=") {
+                    push(path, false, false);
+                }
+            }
+        }
+        "find" => {
+            // Starting points come before the first predicate
+            for word in cmd.args.iter().take_while(|w| !w.text.starts_with(['-', '(', '!'])) {
+                push(&word.text, word.glob, !read_only);
+            }
+        }
+        _ => {
+            for word in operands.iter().filter(|w| looks_like_path(w)) {
+                push(&word.text, word.glob, !read_only);
+            }
+        }
+    }
+}
+
+fn looks_like_path(word: &Word) -> bool {
+    let text = word.text.as_str();
+    word.glob
+        || text == "."
+        || text == ".."
+        || text.starts_with(['/', '~', '.'])
+        || text.starts_with("$HOME")
+        || text.starts_with("${HOME}")
+        || (text.contains('/') && !text.contains("://"))
+}
+
+/// The directories from `security.allowed_directories`, resolved against the
+/// current directory and home.
+pub fn allowed_roots(allowed: &[String], context: &Context) -> Vec<PathBuf> {
+    allowed
+        .iter()
+        .filter_map(|dir| context.resolve(dir))
+        .map(|dir| canonicalize_existing(&dir))
+        .collect()
+}
+
+/// Whether a written path stays inside one of `roots`. Globs are judged by
+/// the directory they expand in; a path we cannot resolve never is.
+pub fn is_allowed(touched: &TouchedPath, roots: &[PathBuf]) -> bool {
+    if touched.unresolved {
+        return false;
+    }
+    let path = if touched.glob { glob_base(&touched.path) } else { touched.path.clone() };
+    let path = canonicalize_existing(&path);
+    roots.iter().any(|root| path.starts_with(root))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+
+    fn context() -> Context {
+        Context { cwd: PathBuf::from("/home/me/project"), home: Some(PathBuf::from("/home/me")) }
+    }
+
+    fn writes(command: &str) -> Vec<String> {
+        touched_paths(&parser::parse(command).unwrap(), &context())
+            .into_iter()
+            .filter(|t| t.write)
+            .map(|t| t.path.display().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_resolve() {
+        let ctx = context();
+        assert_eq!(ctx.resolve("a/../b/./c"), Some(PathBuf::from("/home/me/project/b/c")));
+        assert_eq!(ctx.resolve("~/x"), Some(PathBuf::from("/home/me/x")));
+        assert_eq!(ctx.resolve("$HOME"), Some(PathBuf::from("/home/me")));
+        assert_eq!(ctx.resolve("../../.."), Some(PathBuf::from("/")));
+        assert_eq!(ctx.resolve("$TMPDIR/x"), None);
+        assert_eq!(ctx.resolve("https://example.com/x"), None);
+        assert_eq!(ctx.resolve("user@host:backup/"), None);
+        assert_eq!(ctx.resolve("~other/x"), None);
+        assert!(ctx.is_unresolvable("~other/x"));
+        assert!(ctx.is_unresolvable("$(mktemp -d)/x"));
+        assert!(!ctx.is_unresolvable("user@host:backup/"));
+        assert!(!ctx.is_unresolvable("-"));
+    }
+
+    #[test]
+    fn test_file_programs() {
+        assert_eq!(writes("rm -rf build /tmp/x"), vec!["/home/me/project/build", "/tmp/x"]);
+        assert_eq!(writes("cp a.txt b.txt /etc/"), vec!["/etc"]);
+        assert_eq!(writes("chmod 644 ~/.ssh/config"), vec!["/home/me/.ssh/config"]);
+        assert_eq!(writes("sed -i 's/a/b/' conf.ini"), vec!["/home/me/project/conf.ini"]);
+        assert!(writes("sed 's/a/b/' conf.ini").is_empty());
+        assert_eq!(writes("dd if=img of=/dev/sdb"), vec!["/dev/sdb"]);
+    }
+
+    #[test]
+    fn test_redirects_and_cd() {
+        assert_eq!(writes("echo hi > out.txt 2>/dev/null"), vec!["/home/me/project/out.txt", "/dev/null"]);
+        assert_eq!(writes("cd /etc && rm hosts"), vec!["/etc/hosts"]);
+        assert_eq!(writes("(cd /etc && rm hosts); rm local"), vec!["/etc/hosts", "/home/me/project/local"]);
+        assert_eq!(writes("cd && touch x"), vec!["/home/me/x"]);
+        // After a `cd` we cannot follow, relative paths are unknown
+        assert_eq!(writes("cd \"$DIR\" && touch x /tmp/y"), vec!["x", "/tmp/y"]);
+    }
+
+    #[test]
+    fn test_other_programs_use_path_like_operands() {
+        assert_eq!(writes("git clone https://x/y.git ../y"), vec!["/home/me/y"]);
+        assert!(writes("git push origin main").is_empty());
+        assert!(writes("ls /etc").is_empty());
+        assert_eq!(writes("find /var/log -name '*.gz' -delete"), vec!["/var/log"]);
+    }
+
+    #[test]
+    fn test_allowed_roots() {
+        let ctx = context();
+        let roots = vec![PathBuf::from("/home/me")];
+        let check = |command: &str| {
+            touched_paths(&parser::parse(command).unwrap(), &ctx)
+                .iter()
+                .filter(|t| t.write)
+                .all(|t| is_allowed(t, &roots))
+        };
+        assert!(check("rm -rf build"));
+        assert!(check("rm ~/*.tmp"));
+        assert!(!check("rm /etc/hosts"));
+        assert!(!check("rm ../../*"));
+        assert!(!check("cd .. && cd .. && touch x"));
+        assert!(!check("rm \"$TARGET\""));
+        assert!(!check("touch `pwd`/x"));
+        assert!(!check("rm ~root/.bashrc"));
+        assert!(!check("cd $(mktemp -d) && rm -rf build"));
+        assert!(!check("cd - && touch x"));
+    }
+
+    #[test]
+    fn test_glob_base() {
+        assert_eq!(glob_base(Path::new("/home/me/*.log")), PathBuf::from("/home/me"));
+        assert_eq!(glob_base(Path::new("/a/b*/c")), PathBuf::from("/a"));
+        assert_eq!(glob_base(Path::new("/*")), PathBuf::from("/"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_escape_is_detected() {
+        let dir = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        let link = dir.path().join("link");
+        std::os::unix::fs::symlink(outside.path(), &link).unwrap();
+
+        let roots = vec![dir.path().canonicalize().unwrap()];
+        let touched = TouchedPath { raw: "link/x".into(), path: link.join("x"), write: true, glob: false, unresolved: false };
+        assert!(!is_allowed(&touched, &roots));
+    }
+}
@@ -0,0 +1,343 @@
+// src/placeholder.rs
+use crate::parser::{self, Quoting};
+use anyhow::{anyhow, Result};
+use colored::*;
+use rustyline::completion::{Completer, FilenameCompleter, Pair};
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use std::process::Command;
+
+/// What kind of value a placeholder expects, used to pick defaults and completion.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlaceholderKind {
+    Path,
+    Branch,
+    Text,
+}
+
+/// A value the user must supply before a suggested command can run.
+///
+/// Explicit placeholders are written by the model as `{{?name}}`,
+/// `{{?name:kind}}` or `{{?name:kind=default}}`. Unmarked ones such as
+/// `<your-branch>` or `/path/to/file` are detected heuristically so they are
+/// never executed literally.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Placeholder {
+    pub text: String,
+    pub name: String,
+    pub kind: PlaceholderKind,
+    pub default: Option<String>,
+}
+
+pub fn find_placeholders(command: &str) -> Vec<Placeholder> {
+    let mut found: Vec<Placeholder> = Vec::new();
+    let mut push = |placeholder: Placeholder| {
+        if !found.iter().any(|p| p.text == placeholder.text) {
+            found.push(placeholder);
+        }
+    };
+
+    // Explicit {{?name:kind=default}} markers
+    let mut rest = command;
+    while let Some(start) = rest.find("{{?") {
+        let after = &rest[start + 3..];
+        let Some(end) = after.find("}}") else { break };
+        let inner = &after[..end];
+        if let Some(placeholder) = parse_marker(inner) {
+            push(placeholder);
+        }
+        rest = &after[end + 2..];
+    }
+
+    // Unmarked <your-value> style placeholders
+    let chars: Vec<char> = command.chars().collect();
+    let mut i = 0;
+    while i < chars.len() {
+        if chars[i] == '<' && chars.get(i + 1).is_some_and(|c| c.is_ascii_alphabetic()) {
+            let end = chars[i + 1..].iter().position(|c| !(c.is_ascii_alphanumeric() || *c == '-' || *c == '_'));
+            if let Some(offset) = end {
+                let close = i + 1 + offset;
+                let preceded_by_angle = i > 0 && chars[i - 1] == '<';
+                if chars[close] == '>' && !preceded_by_angle {
+                    let name: String = chars[i + 1..close].iter().collect();
+                    push(Placeholder {
+                        text: format!("<{}>", name),
+                        kind: guess_kind(&name),
+                        name,
+                        default: None,
+                    });
+                    i = close;
+                }
+            }
+        }
+        i += 1;
+    }
+
+    // /path/to/something stand-ins
+    for word in command.split(|c: char| c.is_whitespace() || c == '"' || c == '\'' || c == '=') {
+        if let Some(index) = word.find("/path/to/") {
+            let text = &word[index..];
+            let text = text.trim_end_matches([';', ')', '|', '&']);
+            push(Placeholder {
+                text: text.to_string(),
+                name: text.trim_start_matches('/').to_string(),
+                kind: PlaceholderKind::Path,
+                default: None,
+            });
+        }
+    }
+
+    found
+}
+
+fn parse_marker(inner: &str) -> Option<Placeholder> {
+    let (spec, default) = match inner.split_once('=') {
+        Some((spec, default)) => (spec, Some(default.to_string())),
+        None => (inner, None),
+    };
+    let (name, kind) = match spec.split_once(':') {
+        Some((name, kind)) => (name, parse_kind(kind)),
+        None => (spec, guess_kind(spec)),
+    };
+    let name = name.trim();
+    if name.is_empty() || name.contains(char::is_whitespace) {
+        return None;
+    }
+    Some(Placeholder {
+        text: format!("{{{{?{}}}}}", inner),
+        name: name.to_string(),
+        kind,
+        default: default.filter(|d| !d.is_empty()),
+    })
+}
+
+fn parse_kind(kind: &str) -> PlaceholderKind {
+    match kind.trim().to_lowercase().as_str() {
+        "path" | "file" | "dir" | "directory" => PlaceholderKind::Path,
+        "branch" | "git-branch" => PlaceholderKind::Branch,
+        _ => PlaceholderKind::Text,
+    }
+}
+
+fn guess_kind(name: &str) -> PlaceholderKind {
+    let name = name.to_lowercase();
+    if name.contains("branch") {
+        PlaceholderKind::Branch
+    } else if ["file", "path", "dir", "folder"].iter().any(|k| name.contains(k)) {
+        PlaceholderKind::Path
+    } else {
+        PlaceholderKind::Text
+    }
+}
+
+/// Replaces every placeholder with its value, escaped for the quoting the
+/// placeholder sits in, so the shell only ever sees the value as data.
+pub fn substitute(command: &str, placeholder: &Placeholder, value: &str) -> String {
+    let mut result = String::new();
+    let mut rest = command;
+    while let Some(index) = rest.find(&placeholder.text) {
+        result.push_str(&rest[..index]);
+        let escaped = match parser::quoting_at_end(&result) {
+            Quoting::Unquoted => shell_quote(value),
+            Quoting::Single => value.replace('\'', r"'\''"),
+            Quoting::AnsiC => value.replace('\\', r"\\").replace('\'', r"\'"),
+            Quoting::Double => value.chars().fold(String::new(), |mut out, c| {
+                if matches!(c, '"' | '$' | '`' | '\\') {
+                    out.push('\\');
+                }
+                out.push(c);
+                out
+            }),
+        };
+        result.push_str(&escaped);
+        rest = &rest[index + placeholder.text.len()..];
+    }
+    result.push_str(rest);
+    result
+}
+
+/// Single-quotes `value` for a POSIX shell if it has anything the shell
+/// would interpret.
+pub fn shell_quote(value: &str) -> String {
+    if needs_quoting(value) {
+        format!("'{}'", value.replace('\'', "'\\''"))
+    } else {
+        value.to_string()
+    }
+}
+
+fn needs_quoting(value: &str) -> bool {
+    value.is_empty() || value.chars().any(|c| c.is_whitespace() || "'\"$`\\|&;<>()*?[]{}!#~".contains(c))
+}
+
+/// Prompts for every placeholder in `command` and returns the filled command.
+/// Fails if any placeholder is left without a value.
+pub fn fill_placeholders(command: &str) -> Result<String> {
+    let placeholders = find_placeholders(command);
+    if placeholders.is_empty() {
+        return Ok(command.to_string());
+    }
+
+    println!("\n{}", "This command needs some values:".blue().bold());
+    let mut editor: Editor<PathHelper, DefaultHistory> = Editor::new()?;
+    let mut filled = command.to_string();
+
+    for placeholder in &placeholders {
+        let default = placeholder.default.clone().or_else(|| default_for(&placeholder.kind));
+        let mut prompt = format!("{}", placeholder.name.bold());
+
+        match placeholder.kind {
+            PlaceholderKind::Branch => {
+                let branches = git_branches();
+                if !branches.is_empty() {
+                    println!("Branches: {}", branches.join(", "));
+                }
+                editor.set_helper(None);
+            }
+            PlaceholderKind::Path => {
+                prompt.push_str(" (tab completes)");
+                editor.set_helper(Some(PathHelper::default()));
+            }
+            PlaceholderKind::Text => editor.set_helper(None),
+        }
+        if let Some(default) = &default {
+            prompt.push_str(&format!(" [{}]", default));
+        }
+        prompt.push_str(": ");
+
+        let value = editor.readline(&prompt)?;
+        let value = match value.trim() {
+            "" => default.unwrap_or_default(),
+            value => value.to_string(),
+        };
+        if value.is_empty() {
+            return Err(anyhow!("No value given for {}; not executing", placeholder.text));
+        }
+        filled = substitute(&filled, placeholder, &value);
+    }
+
+    if let Some(left) = find_placeholders(&filled).first() {
+        return Err(anyhow!("Command still contains placeholder {}; not executing", left.text));
+    }
+    Ok(filled)
+}
+
+fn default_for(kind: &PlaceholderKind) -> Option<String> {
+    match kind {
+        PlaceholderKind::Branch => git_output(&["rev-parse", "--abbrev-ref", "HEAD"]),
+        _ => None,
+    }
+}
+
+fn git_branches() -> Vec<String> {
+    git_output(&["branch", "--format=%(refname:short)"])
+        .map(|out| out.lines().map(str::to_string).collect())
+        .unwrap_or_default()
+}
+
+fn git_output(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let out = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (!out.is_empty()).then_some(out)
+}
+
+/// Line editor helper that completes file paths.
+#[derive(Default)]
+struct PathHelper {
+    completer: FilenameCompleter,
+}
+
+impl Completer for PathHelper {
+    type Candidate = Pair;
+
+    fn complete(&self, line: &str, pos: usize, ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<Pair>)> {
+        self.completer.complete(line, pos, ctx)
+    }
+}
+
+impl Hinter for PathHelper {
+    type Hint = String;
+}
+
+impl Highlighter for PathHelper {}
+
+impl Validator for PathHelper {}
+
+impl Helper for PathHelper {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_explicit_markers() {
+        let found = find_placeholders("git push origin {{?branch:branch}} && cat {{?file:path=README.md}}");
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].name, "branch");
+        assert_eq!(found[0].kind, PlaceholderKind::Branch);
+        assert_eq!(found[1].kind, PlaceholderKind::Path);
+        assert_eq!(found[1].default.as_deref(), Some("README.md"));
+        assert_eq!(found[1].text, "{{?file:path=README.md}}");
+    }
+
+    #[test]
+    fn test_unmarked_placeholders() {
+        let found = find_placeholders("git checkout <your-branch> -- /path/to/file.txt");
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].text, "<your-branch>");
+        assert_eq!(found[0].kind, PlaceholderKind::Branch);
+        assert_eq!(found[1].text, "/path/to/file.txt");
+        assert_eq!(found[1].kind, PlaceholderKind::Path);
+    }
+
+    #[test]
+    fn test_no_false_positives() {
+        assert!(find_placeholders("sort <input.txt >out.txt").is_empty());
+        assert!(find_placeholders("cat <<EOF\nhi\nEOF").is_empty());
+        assert!(find_placeholders("docker ps --format '{{.Names}}'").is_empty());
+        assert!(find_placeholders("ls -la").is_empty());
+    }
+
+    #[test]
+    fn test_duplicates_collapsed() {
+        assert_eq!(find_placeholders("cp {{?f}} {{?f}}.bak").len(), 1);
+    }
+
+    #[test]
+    fn test_substitute_quotes_when_needed() {
+        let p = &find_placeholders("cat {{?file}}")[0];
+        assert_eq!(substitute("cat {{?file}}", p, "notes.txt"), "cat notes.txt");
+        assert_eq!(substitute("cat {{?file}}", p, "my notes.txt"), "cat 'my notes.txt'");
+        assert_eq!(substitute("cat \"{{?file}}\"", p, "my notes.txt"), "cat \"my notes.txt\"");
+        assert_eq!(substitute("cat {{?file}} {{?file}}", p, "a"), "cat a a");
+    }
+
+    #[test]
+    fn test_substitute_escapes_for_real_quoting() {
+        let p = &find_placeholders("echo {{?x}}")[0];
+        assert_eq!(substitute("echo {{?x}}", p, "$(rm -rf ~)"), "echo '$(rm -rf ~)'");
+        assert_eq!(substitute("echo '{{?x}}'", p, "it's"), r"echo 'it'\''s'");
+        assert_eq!(substitute("echo '{{?x}}'", p, "'; rm -rf ~; '"), r"echo ''\''; rm -rf ~; '\'''");
+        assert_eq!(substitute(r#"echo "{{?x}}""#, p, r#"$(rm -rf ~) `id` "\"#), r#"echo "\$(rm -rf ~) \`id\` \"\\""#);
+        assert_eq!(substitute("echo $'{{?x}}'", p, r"a\'b"), r"echo $'a\\\'b'");
+        // A quote next to the placeholder is not necessarily around it
+        assert_eq!(substitute(r#"echo "a"{{?x}}"#, p, "$(id)"), r#"echo "a"'$(id)'"#);
+        assert_eq!(substitute(r#"echo "$(cat {{?x}})""#, p, "a b"), r#"echo "$(cat 'a b')""#);
+    }
+
+    #[test]
+    fn test_substituted_values_stay_data() {
+        for command in ["echo {{?x}}", "echo '{{?x}}'", r#"echo "{{?x}}""#, "echo $'{{?x}}'", r#"echo "a"{{?x}}"#] {
+            let p = &find_placeholders(command)[0];
+            let filled = substitute(command, p, r#"'"$(rm -rf ~)`id`\"#);
+            let script = parser::parse(&filled).unwrap();
+            assert_eq!(script.commands().len(), 1, "{}", filled);
+        }
+    }
+}
@@ -0,0 +1,355 @@
+// src/policy.rs
+use crate::config::RiskAction;
+use crate::parser::{self, Pipeline, SimpleCommand, Stage};
+use crate::paths::{self, Context};
+use anyhow::{anyhow, Result};
+use glob::Pattern;
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// One or more glob patterns; a value matches if any of them does.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum Patterns {
+    One(String),
+    Any(Vec<String>),
+}
+
+impl Patterns {
+    fn compile(&self) -> Result<Vec<Pattern>> {
+        let texts = match self {
+            Patterns::One(text) => std::slice::from_ref(text),
+            Patterns::Any(texts) => texts.as_slice(),
+        };
+        texts
+            .iter()
+            .map(|text| Pattern::new(text).map_err(|e| anyhow!("invalid pattern {:?}: {}", text, e)))
+            .collect()
+    }
+}
+
+/// A rule as written in `policy.toml`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleFile {
+    name: Option<String>,
+    /// `allow`, `confirm`, `typed` or `deny`.
+    action: RiskAction,
+    program: Option<Patterns>,
+    #[serde(default)]
+    args: Vec<Patterns>,
+    #[serde(default)]
+    flags: Vec<Patterns>,
+    cwd: Option<String>,
+    #[serde(default)]
+    pipeline: Vec<Patterns>,
+    reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PolicyFile {
+    #[serde(default)]
+    rules: Vec<RuleFile>,
+}
+
+/// A compiled rule. Every matcher that is set must match.
+#[derive(Debug)]
+pub struct Rule {
+    /// The rule's `name`, or where it was defined.
+    pub label: String,
+    pub action: RiskAction,
+    pub reason: Option<String>,
+    program: Option<Vec<Pattern>>,
+    /// Operands that must appear, in this order.
+    args: Vec<Vec<Pattern>>,
+    flags: Vec<Vec<Pattern>>,
+    cwd: Option<String>,
+    /// Programs that must appear in the command's pipeline, in this order.
+    pipeline: Vec<Vec<Pattern>>,
+}
+
+/// A rule that decided one command.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Match {
+    pub command: String,
+    pub rule: String,
+    pub action: RiskAction,
+    pub reason: Option<String>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Verdict {
+    pub matches: Vec<Match>,
+    /// Whether a rule decided every command, so the risk policy has no say.
+    pub all_covered: bool,
+}
+
+impl Verdict {
+    /// Combines the rules' decisions with what the risk policy requires.
+    /// Rules replace the risk policy for the commands they cover; a `deny`
+    /// anywhere blocks the whole command line.
+    pub fn apply(&self, risk_action: RiskAction) -> RiskAction {
+        match self.matches.iter().map(|m| m.action).max() {
+            None => risk_action,
+            Some(action) if self.all_covered => action,
+            Some(action) => action.max(risk_action),
+        }
+    }
+}
+
+/// Ordered allow/confirm/typed/deny rules. For each command the first
+/// matching rule wins.
+#[derive(Debug, Default)]
+pub struct Policy {
+    pub rules: Vec<Rule>,
+}
+
+impl Policy {
+    /// Loads rules from each existing file in order. A file that cannot be
+    /// read or parsed is an error, so a broken policy never allows anything.
+    pub fn load(paths: &[PathBuf]) -> Result<Self> {
+        let mut policy = Policy::default();
+        for path in paths.iter().filter(|p| p.exists()) {
+            let text = fs::read_to_string(path)
+                .map_err(|e| anyhow!("Could not read policy {:?}: {}", path, e))?;
+            policy.add_rules(&text, path).map_err(|e| anyhow!("Invalid policy {:?}: {}", path, e))?;
+        }
+        Ok(policy)
+    }
+
+    fn add_rules(&mut self, text: &str, path: &Path) -> Result<()> {
+        let file: PolicyFile = toml::from_str(text)?;
+        for (index, rule) in file.rules.into_iter().enumerate() {
+            let label = rule
+                .name
+                .clone()
+                .unwrap_or_else(|| format!("rule {} in {}", index + 1, path.display()));
+            if rule.program.is_none() && rule.args.is_empty() && rule.flags.is_empty() && rule.pipeline.is_empty() {
+                return Err(anyhow!("{} needs a program, args, flags or pipeline to match", label));
+            }
+            let compile_all = |list: &[Patterns]| list.iter().map(Patterns::compile).collect::<Result<Vec<_>>>();
+            self.rules.push(Rule {
+                program: rule.program.as_ref().map(Patterns::compile).transpose()?,
+                args: compile_all(&rule.args)?,
+                flags: compile_all(&rule.flags)?,
+                pipeline: compile_all(&rule.pipeline)?,
+                cwd: rule.cwd,
+                action: rule.action,
+                reason: rule.reason,
+                label,
+            });
+        }
+        Ok(())
+    }
+
+    /// Finds the first matching rule for every command in `command`.
+    /// Commands that do not parse match nothing.
+    pub fn evaluate(&self, command: &str, context: &Context) -> Verdict {
+        let mut verdict = Verdict { matches: Vec::new(), all_covered: true };
+        if self.rules.is_empty() {
+            verdict.all_covered = false;
+            return verdict;
+        }
+        let Ok(script) = parser::parse(command) else {
+            verdict.all_covered = false;
+            return verdict;
+        };
+
+        paths::walk_commands(&script, context, &mut |cmd, pipeline, here| {
+            if cmd.program.is_empty() {
+                return;
+            }
+            match self.rules.iter().find(|rule| rule.matches(cmd, pipeline, here)) {
+                Some(rule) => verdict.matches.push(Match {
+                    command: cmd.to_string(),
+                    rule: rule.label.clone(),
+                    action: rule.action,
+                    reason: rule.reason.clone(),
+                }),
+                None => verdict.all_covered = false,
+            }
+        });
+        verdict
+    }
+}
+
+impl Rule {
+    fn matches(&self, cmd: &SimpleCommand, pipeline: &Pipeline, context: &Context) -> bool {
+        if let Some(program) = &self.program {
+            if !any_match(program, cmd.name()) {
+                return false;
+            }
+        }
+
+        let operands: Vec<&str> = cmd.operands().map(|w| w.text.as_str()).collect();
+        if !in_order(&self.args, &operands) {
+            return false;
+        }
+
+        let options = option_forms(cmd);
+        if !self.flags.iter().all(|flag| options.iter().any(|option| any_match(flag, option))) {
+            return false;
+        }
+
+        if let Some(cwd) = &self.cwd {
+            if !cwd_matches(cwd, context) {
+                return false;
+            }
+        }
+
+        let programs: Vec<&str> = pipeline
+            .stages
+            .iter()
+            .filter_map(|stage| match stage {
+                Stage::Command(cmd) => Some(cmd.name()),
+                Stage::Group(_) => None,
+            })
+            .collect();
+        in_order(&self.pipeline, &programs)
+    }
+}
+
+fn any_match(patterns: &[Pattern], text: &str) -> bool {
+    patterns.iter().any(|pattern| pattern.matches(text))
+}
+
+/// Whether each pattern list matches a distinct item of `items`, in order.
+fn in_order(wanted: &[Vec<Pattern>], items: &[&str]) -> bool {
+    let mut items = items.iter();
+    wanted.iter().all(|patterns| items.any(|item| any_match(patterns, item)))
+}
+
+/// Every way an option can be written, so `--context=prod-*` matches
+/// `--context prod-eu` and `-f` matches the cluster `-rf`.
+fn option_forms(cmd: &SimpleCommand) -> Vec<String> {
+    let args: Vec<&str> = cmd.args.iter().map(|w| w.text.as_str()).take_while(|a| *a != "--").collect();
+    let mut forms = Vec::new();
+    for (i, arg) in args.iter().enumerate() {
+        if !arg.starts_with('-') || *arg == "-" {
+            continue;
+        }
+        forms.push(arg.to_string());
+        if let Some(next) = args.get(i + 1).filter(|next| !next.starts_with('-')) {
+            if !arg.contains('=') {
+                forms.push(format!("{}={}", arg, next));
+            }
+        }
+        if !arg.starts_with("--") && arg.len() > 2 {
+            forms.extend(arg[1..].chars().map(|c| format!("-{}", c)));
+        }
+    }
+    forms
+}
+
+/// Whether the command runs in `pattern`'s directory or below it. Globs
+/// match any of the enclosing directories.
+fn cwd_matches(pattern: &str, context: &Context) -> bool {
+    let Some(resolved) = context.resolve(pattern) else {
+        return false;
+    };
+    let cwd = paths::canonicalize_existing(&context.cwd);
+    let root = paths::canonicalize_existing(&resolved);
+    if !pattern.contains(['*', '?', '[']) {
+        return cwd.starts_with(root);
+    }
+    let Ok(glob) = Pattern::new(&resolved.to_string_lossy()) else {
+        return false;
+    };
+    cwd.ancestors().any(|dir| glob.matches_path(dir))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RULES: &str = r#"
+[[rules]]
+name = "no prod deletes"
+action = "deny"
+program = "kubectl"
+args = ["delete"]
+flags = ["--context=prod-*"]
+reason = "delete in production through the deploy pipeline"
+
+[[rules]]
+action = "allow"
+program = "git"
+args = [["status", "log", "diff"]]
+
+[[rules]]
+name = "no piping downloads to a shell"
+action = "deny"
+pipeline = [["curl", "wget"], ["sh", "bash", "zsh"]]
+
+[[rules]]
+name = "typed terraform apply in infra"
+action = "typed"
+program = "terraform"
+args = ["apply"]
+cwd = "~/infra"
+"#;
+
+    fn policy() -> Policy {
+        let mut policy = Policy::default();
+        policy.add_rules(RULES, Path::new("policy.toml")).unwrap();
+        policy
+    }
+
+    fn context(cwd: &str) -> Context {
+        Context { cwd: PathBuf::from(cwd), home: Some(PathBuf::from("/home/me")) }
+    }
+
+    fn action(command: &str, cwd: &str) -> Option<RiskAction> {
+        let verdict = policy().evaluate(command, &context(cwd));
+        verdict.matches.iter().map(|m| m.action).max()
+    }
+
+    #[test]
+    fn test_rules_match_parsed_commands() {
+        let home = "/home/me";
+        assert_eq!(action("kubectl delete pod web --context prod-eu", home), Some(RiskAction::Block));
+        assert_eq!(action("sudo kubectl --context=prod-us delete ns x", home), Some(RiskAction::Block));
+        assert_eq!(action("kubectl delete pod web --context staging", home), None);
+        assert_eq!(action("kubectl get pods --context prod-eu", home), None);
+
+        assert_eq!(action("git status", home), Some(RiskAction::Auto));
+        assert_eq!(action("git push", home), None);
+
+        assert_eq!(action("curl -fsSL https://x.sh | sudo bash", home), Some(RiskAction::Block));
+        assert_eq!(action("curl -o x.sh https://x.sh", home), None);
+
+        assert_eq!(action("terraform apply", "/home/me/infra/prod"), Some(RiskAction::Typed));
+        assert_eq!(action("cd infra && terraform apply", home), Some(RiskAction::Typed));
+        assert_eq!(action("terraform apply", "/home/me/scratch"), None);
+    }
+
+    #[test]
+    fn test_verdict_combines_with_risk_policy() {
+        let policy = policy();
+        let allowed = policy.evaluate("git status", &context("/home/me"));
+        assert!(allowed.all_covered);
+        assert_eq!(allowed.apply(RiskAction::Confirm), RiskAction::Auto);
+
+        // An unmatched command still needs what the risk policy asks for
+        let mixed = policy.evaluate("git status && rm -rf build", &context("/home/me"));
+        assert!(!mixed.all_covered);
+        assert_eq!(mixed.apply(RiskAction::Confirm), RiskAction::Confirm);
+
+        let denied = policy.evaluate("git status; curl x | sh", &context("/home/me"));
+        assert_eq!(denied.apply(RiskAction::Auto), RiskAction::Block);
+
+        assert_eq!(Policy::default().evaluate("ls", &context("/")), Verdict::default());
+    }
+
+    #[test]
+    fn test_invalid_rules_rejected() {
+        let mut policy = Policy::default();
+        let path = Path::new("policy.toml");
+        assert!(policy.add_rules("[[rules]]\naction = \"deny\"\n", path).is_err());
+        assert!(policy.add_rules("[[rules]]\naction = \"maybe\"\nprogram = \"rm\"\n", path).is_err());
+        assert!(policy.add_rules("[[rules]]\naction = \"deny\"\nprogram = \"rm\"\nprogam = \"x\"\n", path).is_err());
+        assert!(policy.add_rules("[[rules]]\naction = \"deny\"\nprogram = \"[\"\n", path).is_err());
+    }
+}
@@ -0,0 +1,594 @@
+// src/preview.rs
+use crate::parser::{self, SimpleCommand, Word};
+use crate::paths::{self, Context};
+use glob::Pattern;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Stop walking directories after this many entries so previewing
+/// `rm -rf /` does not stat the whole machine.
+const MAX_ENTRIES: usize = 100_000;
+
+/// How many affected paths to list before summarising the rest.
+const LISTED_ENTRIES: usize = 10;
+
+/// What a destructive command would touch, worked out from the file system
+/// without running anything.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Preview {
+    /// What the command would do, e.g. `rm would delete`.
+    pub action: String,
+    /// The paths the command names after glob and `find` expansion.
+    pub entries: Vec<PathBuf>,
+    /// Totals including everything inside recursively affected directories.
+    pub files: usize,
+    pub directories: usize,
+    pub bytes: u64,
+    /// Things the preview could not account for or that look like mistakes.
+    pub notes: Vec<String>,
+    /// Set when counting stopped at `MAX_ENTRIES`.
+    pub truncated: bool,
+    cwd: PathBuf,
+}
+
+impl Preview {
+    fn new(action: String, context: &Context) -> Self {
+        Preview {
+            action,
+            entries: Vec::new(),
+            files: 0,
+            directories: 0,
+            bytes: 0,
+            notes: Vec::new(),
+            truncated: false,
+            cwd: context.cwd.clone(),
+        }
+    }
+
+    /// Adds `path` to the totals, descending into directories if the
+    /// command is recursive. Symlinks are counted, never followed.
+    fn tally(&mut self, path: &Path, recursive: bool) {
+        let Ok(metadata) = fs::symlink_metadata(path) else {
+            return;
+        };
+        if self.files + self.directories >= MAX_ENTRIES {
+            self.truncated = true;
+            return;
+        }
+        if !metadata.is_dir() {
+            self.files += 1;
+            self.bytes += metadata.len();
+            return;
+        }
+        self.directories += 1;
+        if recursive {
+            if let Ok(children) = fs::read_dir(path) {
+                for child in children.flatten() {
+                    self.tally(&child.path(), true);
+                }
+            }
+        }
+    }
+
+    fn display_path(&self, path: &Path) -> String {
+        match path.strip_prefix(&self.cwd) {
+            Ok(relative) if !relative.as_os_str().is_empty() => relative.display().to_string(),
+            _ => path.display().to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Preview {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut counts = Vec::new();
+        if self.files > 0 {
+            counts.push(plural(self.files, "file", "files"));
+        }
+        if self.directories > 0 {
+            counts.push(plural(self.directories, "directory", "directories"));
+        }
+        if counts.is_empty() {
+            write!(f, "{} nothing", self.action)?;
+        } else {
+            let more = if self.truncated { "at least " } else { "" };
+            write!(f, "{} {}{} ({})", self.action, more, counts.join(" and "), human_size(self.bytes))?;
+        }
+
+        for entry in self.entries.iter().take(LISTED_ENTRIES) {
+            let suffix = if entry.is_dir() && !entry.is_symlink() { "/" } else { "" };
+            write!(f, "\n  {}{}", self.display_path(entry), suffix)?;
+        }
+        if self.entries.len() > LISTED_ENTRIES {
+            write!(f, "\n  ... and {} more", self.entries.len() - LISTED_ENTRIES)?;
+        }
+        for note in &self.notes {
+            write!(f, "\n  note: {}", note)?;
+        }
+        Ok(())
+    }
+}
+
+/// Previews every recognised destructive command in a Bash `command`:
+/// `rm`, `rmdir`, `mv`, recursive `chmod`/`chown`/`chgrp`, and `find` with
+/// `-delete` or `-exec rm`. Other commands produce no preview.
+pub fn preview(command: &str, context: &Context) -> Vec<Preview> {
+    let Ok(script) = parser::parse(command) else {
+        return Vec::new();
+    };
+    let mut previews = Vec::new();
+    paths::walk_commands(&script, context, &mut |cmd, _, here| {
+        if let Some(mut preview) = preview_command(cmd, here) {
+            // List paths relative to where the user is, not where a `cd` went
+            preview.cwd = context.cwd.clone();
+            previews.push(preview);
+        }
+    });
+    previews
+}
+
+fn preview_command(cmd: &SimpleCommand, context: &Context) -> Option<Preview> {
+    // A `find -exec` body: the preview of the `find` lists what it runs on
+    if cmd.name() != "find" && cmd.operands().any(|w| w.text == "{}") {
+        return None;
+    }
+    let recursive = cmd.has_short_flag('r') || cmd.has_short_flag('R') || cmd.has_long_flag("recursive");
+    match cmd.name() {
+        "rm" | "rmdir" => {
+            let mut preview = Preview::new(format!("{} would delete", cmd.name()), context);
+            for path in expand_operands(cmd.operands(), context, &mut preview.notes) {
+                if path.is_dir() && !path.is_symlink() && cmd.name() == "rm" && !recursive && !cmd.has_short_flag('d') {
+                    preview.notes.push(format!("rm will refuse to delete the directory {} without -r", path.display()));
+                    continue;
+                }
+                preview.tally(&path, recursive);
+                preview.entries.push(path);
+            }
+            Some(preview)
+        }
+        "mv" => preview_move(cmd, context),
+        "chmod" | "chown" | "chgrp" if recursive => {
+            let action = match cmd.name() {
+                "chmod" => "chmod -R would change permissions on",
+                "chown" => "chown -R would change ownership of",
+                _ => "chgrp -R would change the group of",
+            };
+            let mut preview = Preview::new(action.to_string(), context);
+            // The first operand is the mode or owner unless --reference supplies it
+            let skip = if cmd.args.iter().any(|w| w.text.starts_with("--reference")) { 0 } else { 1 };
+            for path in expand_operands(cmd.operands().skip(skip), context, &mut preview.notes) {
+                preview.tally(&path, true);
+                preview.entries.push(path);
+            }
+            Some(preview)
+        }
+        "find" => preview_find(cmd, context),
+        _ => None,
+    }
+}
+
+fn preview_move(cmd: &SimpleCommand, context: &Context) -> Option<Preview> {
+    let operands: Vec<&Word> = cmd.operands().collect();
+    let (destination, sources) = operands.split_last()?;
+    if sources.is_empty() {
+        return None;
+    }
+    let destination_path = context.resolve(&destination.text)?;
+
+    let mut preview = Preview::new(format!("mv would move to {}", destination.text), context);
+    let paths = expand_operands(sources.iter().copied(), context, &mut preview.notes);
+    let into_directory = destination_path.is_dir();
+    for path in paths {
+        let target = match (into_directory, path.file_name()) {
+            (true, Some(name)) => destination_path.join(name),
+            _ => destination_path.clone(),
+        };
+        if target != path && fs::symlink_metadata(&target).is_ok() {
+            preview.notes.push(format!("would overwrite {}", preview.display_path(&target)));
+        }
+        preview.tally(&path, true);
+        preview.entries.push(path);
+    }
+    if !into_directory && preview.entries.len() > 1 {
+        preview.notes.push(format!("{} is not a directory, so mv will fail", destination.text));
+    }
+    Some(preview)
+}
+
+/// Expands operands to the existing paths they name, explaining in `notes`
+/// any that match nothing or cannot be known before running.
+fn expand_operands<'a>(
+    words: impl Iterator<Item = &'a Word>,
+    context: &Context,
+    notes: &mut Vec<String>,
+) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    for word in words {
+        let Some(path) = context.resolve(&word.text) else {
+            notes.push(format!("cannot preview `{}` before it runs", word.text));
+            continue;
+        };
+        if word.glob {
+            let matches: Vec<PathBuf> = glob::glob(&path.to_string_lossy())
+                .map(|found| found.flatten().collect())
+                .unwrap_or_default();
+            if matches.is_empty() {
+                notes.push(format!("`{}` matches nothing", word.text));
+            }
+            paths.extend(matches);
+        } else if fs::symlink_metadata(&path).is_ok() {
+            paths.push(path);
+        } else {
+            notes.push(format!("{} does not exist", word.text));
+        }
+    }
+    paths
+}
+
+/// A numeric `find` argument: `+n` is more than, `-n` less than, `n` exactly.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Compare {
+    More(u64),
+    Less(u64),
+    Exactly(u64),
+}
+
+impl Compare {
+    fn parse(arg: &str) -> Option<Self> {
+        let (make, digits): (fn(u64) -> Compare, &str) = match arg.as_bytes().first()? {
+            b'+' => (Compare::More, &arg[1..]),
+            b'-' => (Compare::Less, &arg[1..]),
+            _ => (Compare::Exactly, arg),
+        };
+        digits.parse().ok().map(make)
+    }
+
+    fn matches(self, value: u64) -> bool {
+        match self {
+            Compare::More(n) => value > n,
+            Compare::Less(n) => value < n,
+            Compare::Exactly(n) => value == n,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Test {
+    Name(Pattern, bool),
+    Path(Pattern, bool),
+    Type(char),
+    /// Age in whole units of the given length, as `-mtime` and `-mmin` round.
+    Age(Compare, u64),
+    /// Size in units of the given length, rounded up as `find -size` does.
+    Size(Compare, u64),
+    Empty,
+    Newer(SystemTime),
+}
+
+#[derive(Debug, Default)]
+struct FindQuery {
+    starts: Vec<PathBuf>,
+    tests: Vec<(bool, Test)>,
+    min_depth: usize,
+    max_depth: Option<usize>,
+}
+
+fn preview_find(cmd: &SimpleCommand, context: &Context) -> Option<Preview> {
+    let args: Vec<&str> = cmd.args.iter().map(|w| w.text.as_str()).collect();
+    let action = (0..args.len()).find(|&i| is_delete_action(&args[i..]))?;
+
+    let mut preview = Preview::new("find would delete".to_string(), context);
+    let query = match parse_find(&args[..action], context) {
+        Ok(query) => query,
+        Err(reason) => {
+            preview.notes.push(reason);
+            return Some(preview);
+        }
+    };
+    let recursive = args[action] != "-delete";
+    let mut walk = Walk { matches: Vec::new(), budget: MAX_ENTRIES, truncated: false };
+    for start in &query.starts {
+        if fs::symlink_metadata(start).is_err() {
+            preview.notes.push(format!("{} does not exist", start.display()));
+            continue;
+        }
+        find_matches(start, 0, &query, &mut walk);
+    }
+    preview.truncated = walk.truncated;
+    for path in walk.matches {
+        // -delete removes a directory only once it is empty; rm -r takes it all
+        preview.tally(&path, recursive);
+        preview.entries.push(path);
+    }
+    Some(preview)
+}
+
+/// Whether `args` starts with an action that deletes what `find` matched:
+/// `-delete`, or `-exec rm` and friends.
+fn is_delete_action(args: &[&str]) -> bool {
+    match args {
+        ["-delete", ..] => true,
+        ["-exec" | "-execdir" | "-ok" | "-okdir", program, ..] => {
+            matches!(program.rsplit('/').next(), Some("rm" | "rmdir" | "unlink"))
+        }
+        _ => false,
+    }
+}
+
+fn parse_find(args: &[&str], context: &Context) -> Result<FindQuery, String> {
+    let mut query = FindQuery::default();
+    let mut rest = args.iter().copied().peekable();
+    while let Some(start) = rest.next_if(|arg| !arg.starts_with('-') && !matches!(*arg, "(" | ")" | "!" | ",")) {
+        let path = context.resolve(start).ok_or_else(|| format!("cannot preview `{}` before it runs", start))?;
+        query.starts.push(path);
+    }
+    if query.starts.is_empty() {
+        query.starts.push(context.cwd.clone());
+    }
+
+    let mut negate = false;
+    while let Some(arg) = rest.next() {
+        let mut value = || rest.next().ok_or_else(|| format!("find {} is missing its argument", arg));
+        let test = match arg {
+            "!" | "-not" => {
+                negate = !negate;
+                continue;
+            }
+            "-print" | "-depth" | "-xdev" | "-mount" | "-true" => continue,
+            "-mindepth" | "-maxdepth" => {
+                let depth = value()?.parse().map_err(|_| format!("find {} needs a number", arg))?;
+                if arg == "-mindepth" {
+                    query.min_depth = depth;
+                } else {
+                    query.max_depth = Some(depth);
+                }
+                continue;
+            }
+            "-name" | "-iname" | "-path" | "-ipath" | "-wholename" => {
+                let pattern = Pattern::new(value()?).map_err(|e| format!("find {}: {}", arg, e))?;
+                let insensitive = arg.starts_with("-i");
+                if arg.ends_with("name") && !arg.contains("whole") {
+                    Test::Name(pattern, insensitive)
+                } else {
+                    Test::Path(pattern, insensitive)
+                }
+            }
+            "-type" => match value()? {
+                kind @ ("f" | "d" | "l") => Test::Type(kind.chars().next().unwrap_or('f')),
+                kind => return Err(format!("cannot preview find -type {}", kind)),
+            },
+            "-mtime" | "-mmin" => {
+                let compare = Compare::parse(value()?).ok_or_else(|| format!("find {} needs a number", arg))?;
+                Test::Age(compare, if arg == "-mtime" { 86_400 } else { 60 })
+            }
+            "-size" => parse_size(value()?).ok_or_else(|| "cannot preview this find -size".to_string())?,
+            "-empty" => Test::Empty,
+            "-newer" => {
+                let reference = value()?;
+                let modified = context
+                    .resolve(reference)
+                    .and_then(|path| fs::metadata(path).ok())
+                    .and_then(|metadata| metadata.modified().ok())
+                    .ok_or_else(|| format!("cannot read the modification time of {}", reference))?;
+                Test::Newer(modified)
+            }
+            other => return Err(format!("cannot preview find {}", other)),
+        };
+        query.tests.push((negate, test));
+        negate = false;
+    }
+    Ok(query)
+}
+
+fn parse_size(arg: &str) -> Option<Test> {
+    let (number, unit) = match arg.char_indices().last()? {
+        (i, c) if c.is_ascii_alphabetic() => (&arg[..i], c),
+        _ => (arg, 'b'),
+    };
+    let unit = match unit {
+        'c' => 1,
+        'w' => 2,
+        'b' => 512,
+        'k' => 1024,
+        'M' => 1024 * 1024,
+        'G' => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    Some(Test::Size(Compare::parse(number)?, unit))
+}
+
+/// What `find_matches` has found so far.
+struct Walk {
+    matches: Vec<PathBuf>,
+    /// Entries still to look at, matching or not, before giving up.
+    budget: usize,
+    truncated: bool,
+}
+
+fn find_matches(path: &Path, depth: usize, query: &FindQuery, walk: &mut Walk) {
+    if walk.budget == 0 {
+        walk.truncated = true;
+        return;
+    }
+    walk.budget -= 1;
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return;
+    };
+    if metadata.is_dir() && query.max_depth.is_none_or(|max| depth < max) {
+        if let Ok(children) = fs::read_dir(path) {
+            for child in children.flatten() {
+                find_matches(&child.path(), depth + 1, query, walk);
+            }
+        }
+    }
+    // Children first, the order -delete removes them in
+    if depth >= query.min_depth
+        && query.max_depth.is_none_or(|max| depth <= max)
+        && query.tests.iter().all(|(negate, test)| evaluate(test, path, &metadata) != *negate)
+    {
+        walk.matches.push(path.to_path_buf());
+    }
+}
+
+fn evaluate(test: &Test, path: &Path, metadata: &fs::Metadata) -> bool {
+    let options = |insensitive: bool| glob::MatchOptions {
+        case_sensitive: !insensitive,
+        require_literal_separator: false,
+        require_literal_leading_dot: false,
+    };
+    match test {
+        Test::Name(pattern, insensitive) => path
+            .file_name()
+            .is_some_and(|name| pattern.matches_with(&name.to_string_lossy(), options(*insensitive))),
+        Test::Path(pattern, insensitive) => pattern.matches_with(&path.to_string_lossy(), options(*insensitive)),
+        Test::Type(kind) => match kind {
+            'd' => metadata.is_dir(),
+            'l' => metadata.is_symlink(),
+            _ => metadata.is_file(),
+        },
+        Test::Age(compare, unit) => {
+            let age = metadata
+                .modified()
+                .ok()
+                .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+                .unwrap_or(Duration::ZERO);
+            compare.matches(age.as_secs() / unit)
+        }
+        Test::Size(compare, unit) => compare.matches(metadata.len().div_ceil(*unit)),
+        Test::Empty => {
+            if metadata.is_dir() {
+                fs::read_dir(path).is_ok_and(|mut children| children.next().is_none())
+            } else {
+                metadata.is_file() && metadata.len() == 0
+            }
+        }
+        Test::Newer(reference) => metadata.modified().is_ok_and(|modified| modified > *reference),
+    }
+}
+
+fn plural(count: usize, one: &str, many: &str) -> String {
+    format!("{} {}", count, if count == 1 { one } else { many })
+}
+
+fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn fixture() -> (TempDir, Context) {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("a.log"), "12345").unwrap();
+        fs::write(dir.path().join("b.log"), "123").unwrap();
+        fs::write(dir.path().join("keep.txt"), "").unwrap();
+        fs::create_dir_all(dir.path().join("build/deep")).unwrap();
+        fs::write(dir.path().join("build/out.o"), vec![0u8; 2048]).unwrap();
+        fs::write(dir.path().join("build/deep/x.log"), "1").unwrap();
+        let context = Context { cwd: dir.path().to_path_buf(), home: None };
+        (dir, context)
+    }
+
+    fn names(preview: &Preview, dir: &TempDir) -> Vec<String> {
+        preview
+            .entries
+            .iter()
+            .map(|p| p.strip_prefix(dir.path()).unwrap().display().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_rm_expands_globs() {
+        let (dir, context) = fixture();
+        let previews = preview("rm *.log missing.txt", &context);
+        assert_eq!(previews.len(), 1);
+        let p = &previews[0];
+        assert_eq!(names(p, &dir), vec!["a.log", "b.log"]);
+        assert_eq!((p.files, p.directories, p.bytes), (2, 0, 8));
+        assert_eq!(p.notes, vec!["missing.txt does not exist"]);
+
+        let p = &preview("rm *.tmp", &context)[0];
+        assert!(p.entries.is_empty());
+        assert_eq!(p.to_string(), "rm would delete nothing\n  note: `*.tmp` matches nothing");
+    }
+
+    #[test]
+    fn test_rm_recursive_counts_contents() {
+        let (dir, context) = fixture();
+        let p = &preview("cd build && rm -rf ../build", &context)[0];
+        assert_eq!(names(p, &dir), vec!["build"]);
+        assert_eq!((p.files, p.directories, p.bytes), (2, 2, 2049));
+        assert!(p.to_string().starts_with("rm would delete 2 files and 2 directories (2.0 KiB)\n  build/"));
+
+        let p = &preview("rm build", &context)[0];
+        assert!(p.entries.is_empty());
+        assert!(p.notes[0].contains("without -r"));
+    }
+
+    #[test]
+    fn test_find_predicates() {
+        let (dir, context) = fixture();
+        let p = &preview("find . -name '*.log' -type f -delete", &context)[0];
+        let mut found = names(p, &dir);
+        found.sort();
+        assert_eq!(found, vec!["a.log", "b.log", "build/deep/x.log"]);
+
+        let p = &preview("find . -maxdepth 1 -name '*.log' ! -size -4c -delete", &context)[0];
+        assert_eq!(names(p, &dir), vec!["a.log"]);
+
+        let p = &preview("find build -empty -o -name x -delete", &context)[0];
+        assert!(p.entries.is_empty());
+        assert_eq!(p.notes, vec!["cannot preview find -o"]);
+
+        let p = &preview("find . -type d -name deep -exec rm -rf {} +", &context)[0];
+        assert_eq!(names(p, &dir), vec!["build/deep"]);
+        assert_eq!((p.files, p.directories), (1, 1));
+
+        assert!(preview("find . -name '*.log'", &context).is_empty());
+    }
+
+    #[test]
+    fn test_find_walk_is_capped() {
+        let (dir, context) = fixture();
+        let query = parse_find(&[".", "-name", "nothing"], &context).unwrap();
+        let mut walk = Walk { matches: Vec::new(), budget: 3, truncated: false };
+        find_matches(dir.path(), 0, &query, &mut walk);
+        // Nothing matched, yet the walk still stopped
+        assert!(walk.matches.is_empty());
+        assert!(walk.truncated);
+    }
+
+    #[test]
+    fn test_move_and_permissions() {
+        let (dir, context) = fixture();
+        let p = &preview("mv a.log b.log", &context)[0];
+        assert_eq!(names(p, &dir), vec!["a.log"]);
+        assert_eq!(p.notes, vec!["would overwrite b.log"]);
+
+        let p = &preview("chmod -R 755 build", &context)[0];
+        assert_eq!((p.files, p.directories), (2, 2));
+        assert!(p.action.starts_with("chmod -R"));
+
+        assert!(preview("chmod 644 a.log && ls -la", &context).is_empty());
+    }
+
+    #[test]
+    fn test_human_size() {
+        assert_eq!(human_size(0), "0 B");
+        assert_eq!(human_size(1536), "1.5 KiB");
+        assert_eq!(human_size(5 * 1024 * 1024 * 1024), "5.0 GiB");
+    }
+}
@@ -0,0 +1,249 @@
+// src/privilege.rs
+//! Finds the parts of a command that run as root, and removes `sudo`-style
+//! prefixes for users who would rather run those themselves.
+
+use crate::parser::{Script, SimpleCommand};
+
+/// Programs that run a command, or a shell, as another user.
+const ESCALATORS: &[&str] = &["sudo", "su", "doas", "pkexec", "run0"];
+
+/// Escalators that can be dropped from the front of a command, and their
+/// options that take a separate value.
+const STRIPPABLE: &[(&str, &[&str])] = &[
+    ("sudo", &["-u", "-g", "-C", "-D", "-h", "-p", "-r", "-t", "-U", "-T", "--user", "--group"]),
+    ("doas", &["-u", "-C"]),
+    ("pkexec", &["--user"]),
+    ("run0", &["-u", "-g", "-D", "--user", "--group", "--chdir"]),
+];
+
+/// A command that runs with elevated privileges.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Escalation {
+    /// The program granting the privileges, e.g. `sudo`.
+    pub via: String,
+    /// The user it runs as: `root` unless `-u` or similar says otherwise.
+    pub user: String,
+    /// What runs, as written after the escalation prefix.
+    pub command: String,
+}
+
+/// Every command in `script` that runs as another user, in source order.
+pub fn escalations(script: &Script) -> Vec<Escalation> {
+    let mut found = Vec::new();
+    for cmd in script.commands() {
+        if let Some(index) = cmd.wrappers.iter().rposition(|w| ESCALATORS.contains(&w.as_str())) {
+            let via = cmd.wrappers[index].clone();
+            let chars: Vec<char> = cmd.wrapper_sources.get(index).map(|s| s.chars().collect()).unwrap_or_default();
+            let escalation = match prefix_end(&chars, 0) {
+                Some((end, user)) => Escalation { via, user, command: chars[end..].iter().collect() },
+                // `find -exec sudo ...` has no source text of its own
+                None => {
+                    let mut inner = cmd.clone();
+                    inner.env.clear();
+                    inner.wrappers.drain(..=index);
+                    Escalation { via, user: "root".to_string(), command: inner.to_string() }
+                }
+            };
+            found.push(escalation);
+        } else if ESCALATORS.contains(&cmd.name()) && cmd.wrappers.is_empty() {
+            // A bare `su`, `sudo -i` or `su -c '...'`
+            let user = bare_user(cmd);
+            found.push(Escalation { via: cmd.name().to_string(), command: describe(cmd, &user), user });
+        }
+    }
+    found
+}
+
+/// `su -c 'cmd'` runs `cmd`; any other bare escalator opens a shell.
+fn describe(cmd: &SimpleCommand, user: &str) -> String {
+    let inner: Vec<String> = cmd.nested.iter().flat_map(|s| s.commands()).map(|c| c.to_string()).collect();
+    if !inner.is_empty() {
+        inner.join("; ")
+    } else if user == "root" {
+        format!("an interactive root shell (`{}`)", cmd)
+    } else {
+        format!("an interactive shell as {} (`{}`)", user, cmd)
+    }
+}
+
+/// The user a bare escalator switches to: `sudo -u app -i`, `su - app`.
+fn bare_user(cmd: &SimpleCommand) -> String {
+    let mut args = cmd.args.iter().map(|w| w.text.as_str());
+    while let Some(arg) = args.next() {
+        if let Some(user) = arg.strip_prefix("--user=") {
+            return user.to_string();
+        }
+        if arg == "-u" || arg == "--user" {
+            if let Some(user) = args.next() {
+                return user.to_string();
+            }
+        } else if cmd.name() == "su" && arg.starts_with('-') && !arg.starts_with("--") && arg.ends_with('c') {
+            args.next();
+        } else if cmd.name() == "su" && !arg.starts_with('-') {
+            return arg.to_string();
+        }
+    }
+    "root".to_string()
+}
+
+/// Removes `sudo`, `doas`, `pkexec` and `run0` prefixes, with their
+/// options, wherever a command starts. Returns `None` if there were none.
+/// `su` is left alone: what it runs is a quoted string, not a prefix.
+pub fn strip(command: &str) -> Option<String> {
+    let chars: Vec<char> = command.chars().collect();
+    let mut out = String::new();
+    let mut stripped = false;
+    let mut at_command_start = true;
+    let mut quote: Option<char> = None;
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        if let Some(q) = quote {
+            out.push(c);
+            if c == '\\' && q == '"' {
+                if let Some(&next) = chars.get(i + 1) {
+                    out.push(next);
+                    i += 1;
+                }
+            } else if c == q {
+                quote = None;
+            }
+            i += 1;
+            continue;
+        }
+
+        if at_command_start && !c.is_whitespace() {
+            at_command_start = false;
+            if let Some((end, _)) = prefix_end(&chars, i) {
+                stripped = true;
+                i = end;
+                at_command_start = true;
+                continue;
+            }
+        }
+
+        match c {
+            '\'' | '"' => quote = Some(c),
+            '\\' => {
+                out.push(c);
+                if let Some(&next) = chars.get(i + 1) {
+                    out.push(next);
+                }
+                i += 2;
+                continue;
+            }
+            ';' | '&' | '|' | '(' | '{' | '`' | '\n' => at_command_start = true,
+            _ => {}
+        }
+        out.push(c);
+        i += 1;
+    }
+    stripped.then_some(out)
+}
+
+/// If an escalation prefix starts at `start`, the index of the program it
+/// runs and the user it runs as.
+fn prefix_end(chars: &[char], start: usize) -> Option<(usize, String)> {
+    let (name, mut i) = word_at(chars, start);
+    let name = name.rsplit('/').next().unwrap_or(&name).to_string();
+    STRIPPABLE.iter().find(|(escalator, _)| *escalator == name)?;
+
+    let mut user = "root".to_string();
+    let mut expects_value = false;
+    let mut expects_user = false;
+    loop {
+        i = skip_blanks(chars, i);
+        let (word, end) = word_at(chars, i);
+        if word.is_empty() {
+            // Nothing to run (`sudo -v`), so there is no unprivileged equivalent
+            return None;
+        }
+        if expects_value {
+            if expects_user {
+                user = word.trim_matches(|c| c == '\'' || c == '"').to_string();
+            }
+            expects_value = false;
+        } else if word == "--" {
+            return Some((skip_blanks(chars, end), user));
+        } else if let Some(value) = word.strip_prefix("--user=") {
+            user = value.to_string();
+        } else if word.starts_with('-') {
+            expects_value = takes_value(&name, &word);
+            expects_user = word == "-u" || word == "--user";
+        } else {
+            return Some((i, user));
+        }
+        i = end;
+    }
+}
+
+/// The unquoted word starting at `start` and the index just past it.
+fn word_at(chars: &[char], start: usize) -> (String, usize) {
+    let mut end = start;
+    while end < chars.len() && !chars[end].is_whitespace() && !matches!(chars[end], ';' | '&' | '|' | ')') {
+        end += 1;
+    }
+    (chars[start..end].iter().collect(), end)
+}
+
+fn skip_blanks(chars: &[char], mut i: usize) -> usize {
+    while i < chars.len() && (chars[i] == ' ' || chars[i] == '\t') {
+        i += 1;
+    }
+    i
+}
+
+fn takes_value(escalator: &str, option: &str) -> bool {
+    STRIPPABLE
+        .iter()
+        .find(|(name, _)| *name == escalator)
+        .is_some_and(|(_, options)| options.contains(&option))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    fn root(command: &str) -> Vec<(String, String)> {
+        escalations(&parse(command).unwrap()).into_iter().map(|e| (e.via, e.command)).collect()
+    }
+
+    fn users(command: &str) -> Vec<String> {
+        escalations(&parse(command).unwrap()).into_iter().map(|e| e.user).collect()
+    }
+
+    #[test]
+    fn test_escalations() {
+        assert_eq!(
+            root("ls && sudo -u root nice -n 5 apt install -y ripgrep"),
+            vec![("sudo".to_string(), "nice -n 5 apt install -y ripgrep".to_string())]
+        );
+        assert_eq!(root("sudo tee -a '/etc/my hosts' > /dev/null")[0].1, "tee -a '/etc/my hosts' > /dev/null");
+        assert_eq!(root("su -c 'systemctl restart nginx'"), vec![("su".to_string(), "systemctl restart nginx".to_string())]);
+        assert_eq!(root("sudo -i")[0].1, "an interactive root shell (`sudo -i`)");
+        assert_eq!(root("doas sh -c 'rm -f /var/log/x'").len(), 1);
+        assert!(root("ls -la /root").is_empty());
+    }
+
+    #[test]
+    fn test_escalation_users() {
+        assert_eq!(users("sudo -u postgres psql"), vec!["postgres"]);
+        assert_eq!(users("pkexec --user backup tar czf /srv/x.tgz /srv/x"), vec!["backup"]);
+        assert_eq!(users("sudo -g adm cat /var/log/syslog"), vec!["root"]);
+        assert_eq!(users("su - deploy"), vec!["deploy"]);
+        assert_eq!(root("sudo -u app -i")[0].1, "an interactive shell as app (`sudo -u app -i`)");
+    }
+
+    #[test]
+    fn test_strip() {
+        assert_eq!(strip("sudo apt update && sudo -u root apt install -y jq").as_deref(), Some("apt update && apt install -y jq"));
+        assert_eq!(strip("echo 'sudo rm x' | doas tee /etc/x").as_deref(), Some("echo 'sudo rm x' | tee /etc/x"));
+        assert_eq!(strip("(pkexec --user root ls)").as_deref(), Some("(ls)"));
+        assert_eq!(strip("sudo -- rm -f /tmp/x").as_deref(), Some("rm -f /tmp/x"));
+        assert_eq!(strip("sudo -v"), None);
+        assert_eq!(strip("ls sudo"), None);
+        assert_eq!(strip("su -c 'ls'"), None);
+    }
+}
@@ -0,0 +1,397 @@
+// src/rce.rs
+//! Detects commands that run code nobody has looked at: downloads piped
+//! into a shell, `eval` of a download, decoded payloads fed to an
+//! interpreter and package sources added from unknown hosts.
+//!
+//! Where it can, each finding carries a rewrite that saves the script to a
+//! file, opens it for inspection and only then runs it.
+
+use crate::config::RiskLevel;
+use crate::parser::{Pipeline, Script, SimpleCommand, Stage};
+use crate::placeholder::shell_quote;
+use crate::safety::Finding;
+
+/// Programs that execute a script read from stdin or a file argument.
+const INTERPRETERS: &[&str] = &[
+    "sh", "bash", "zsh", "dash", "ksh", "fish", "python", "python2", "python3", "perl", "ruby", "node", "php",
+];
+
+/// Builtins that run their argument as shell code.
+const EVALUATORS: &[&str] = &["eval", "source", "."];
+
+const PRIVILEGED_WRAPPERS: &[&str] = &["sudo", "doas", "pkexec", "su"];
+
+/// Hosts of the official Debian and Ubuntu archives.
+const TRUSTED_APT_HOSTS: &[&str] = &[
+    "deb.debian.org",
+    "security.debian.org",
+    "archive.ubuntu.com",
+    "security.ubuntu.com",
+    "ports.ubuntu.com",
+];
+
+/// Every remote-code-execution pattern in `script`.
+pub fn findings(script: &Script) -> Vec<Finding> {
+    let mut findings = Vec::new();
+
+    let mut pipelines = Vec::new();
+    collect_pipelines(script, &mut pipelines);
+    for pipeline in pipelines {
+        findings.extend(pipeline_finding(pipeline));
+    }
+
+    for cmd in script.commands() {
+        findings.extend(substitution_finding(cmd));
+    }
+
+    if let Some(cmd) = script.commands().into_iter().find(|cmd| adds_package_source(cmd)) {
+        if !all_urls_trusted(script) {
+            findings.push(Finding {
+                rule: "untrusted-package-source".to_string(),
+                reason: "adds a package source; anything installed from it later runs as root, so only add \
+                         repositories you trust"
+                    .to_string(),
+                risk: RiskLevel::Destructive,
+                segment: Some(cmd.to_string()),
+                requires: None,
+                alternative: None,
+            });
+        }
+    }
+
+    findings
+}
+
+/// Every pipeline in the script, including those inside groups and nested
+/// scripts.
+fn collect_pipelines<'a>(script: &'a Script, out: &mut Vec<&'a Pipeline>) {
+    for pipeline in &script.pipelines {
+        out.push(pipeline);
+        for stage in &pipeline.stages {
+            match stage {
+                Stage::Command(cmd) => {
+                    for nested in &cmd.nested {
+                        collect_pipelines(nested, out);
+                    }
+                }
+                Stage::Group(body) => collect_pipelines(body, out),
+            }
+        }
+    }
+}
+
+/// `curl URL | sh` or `base64 -d blob | sh`: an interpreter reading its
+/// program from a pipe fed by a download or a decoder.
+fn pipeline_finding(pipeline: &Pipeline) -> Option<Finding> {
+    let runner = pipeline.stages.iter().position(|stage| match stage {
+        Stage::Command(cmd) => reads_script_from_stdin(cmd),
+        Stage::Group(_) => false,
+    })?;
+    let Stage::Command(interpreter) = &pipeline.stages[runner] else { return None };
+    // Commands feeding the interpreter, including those inside `( ... )` groups
+    let source: Vec<&SimpleCommand> = pipeline.stages[..runner]
+        .iter()
+        .flat_map(|stage| match stage {
+            Stage::Command(cmd) => vec![cmd],
+            Stage::Group(body) => body.commands(),
+        })
+        .collect();
+
+    if let Some(downloader) = source.iter().find(|cmd| downloads_to_stdout(cmd)) {
+        return Some(Finding {
+            rule: "pipe-to-shell".to_string(),
+            reason: format!(
+                "pipes a download straight into `{}`, running whatever the server sends without a chance to \
+                 read it",
+                interpreter.name()
+            ),
+            risk: rating(interpreter),
+            segment: Some(interpreter.to_string()),
+            requires: None,
+            alternative: download_then_run(downloader, interpreter),
+        });
+    }
+
+    if source.iter().any(|cmd| decodes(cmd)) {
+        // Stopping before the interpreter prints the payload instead of running it
+        let preview: Vec<String> = pipeline.stages[..runner].iter().map(display_stage).collect();
+        return Some(Finding {
+            rule: "decode-to-shell".to_string(),
+            reason: format!("decodes a hidden payload and runs it with `{}`", interpreter.name()),
+            risk: rating(interpreter),
+            segment: Some(interpreter.to_string()),
+            requires: None,
+            alternative: Some(preview.join(" | ")),
+        });
+    }
+    None
+}
+
+fn display_stage(stage: &Stage) -> String {
+    match stage {
+        Stage::Command(cmd) => cmd.to_string(),
+        Stage::Group(body) => {
+            let commands: Vec<String> = body.commands().iter().map(|cmd| cmd.to_string()).collect();
+            format!("( {} )", commands.join("; "))
+        }
+    }
+}
+
+/// `bash <(curl URL)`, `eval "$(curl URL)"`, `source <(wget -O- URL)` and
+/// `eval "$(echo ... | base64 -d)"`.
+fn substitution_finding(cmd: &SimpleCommand) -> Option<Finding> {
+    let name = cmd.name();
+    let evaluates = EVALUATORS.contains(&name);
+    if !evaluates && !INTERPRETERS.contains(&name) {
+        return None;
+    }
+    // Interpreters only count when handed a substitution, not `sh -c 'curl URL'`
+    let substituted = evaluates
+        || cmd.args.iter().any(|w| w.text.contains("$(") || w.text.contains("<(") || w.text.contains('`'));
+    if !substituted {
+        return None;
+    }
+    let inner: Vec<&SimpleCommand> = cmd.nested.iter().flat_map(|script| script.commands()).collect();
+
+    if let Some(downloader) = inner.iter().find(|inner| downloads_to_stdout(inner)) {
+        return Some(Finding {
+            rule: "eval-download".to_string(),
+            reason: format!(
+                "runs code fetched from the network with `{}` without saving it for inspection",
+                name
+            ),
+            risk: rating(cmd),
+            segment: Some(cmd.to_string()),
+            requires: None,
+            alternative: download_then_run(downloader, cmd),
+        });
+    }
+    if inner.iter().any(|inner| decodes(inner)) {
+        return Some(Finding {
+            rule: "decode-to-shell".to_string(),
+            reason: format!("decodes a hidden payload and runs it with `{}`", name),
+            risk: rating(cmd),
+            segment: Some(cmd.to_string()),
+            requires: None,
+            alternative: None,
+        });
+    }
+    None
+}
+
+/// Running someone else's code as root can take over the whole machine.
+fn rating(runner: &SimpleCommand) -> RiskLevel {
+    if runner.wrappers.iter().any(|w| PRIVILEGED_WRAPPERS.contains(&w.as_str())) {
+        RiskLevel::SystemCritical
+    } else {
+        RiskLevel::Destructive
+    }
+}
+
+/// `sh`, `bash -s -- --flag`, `python3 -`: an interpreter with no script
+/// file, so it runs whatever arrives on stdin.
+fn reads_script_from_stdin(cmd: &SimpleCommand) -> bool {
+    if !INTERPRETERS.contains(&cmd.name()) || cmd.has_short_flag('c') || cmd.has_short_flag('e') {
+        return false;
+    }
+    cmd.has_short_flag('s') || cmd.operands().next().is_none_or(|word| word.text == "-")
+}
+
+/// Whether the download goes to stdout rather than a file.
+fn downloads_to_stdout(cmd: &SimpleCommand) -> bool {
+    let args: Vec<&str> = cmd.args.iter().map(|w| w.text.as_str()).collect();
+    let to_stdout = args.windows(2).any(|pair| matches!(pair[0], "-o" | "-O" | "--output" | "--output-document") && pair[1] == "-")
+        || args.iter().any(|arg| matches!(*arg, "--output=-" | "--output-document=-"));
+
+    match cmd.name() {
+        "curl" | "fetch" => {
+            let to_file = cmd.has_short_flag('o')
+                || cmd.has_short_flag('O')
+                || cmd.has_long_flag("output")
+                || cmd.has_long_flag("remote-name")
+                || cmd.has_long_flag("remote-name-all");
+            to_stdout || !to_file
+        }
+        // wget saves to a file unless told `-O -`, including clusters like `-qO-`
+        "wget" => {
+            to_stdout
+                || args.iter().any(|arg| arg.starts_with('-') && !arg.starts_with("--") && arg.ends_with("O-"))
+        }
+        _ => false,
+    }
+}
+
+fn decodes(cmd: &SimpleCommand) -> bool {
+    let args: Vec<&str> = cmd.args.iter().map(|w| w.text.as_str()).collect();
+    match cmd.name() {
+        "base64" | "base32" => cmd.has_short_flag('d') || cmd.has_short_flag('D') || cmd.has_long_flag("decode"),
+        "xxd" => cmd.has_short_flag('r'),
+        "openssl" => args.iter().any(|arg| matches!(*arg, "base64" | "-base64" | "-a")) && args.contains(&"-d"),
+        _ => false,
+    }
+}
+
+fn adds_package_source(cmd: &SimpleCommand) -> bool {
+    let writes_source_list = |target: &str| target.starts_with("/etc/apt/sources.list");
+    if cmd.redirects.iter().any(|r| r.writes() && writes_source_list(&r.target)) {
+        return true;
+    }
+    let operands: Vec<&str> = cmd.operands().map(|w| w.text.as_str()).collect();
+    match cmd.name() {
+        "add-apt-repository" | "apt-add-repository" => !cmd.has_short_flag('r') && !cmd.has_long_flag("remove"),
+        "tee" | "cp" | "mv" | "install" => operands.iter().any(|target| writes_source_list(target)),
+        "apt-key" => operands.first() == Some(&"add"),
+        "yum-config-manager" => cmd.has_long_flag("add-repo"),
+        "dnf" => operands.first() == Some(&"config-manager") && cmd.has_long_flag("add-repo"),
+        "zypper" => operands.first().is_some_and(|sub| matches!(*sub, "ar" | "addrepo")),
+        _ => false,
+    }
+}
+
+/// Whether every URL in the script points at an official archive. A script
+/// with no URLs at all (`add-apt-repository ppa:...`) is not trusted.
+fn all_urls_trusted(script: &Script) -> bool {
+    let urls: Vec<String> = script
+        .commands()
+        .iter()
+        .flat_map(|cmd| cmd.args.iter())
+        .flat_map(|word| word.text.split_whitespace().map(str::to_string).collect::<Vec<_>>())
+        .filter(|token| token.contains("://"))
+        .collect();
+    !urls.is_empty() && urls.iter().all(|url| TRUSTED_APT_HOSTS.contains(&host(url)))
+}
+
+fn host(url: &str) -> &str {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let authority = rest.split('/').next().unwrap_or(rest);
+    let authority = authority.rsplit('@').next().unwrap_or(authority);
+    authority.split(':').next().unwrap_or(authority)
+}
+
+/// Rewrites a download-and-run into: save to a file, open it in `less`, then
+/// run the saved file the way the original command would have.
+fn download_then_run(downloader: &SimpleCommand, runner: &SimpleCommand) -> Option<String> {
+    let url = downloader
+        .operands()
+        .map(|w| w.text.as_str())
+        .find(|text| text.contains("://"))
+        .or_else(|| downloader.operands().map(|w| w.text.as_str()).find(|text| looks_like_host(text)))?;
+
+    let name = url
+        .split(['?', '#'])
+        .next()
+        .and_then(|path| path.split("://").last())
+        .and_then(|path| path.split_once('/').map(|(_, path)| path))
+        .and_then(|path| path.rsplit('/').next())
+        .filter(|name| !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || "._-".contains(c)))
+        .unwrap_or("install.sh");
+    let file = format!("/tmp/{}", name);
+
+    let download = match downloader.name() {
+        "wget" => format!("wget -q -O {} {}", file, shell_quote(url)),
+        _ => format!("curl -fsSL -o {} {}", file, shell_quote(url)),
+    };
+
+    let mut run: Vec<String> = runner.env.iter().map(|(k, v)| format!("{}={}", k, shell_quote(v))).collect();
+    run.extend(runner.wrappers.iter().cloned());
+    match runner.name() {
+        "eval" => run.push(format!("eval \"$(cat {})\"", file)),
+        "source" | "." => {
+            run.push(runner.program.clone());
+            run.push(file.clone());
+        }
+        _ => {
+            run.push(runner.program.clone());
+            run.push(file.clone());
+            let substituted = |text: &str| text.contains("$(") || text.contains("<(") || text.contains('`');
+            run.extend(
+                runner
+                    .args
+                    .iter()
+                    .map(|w| w.text.as_str())
+                    .filter(|text| !matches!(*text, "-s" | "-" | "--") && !substituted(text))
+                    .map(shell_quote),
+            );
+        }
+    }
+    Some(format!("{} && less {} && {}", download, file, run.join(" ")))
+}
+
+fn looks_like_host(text: &str) -> bool {
+    !text.starts_with('-') && text.contains('.') && !text.contains(char::is_whitespace)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    fn rules(command: &str) -> Vec<(String, RiskLevel, Option<String>)> {
+        findings(&parse(command).unwrap())
+            .into_iter()
+            .map(|f| (f.rule, f.risk, f.alternative))
+            .collect()
+    }
+
+    #[test]
+    fn test_pipe_to_shell() {
+        assert_eq!(
+            rules("curl -fsSL https://get.example.com/install.sh | sudo bash -s -- --yes"),
+            vec![(
+                "pipe-to-shell".to_string(),
+                RiskLevel::SystemCritical,
+                Some(
+                    "curl -fsSL -o /tmp/install.sh https://get.example.com/install.sh && less /tmp/install.sh \
+                     && sudo bash /tmp/install.sh --yes"
+                        .to_string()
+                ),
+            )]
+        );
+        assert_eq!(
+            rules("wget -qO- https://x.io/setup | sh")[0].2.as_deref(),
+            Some("wget -q -O /tmp/setup https://x.io/setup && less /tmp/setup && sh /tmp/setup")
+        );
+        assert_eq!(rules("(curl https://x.io/a.sh) | sh")[0].0, "pipe-to-shell");
+        assert!(rules("curl -o a.sh https://x.io/a.sh && sh a.sh").is_empty());
+        assert!(rules("wget https://x.io/a.sh | sh").is_empty());
+        assert!(rules("curl https://x.io/data.json | python3 -m json.tool").is_empty());
+        assert!(rules("curl https://x.io/a | grep foo").is_empty());
+    }
+
+    #[test]
+    fn test_eval_and_decode() {
+        let found = rules("eval \"$(curl -s https://x.io/env)\"");
+        assert_eq!(found[0].0, "eval-download");
+        assert_eq!(
+            found[0].2.as_deref(),
+            Some("curl -fsSL -o /tmp/env https://x.io/env && less /tmp/env && eval \"$(cat /tmp/env)\"")
+        );
+        assert_eq!(rules("bash <(curl -s https://x.io/i.sh)")[0].0, "eval-download");
+        assert!(rules("sh -c 'curl https://x.io'").is_empty());
+
+        let found = rules("echo ZWNobyBoaQ== | base64 -d | bash");
+        assert_eq!(
+            found,
+            vec![(
+                "decode-to-shell".to_string(),
+                RiskLevel::Destructive,
+                Some("echo ZWNobyBoaQ== | base64 -d".to_string())
+            )]
+        );
+        assert_eq!(rules("eval \"$(echo ZWNobyBoaQ== | base64 --decode)\"")[0].0, "decode-to-shell");
+        assert!(rules("base64 -d blob > out.bin").is_empty());
+    }
+
+    #[test]
+    fn test_package_sources() {
+        assert_eq!(rules("sudo add-apt-repository ppa:someone/tools")[0].0, "untrusted-package-source");
+        assert_eq!(
+            rules("echo 'deb https://repo.example.com stable main' | sudo tee /etc/apt/sources.list.d/x.list")[0].0,
+            "untrusted-package-source"
+        );
+        assert_eq!(rules("sudo dnf config-manager --add-repo https://x.io/x.repo")[0].0, "untrusted-package-source");
+        assert!(rules("echo 'deb http://deb.debian.org/debian bookworm main' | sudo tee -a /etc/apt/sources.list")
+            .is_empty());
+        assert!(rules("sudo apt install ripgrep").is_empty());
+    }
+}
@@ -0,0 +1,741 @@
+// src/safety.rs
+use crate::config::{RiskAction, RiskLevel, RiskPolicy, SecurityConfig};
+use crate::parser::{self, Script, SimpleCommand};
+use crate::paths::{self, Context};
+use crate::policy::Verdict;
+use crate::privilege;
+use crate::rce;
+use crate::secrets;
+use crate::shell::ShellType;
+
+/// A local rule that flagged a suggested command.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Finding {
+    pub rule: String,
+    pub reason: String,
+    pub risk: RiskLevel,
+    /// The simple command that triggered the rule, when there is one.
+    pub segment: Option<String>,
+    /// The least the REPL must do before running, whatever the risk policy says.
+    pub requires: Option<RiskAction>,
+    /// A safer command that achieves the same thing, when there is one.
+    pub alternative: Option<String>,
+}
+
+impl Finding {
+    pub fn is_privilege_escalation(&self) -> bool {
+        self.rule == PRIVILEGE_ESCALATION
+    }
+}
+
+impl std::fmt::Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}] {} ({})", self.rule, self.reason, self.risk.label())?;
+        if let Some(segment) = &self.segment {
+            write!(f, " in `{}`", segment)?;
+        }
+        Ok(())
+    }
+}
+
+/// The local verdict on a command: its risk level and the rules behind it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Assessment {
+    pub risk: RiskLevel,
+    pub findings: Vec<Finding>,
+}
+
+impl Assessment {
+    /// What the REPL must do for a command of `risk` (the higher of the AI's
+    /// and our own rating), taking rule-specific requirements into account.
+    pub fn required_action(&self, risk: RiskLevel, policy: &RiskPolicy) -> RiskAction {
+        self.findings
+            .iter()
+            .filter_map(|f| f.requires)
+            .fold(policy.action_for(risk.max(self.risk)), RiskAction::max)
+    }
+
+    /// The findings for commands that run as root.
+    pub fn runs_as_root(&self) -> impl Iterator<Item = &Finding> {
+        self.findings.iter().filter(|f| f.is_privilege_escalation())
+    }
+
+    /// The final decision for a command of `risk`: what the risk policy
+    /// requires, overridden by the policy rules that matched, but never
+    /// less than a confirmation for anything that runs as root.
+    pub fn decide(&self, risk: RiskLevel, policy: &RiskPolicy, verdict: &Verdict) -> RiskAction {
+        let action = verdict.apply(self.required_action(risk, policy));
+        if self.runs_as_root().next().is_some() {
+            action.max(RiskAction::Confirm)
+        } else {
+            action
+        }
+    }
+}
+
+const PRIVILEGE_ESCALATION: &str = "privilege-escalation";
+
+/// Checks a command against the configured dangerous patterns and the
+/// built-in rules. The result does not depend on the model, so a suggestion
+/// the AI marks as safe is still caught here.
+///
+/// Bash commands are parsed so rules see real program names and flags in
+/// every pipeline, list, subshell and substitution. Other shells fall back to
+/// matching the configured patterns as text and are never rated read-only.
+pub fn analyze(command: &str, shell: &ShellType, security: &SecurityConfig, context: &Context) -> Assessment {
+    let mut patterns: Vec<&String> = security.dangerous_commands.iter().collect();
+    patterns.sort();
+
+    let (baseline, mut findings) = if *shell == ShellType::Bash {
+        let (baseline, mut findings, script) = bash_findings(command, &patterns);
+        if let Some(script) = script {
+            findings.extend(directory_findings(&script, security, context));
+        }
+        (baseline, findings)
+    } else {
+        (RiskLevel::Modifying, text_findings(command, &patterns))
+    };
+
+    findings.extend(secrets::finding(command, *shell == ShellType::Bash));
+
+    let risk = findings.iter().map(|f| f.risk).fold(baseline, RiskLevel::max);
+    Assessment { risk, findings }
+}
+
+fn bash_findings(command: &str, patterns: &[&String]) -> (RiskLevel, Vec<Finding>, Option<Script>) {
+    let script = match parser::parse(command) {
+        Ok(script) => script,
+        Err(e) => {
+            // Anything we cannot parse is treated as dangerous
+            let mut findings = text_findings(command, patterns);
+            findings.push(Finding {
+                rule: "unparsable".to_string(),
+                reason: format!("could not be parsed for safety analysis: {}", e),
+                risk: RiskLevel::Destructive,
+                segment: None,
+                requires: None,
+                alternative: None,
+            });
+            return (RiskLevel::Modifying, findings, None);
+        }
+    };
+    let commands = script.commands();
+    let mut findings = Vec::new();
+
+    for pattern in patterns {
+        let Some(wanted) = Pattern::parse(pattern) else { continue };
+        if let Some(cmd) = commands.iter().find(|cmd| wanted.matches(cmd)) {
+            findings.push(Finding {
+                rule: pattern.trim().to_string(),
+                reason: "matches a command in security.dangerous_commands".to_string(),
+                risk: RiskLevel::Destructive,
+                segment: Some(cmd.to_string()),
+                requires: None,
+                alternative: None,
+            });
+        }
+    }
+
+    if command.split_whitespace().collect::<String>().contains(":(){:|:&};:") {
+        findings.push(Finding {
+            rule: "fork-bomb".to_string(),
+            reason: "defines a function that endlessly spawns copies of itself".to_string(),
+            risk: RiskLevel::SystemCritical,
+            segment: None,
+            requires: None,
+            alternative: None,
+        });
+    }
+
+    for rule in BUILTIN_RULES {
+        if let Some(cmd) = commands.iter().find(|cmd| (rule.check)(cmd)) {
+            findings.push(Finding {
+                rule: rule.name.to_string(),
+                reason: rule.reason.to_string(),
+                risk: rule.risk,
+                segment: Some(cmd.to_string()),
+                requires: None,
+                alternative: None,
+            });
+        }
+    }
+
+    findings.extend(rce::findings(&script));
+
+    // Root can undo any other safeguard, so it is never run without asking
+    for escalation in privilege::escalations(&script) {
+        findings.push(Finding {
+            rule: PRIVILEGE_ESCALATION.to_string(),
+            reason: format!("runs as {} via {}", escalation.user, escalation.via),
+            risk: RiskLevel::Modifying,
+            segment: Some(escalation.command),
+            requires: Some(RiskAction::Confirm),
+            alternative: None,
+        });
+    }
+
+    let baseline = if commands.iter().all(|cmd| is_read_only(cmd)) {
+        RiskLevel::ReadOnly
+    } else {
+        RiskLevel::Modifying
+    };
+    (baseline, findings, Some(script))
+}
+
+/// Flags every write outside `security.allowed_directories`. An empty list
+/// leaves writes unrestricted.
+fn directory_findings(script: &Script, security: &SecurityConfig, context: &Context) -> Vec<Finding> {
+    if security.allowed_directories.is_empty() {
+        return Vec::new();
+    }
+    let roots = paths::allowed_roots(&security.allowed_directories, context);
+    let outside: Vec<String> = paths::touched_paths(script, context)
+        .into_iter()
+        .filter(|touched| touched.write && !is_harmless_device(&touched.path.to_string_lossy()))
+        .filter(|touched| !paths::is_allowed(touched, &roots))
+        .map(|touched| {
+            let shown = touched.path.display().to_string();
+            if shown == touched.raw { shown } else { format!("{} ({})", shown, touched.raw) }
+        })
+        .collect();
+
+    if outside.is_empty() {
+        return Vec::new();
+    }
+    vec![Finding {
+        rule: "outside-allowed-directories".to_string(),
+        reason: format!(
+            "writes to {}, outside security.allowed_directories ({})",
+            outside.join(", "),
+            security.allowed_directories.join(", ")
+        ),
+        risk: RiskLevel::Modifying,
+        segment: None,
+        requires: Some(security.outside_allowed_directories),
+        alternative: None,
+    }]
+}
+
+/// Programs that only read, so a pipeline of them can be rated read-only.
+const READ_ONLY_PROGRAMS: &[&str] = &[
+    "ls", "cat", "less", "more", "head", "tail", "grep", "egrep", "fgrep", "rg", "ag", "wc", "du", "df",
+    "free", "ps", "pgrep", "pwd", "whoami", "id", "groups", "uname", "cal", "echo", "printf", "stat",
+    "file", "which", "whereis", "type", "tree", "cut", "tr", "jq", "yq", "diff", "cmp", "comm",
+    "md5sum", "sha1sum", "sha256sum", "printenv", "uptime", "lsblk", "lsof", "ss", "netstat", "man",
+    "test", "[", "true", "false", "basename", "dirname", "realpath", "readlink", "column", "nl",
+    "hexdump", "od", "strings", "locate", "host", "dig", "nslookup", "ping", "env", "tac",
+    "fold", "paste", "join", "expand", "seq", "cd", "w", "who", "last", "top", "htop",
+];
+
+/// Read-only subcommands of tools that can also modify things.
+const READ_ONLY_SUBCOMMANDS: &[(&str, &[&str])] = &[
+    ("git", &["status", "log", "diff", "show", "blame", "ls-files", "rev-parse", "describe", "shortlog", "reflog", "grep"]),
+    ("docker", &["ps", "images", "inspect", "logs", "version", "info"]),
+    ("kubectl", &["get", "describe", "logs", "top", "version", "explain"]),
+    ("systemctl", &["status", "list-units", "list-unit-files", "is-active", "is-enabled"]),
+    ("cargo", &["tree", "metadata", "search"]),
+    ("npm", &["ls", "list", "view", "outdated"]),
+];
+
+pub fn is_read_only(cmd: &SimpleCommand) -> bool {
+    if cmd.redirects.iter().any(|r| r.writes() && !is_harmless_device(&r.target)) {
+        return false;
+    }
+    let name = cmd.name();
+    if name.is_empty() {
+        return true;
+    }
+    match name {
+        "find" => !cmd.args.iter().any(|w| {
+            matches!(w.text.as_str(), "-delete" | "-exec" | "-execdir" | "-ok" | "-okdir" | "-fprint" | "-fprintf" | "-fls")
+        }),
+        "sed" => !cmd.has_short_flag('i') && !cmd.has_long_flag("in-place"),
+        "sort" => !cmd.has_short_flag('o') && !cmd.has_long_flag("output"),
+        "fd" => !cmd.has_short_flag('x') && !cmd.has_short_flag('X') && !cmd.has_long_flag("exec"),
+        // `uniq IN OUT` and `xxd IN OUT` write their second operand
+        "uniq" | "xxd" => cmd.operands().count() <= 1,
+        "date" => !cmd.has_short_flag('s') && !cmd.has_long_flag("set") && cmd.operands().all(|w| w.text.starts_with('+')),
+        "hostname" => cmd.operands().next().is_none(),
+        "history" => !cmd.args.iter().any(|w| w.text.starts_with('-')),
+        _ => {
+            if let Some((_, subcommands)) = READ_ONLY_SUBCOMMANDS.iter().find(|(tool, _)| *tool == name) {
+                return cmd.operands().next().is_some_and(|sub| subcommands.contains(&sub.text.as_str()));
+            }
+            READ_ONLY_PROGRAMS.contains(&name)
+        }
+    }
+}
+
+/// A `dangerous_commands` entry parsed into program, flags, operands and
+/// redirection targets, so `rm -rf` also catches `rm -r -f` and `rm --recursive --force`.
+struct Pattern {
+    command: SimpleCommand,
+}
+
+/// Spellings treated as equivalent to a short flag in patterns.
+const FLAG_ALIASES: &[(char, &str)] = &[('r', "recursive"), ('R', "recursive"), ('f', "force")];
+const SHORT_ALIASES: &[(char, char)] = &[('r', 'R'), ('R', 'r')];
+
+impl Pattern {
+    fn parse(pattern: &str) -> Option<Self> {
+        let script = parser::parse(pattern.trim()).ok()?;
+        let commands = script.commands();
+        match commands.as_slice() {
+            [command] => Some(Pattern { command: (*command).clone() }),
+            _ => None,
+        }
+    }
+
+    fn matches(&self, cmd: &SimpleCommand) -> bool {
+        let wanted = &self.command;
+        if !wanted.program.is_empty() && wanted.name() != cmd.name() {
+            return false;
+        }
+
+        for arg in &wanted.args {
+            let text = arg.text.as_str();
+            let present = if let Some(long) = text.strip_prefix("--") {
+                cmd.has_long_flag(long)
+            } else if let Some(short) = text.strip_prefix('-').filter(|s| !s.is_empty()) {
+                short.chars().all(|flag| {
+                    cmd.has_short_flag(flag)
+                        || SHORT_ALIASES.iter().any(|(c, alias)| *c == flag && cmd.has_short_flag(*alias))
+                        || FLAG_ALIASES.iter().any(|(c, long)| *c == flag && cmd.has_long_flag(long))
+                })
+            } else {
+                cmd.operands().any(|operand| operand.text == text)
+            };
+            if !present {
+                return false;
+            }
+        }
+
+        wanted.redirects.iter().filter(|r| r.writes()).all(|wanted| {
+            cmd.redirects
+                .iter()
+                .any(|r| r.writes() && is_under(&r.target, &wanted.target) && !is_harmless_device(&r.target))
+        })
+    }
+}
+
+struct Rule {
+    name: &'static str,
+    reason: &'static str,
+    risk: RiskLevel,
+    check: fn(&SimpleCommand) -> bool,
+}
+
+const BUILTIN_RULES: &[Rule] = &[
+    Rule {
+        name: "recursive-delete-root",
+        reason: "recursively deletes /, ~ or $HOME",
+        risk: RiskLevel::SystemCritical,
+        check: |cmd| cmd.name() == "rm" && is_recursive(cmd) && cmd.operands().any(|w| is_root_like(&w.text)),
+    },
+    Rule {
+        name: "raw-disk-write",
+        reason: "writes directly to a block device",
+        risk: RiskLevel::SystemCritical,
+        check: |cmd| {
+            (cmd.name() == "dd" && cmd.operands().any(|w| w.text.strip_prefix("of=").is_some_and(is_block_device)))
+                || (cmd.name() == "tee" && cmd.operands().any(|w| is_block_device(&w.text)))
+                || (matches!(cmd.name(), "cp" | "mv" | "install" | "rsync" | "cat" | "pv")
+                    && cmd.operands().last().is_some_and(|w| is_block_device(&w.text)))
+                || cmd.redirects.iter().any(|r| r.writes() && is_block_device(&r.target))
+        },
+    },
+    Rule {
+        name: "filesystem-format",
+        reason: "creates a new filesystem or wipes partition data",
+        risk: RiskLevel::SystemCritical,
+        check: |cmd| {
+            let name = cmd.name();
+            name == "mkfs" || name.starts_with("mkfs.") || name == "wipefs" || name == "mkswap"
+        },
+    },
+    Rule {
+        name: "recursive-permissions-root",
+        reason: "recursively changes ownership or permissions of a system path",
+        risk: RiskLevel::SystemCritical,
+        check: |cmd| {
+            matches!(cmd.name(), "chmod" | "chown" | "chgrp")
+                && is_recursive(cmd)
+                && cmd.operands().any(|w| is_root_like(&w.text) || is_system_path(&w.text))
+        },
+    },
+    Rule {
+        name: "power-off",
+        reason: "shuts down or reboots the machine",
+        risk: RiskLevel::SystemCritical,
+        check: |cmd| {
+            matches!(cmd.name(), "shutdown" | "reboot" | "halt" | "poweroff")
+                || (cmd.name() == "systemctl"
+                    && cmd.operands().any(|w| matches!(w.text.as_str(), "poweroff" | "reboot" | "halt")))
+        },
+    },
+    Rule {
+        name: "file-delete",
+        reason: "permanently deletes files",
+        risk: RiskLevel::Destructive,
+        // `find -exec rm {} +` is caught through its nested `rm`
+        check: |cmd| {
+            matches!(cmd.name(), "rm" | "shred" | "unlink" | "srm")
+                || (cmd.name() == "find" && cmd.args.iter().any(|w| w.text == "-delete"))
+        },
+    },
+    Rule {
+        name: "git-discard",
+        reason: "throws away uncommitted changes or untracked files",
+        risk: RiskLevel::Destructive,
+        check: |cmd| {
+            let sub = cmd.operands().next().map(|w| w.text.as_str());
+            cmd.name() == "git"
+                && match sub {
+                    Some("clean") => !cmd.has_short_flag('n') && !cmd.has_long_flag("dry-run"),
+                    Some("reset") => cmd.has_long_flag("hard"),
+                    _ => false,
+                }
+        },
+    },
+    Rule {
+        name: "kill-all",
+        reason: "signals every process, or a whole process group",
+        risk: RiskLevel::Destructive,
+        check: |cmd| cmd.name() == "kill" && kills_group(cmd),
+    },
+];
+
+/// Whether `kill` is given `-1` or a negative process group id. The first
+/// option is the signal, so `kill -1 123` only sends SIGHUP to 123.
+fn kills_group(cmd: &SimpleCommand) -> bool {
+    let mut args = cmd.args.iter().map(|w| w.text.as_str());
+    let mut signal = false;
+    let mut pids = false;
+    while let Some(arg) = args.next() {
+        let negative = arg.strip_prefix('-').is_some_and(|id| !id.is_empty() && id.chars().all(|c| c.is_ascii_digit()));
+        if pids || !arg.starts_with('-') {
+            pids = true;
+            if negative {
+                return true;
+            }
+        } else if arg == "--" {
+            pids = true;
+        } else if matches!(arg, "-l" | "-L") {
+            return false;
+        } else if matches!(arg, "-s" | "-n") {
+            args.next();
+            signal = true;
+        } else if !signal {
+            signal = true;
+        } else if negative {
+            return true;
+        }
+    }
+    false
+}
+
+fn is_recursive(cmd: &SimpleCommand) -> bool {
+    cmd.has_short_flag('r') || cmd.has_short_flag('R') || cmd.has_long_flag("recursive")
+}
+
+fn is_root_like(word: &str) -> bool {
+    matches!(word, "/" | "/*" | "~" | "~/" | "~/*" | "$HOME" | "$HOME/" | "$HOME/*" | "${HOME}" | "${HOME}/*")
+}
+
+fn is_system_path(word: &str) -> bool {
+    ["/etc", "/usr", "/bin", "/sbin", "/lib", "/boot", "/var", "/sys", "/proc", "/dev"]
+        .iter()
+        .any(|path| is_under(word, path))
+}
+
+fn is_block_device(path: &str) -> bool {
+    [
+        "/dev/sd", "/dev/nvme", "/dev/hd", "/dev/vd", "/dev/xvd", "/dev/disk", "/dev/mmcblk", "/dev/mapper/",
+        "/dev/md", "/dev/dm-", "/dev/loop", "/dev/nbd", "/dev/sr", "/dev/zd",
+    ]
+        .iter()
+        .any(|prefix| path.starts_with(prefix))
+}
+
+/// Device files that are routinely written to and never worth a warning.
+fn is_harmless_device(path: &str) -> bool {
+    matches!(path, "/dev/null" | "/dev/stdout" | "/dev/stderr" | "/dev/tty") || path.starts_with("/dev/fd/")
+}
+
+/// Whether `path` is `root` or inside it, component-wise.
+fn is_under(path: &str, root: &str) -> bool {
+    let root = root.trim_end_matches('/');
+    root.is_empty() || path == root || path.starts_with(&format!("{}/", root))
+}
+
+/// Pattern matching for shells we cannot parse: collapse whitespace and match
+/// each pattern as text, refusing to start or end inside a word.
+fn text_findings(command: &str, patterns: &[&String]) -> Vec<Finding> {
+    let normalized = normalize(command);
+    patterns
+        .iter()
+        .filter(|pattern| {
+            let wanted = normalize(pattern);
+            !wanted.is_empty() && contains_at_boundary(&normalized, &wanted)
+        })
+        .map(|pattern| Finding {
+            rule: pattern.trim().to_string(),
+            reason: "matches a command in security.dangerous_commands".to_string(),
+            risk: RiskLevel::Destructive,
+            segment: None,
+            requires: None,
+            alternative: None,
+        })
+        .collect()
+}
+
+fn normalize(command: &str) -> String {
+    command.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn contains_at_boundary(haystack: &str, needle: &str) -> bool {
+    let is_word = |c: char| c.is_alphanumeric() || c == '_' || c == '-';
+    haystack.match_indices(needle).any(|(index, _)| {
+        let before = haystack[..index].chars().next_back();
+        let after = haystack[index + needle.len()..].chars().next();
+        let starts_clean = !needle.starts_with(is_word) || !before.is_some_and(is_word);
+        let ends_clean = !needle.ends_with(is_word) || !after.is_some_and(is_word);
+        starts_clean && ends_clean
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PrivilegeMode;
+    use std::collections::HashSet;
+
+    fn security(patterns: &[&str]) -> SecurityConfig {
+        SecurityConfig {
+            dangerous_commands: patterns.iter().map(|s| s.to_string()).collect::<HashSet<_>>(),
+            require_confirmation: true,
+            max_output_size: 1024,
+            allowed_directories: vec![],
+            disable_dangerous_commands: false,
+            risk_policy: RiskPolicy::default(),
+            outside_allowed_directories: RiskAction::Typed,
+            trial_before_run: false,
+            privileged_commands: PrivilegeMode::Allow,
+            child_environment: Default::default(),
+            resource_limits: Default::default(),
+            command_timeout_secs: None,
+            disable_dangerous_locked: false,
+        }
+    }
+
+    fn context() -> Context {
+        Context { cwd: "/home/me/project".into(), home: Some("/home/me".into()) }
+    }
+
+    fn rules(command: &str, patterns: &[&str]) -> Vec<String> {
+        analyze(command, &ShellType::Bash, &security(patterns), &context()).findings.into_iter().map(|f| f.rule).collect()
+    }
+
+    #[test]
+    fn test_configured_patterns() {
+        assert_eq!(rules("rm -rf build", &["rm -rf"]), vec!["rm -rf", "file-delete"]);
+        assert_eq!(rules("rm   -rf build", &["rm -rf"]), vec!["rm -rf", "file-delete"]);
+        assert!(rules("ls -la", &["rm -rf"]).is_empty());
+    }
+
+    #[test]
+    fn test_patterns_match_flag_variants() {
+        for command in ["rm -r -f build", "rm -fr build", "rm --recursive --force build", "/bin/rm -Rf x"] {
+            assert_eq!(rules(command, &["rm -rf"]), vec!["rm -rf", "file-delete"], "{}", command);
+        }
+        assert_eq!(rules("rm -r build", &["rm -rf"]), vec!["file-delete"]);
+    }
+
+    #[test]
+    fn test_patterns_match_program_names_not_substrings() {
+        assert!(rules("git add .", &["dd"]).is_empty());
+        assert!(rules("git diff --stat | odd", &["dd"]).is_empty());
+        assert_eq!(rules("dd if=a of=b", &["dd"]), vec!["dd"]);
+        assert_eq!(rules("ls | sudo xargs dd", &["dd"]), vec!["dd", "privilege-escalation"]);
+        assert_eq!(rules("echo $(shutdown -h now)", &["shutdown"]), vec!["shutdown", "power-off"]);
+    }
+
+    #[test]
+    fn test_redirect_patterns() {
+        assert_eq!(rules("cat x > /dev/sdb", &["> /dev"]), vec!["> /dev", "raw-disk-write"]);
+        assert!(rules("make 2>/dev/null", &["> /dev"]).is_empty());
+        assert!(rules("echo hi > ./dev", &["> /dev"]).is_empty());
+    }
+
+    #[test]
+    fn test_builtin_rules() {
+        assert_eq!(rules("rm -rf /", &[]), vec!["recursive-delete-root", "file-delete"]);
+        assert_eq!(rules("sudo rm -r -f ~", &[]), vec!["recursive-delete-root", "file-delete", "privilege-escalation"]);
+        assert_eq!(rules("cd /tmp && (rm --recursive \"$HOME\")", &[]), vec!["recursive-delete-root", "file-delete"]);
+        assert_eq!(rules("bash -c 'rm -rf /'", &[]), vec!["recursive-delete-root", "file-delete"]);
+        assert_eq!(rules(":(){ :|:& };:", &[]), vec!["fork-bomb"]);
+        assert_eq!(rules("dd if=/dev/zero of=/dev/sda", &[]), vec!["raw-disk-write"]);
+        assert_eq!(rules("cat img | sudo tee /dev/nvme0n1", &[]), vec!["raw-disk-write", "privilege-escalation"]);
+        assert_eq!(rules("sudo mkfs.ext4 /dev/sdb1", &[]), vec!["filesystem-format", "privilege-escalation"]);
+        assert_eq!(rules("chmod -R 777 /etc", &[]), vec!["recursive-permissions-root"]);
+        assert_eq!(rules("ls && sudo systemctl reboot", &[]), vec!["power-off", "privilege-escalation"]);
+    }
+
+    #[test]
+    fn test_destructive_builtin_rules() {
+        for command in ["find / -delete", "find / -exec rm -rf {} +", "rm important.txt", "shred -u ~/.ssh/id_rsa", "unlink x"] {
+            assert_eq!(rules(command, &[]), vec!["file-delete"], "{}", command);
+        }
+        assert_eq!(rules("git clean -fdx", &[]), vec!["git-discard"]);
+        assert_eq!(rules("git reset --hard HEAD~1", &[]), vec!["git-discard"]);
+        assert!(rules("git clean -n", &[]).is_empty());
+        assert!(rules("git reset HEAD~1", &[]).is_empty());
+
+        for command in ["kill -9 -1", "kill -- -1", "kill -s KILL -1234", "kill 42 -1234"] {
+            assert_eq!(rules(command, &[]), vec!["kill-all"], "{}", command);
+        }
+        assert!(rules("kill -1 1234", &[]).is_empty());
+        assert!(rules("kill -9 1234", &[]).is_empty());
+
+        assert_eq!(rules("cp /dev/zero /dev/sda", &[]), vec!["raw-disk-write"]);
+        assert_eq!(rules("cat disk.img > /dev/loop0", &[]), vec!["raw-disk-write"]);
+
+        // Nested in clause headers and expansions, not just plain commands
+        for command in ["case $(rm -rf /) in *) ;; esac", "echo $(( $(rm -rf /) ))", "for f in $(rm -rf /); do :; done"] {
+            assert!(risk(command) >= RiskLevel::Destructive, "{}", command);
+        }
+        assert!(risk("function f { rm -rf /; }; f") >= RiskLevel::Destructive);
+    }
+
+    #[test]
+    fn test_safe_commands_pass() {
+        assert!(rules("mkdir -p ./build", &[]).is_empty());
+        assert!(rules("chmod -R 755 ./site", &[]).is_empty());
+        assert!(rules("ls -la /dev", &[]).is_empty());
+        assert!(rules("echo 'rm -rf /'", &[]).is_empty());
+        assert!(rules("grep -r shutdown .", &["shutdown"]).is_empty());
+    }
+
+    #[test]
+    fn test_unparsable_is_flagged() {
+        assert_eq!(rules("echo 'unterminated", &[]), vec!["unparsable"]);
+    }
+
+    #[test]
+    fn test_finding_names_segment() {
+        let findings = analyze("ls; rm -rf /", &ShellType::Bash, &security(&[]), &context()).findings;
+        assert_eq!(findings[0].segment.as_deref(), Some("rm -rf /"));
+        assert!(findings[0].to_string().contains("(system-critical) in `rm -rf /`"));
+    }
+
+    #[test]
+    fn test_other_shells_match_text() {
+        let assessment = analyze("Remove-Item  -Recurse C:\\x", &ShellType::PowerShell, &security(&["Remove-Item -Recurse"]), &context());
+        assert_eq!(assessment.findings.len(), 1);
+        assert_eq!(assessment.risk, RiskLevel::Destructive);
+        let assessment = analyze("Get-ChildItem", &ShellType::PowerShell, &security(&[]), &context());
+        assert_eq!(assessment.risk, RiskLevel::Modifying);
+        assert!(analyze("git add .", &ShellType::Cmd, &security(&["dd"]), &context()).findings.is_empty());
+    }
+
+    fn risk(command: &str) -> RiskLevel {
+        analyze(command, &ShellType::Bash, &security(&["rm -rf", "dd"]), &context()).risk
+    }
+
+    #[test]
+    fn test_risk_levels() {
+        assert_eq!(risk("ls -la | grep foo | wc -l"), RiskLevel::ReadOnly);
+        assert_eq!(risk("git status && git log --oneline"), RiskLevel::ReadOnly);
+        assert_eq!(risk("find . -name '*.log' 2>/dev/null"), RiskLevel::ReadOnly);
+        assert_eq!(risk("find . -name '*.log' -delete"), RiskLevel::Destructive);
+        assert_eq!(risk("ls > files.txt"), RiskLevel::Modifying);
+        assert_eq!(risk("sed -i s/a/b/ f"), RiskLevel::Modifying);
+        assert_eq!(risk("git push"), RiskLevel::Modifying);
+        assert_eq!(risk("echo $(touch x)"), RiskLevel::Modifying);
+        assert_eq!(risk("rm -rf build"), RiskLevel::Destructive);
+        assert_eq!(risk("rm -rf /"), RiskLevel::SystemCritical);
+        assert_eq!(risk("echo 'open"), RiskLevel::Destructive);
+    }
+
+    #[test]
+    fn test_read_only_depends_on_arguments() {
+        assert_eq!(risk("sort data | uniq -c"), RiskLevel::ReadOnly);
+        assert_eq!(risk("uniq a /etc/passwd"), RiskLevel::Modifying);
+        assert_eq!(risk("xxd dump.bin"), RiskLevel::ReadOnly);
+        assert_eq!(risk("xxd -r dump.hex out.bin"), RiskLevel::Modifying);
+        assert_eq!(risk("date +%s"), RiskLevel::ReadOnly);
+        assert_eq!(risk("date -s '2020-01-01'"), RiskLevel::Modifying);
+        assert_eq!(risk("date 010100002020"), RiskLevel::Modifying);
+        assert_eq!(risk("hostname"), RiskLevel::ReadOnly);
+        assert_eq!(risk("hostname evil"), RiskLevel::Modifying);
+        assert_eq!(risk("history 20"), RiskLevel::ReadOnly);
+        assert_eq!(risk("history -c"), RiskLevel::Modifying);
+    }
+
+    #[test]
+    fn test_allowed_directories() {
+        let mut security = security(&[]);
+        security.allowed_directories = vec!["~".to_string(), "./".to_string()];
+        let check = |command: &str| analyze(command, &ShellType::Bash, &security, &context());
+
+        let assessment = check("touch build/out.o && echo ok > log.txt");
+        assert!(assessment.findings.is_empty());
+
+        let assessment = check("cd /etc && sudo touch hosts.bak");
+        assert_eq!(assessment.findings.len(), 2);
+        assert_eq!(assessment.runs_as_root().count(), 1);
+        assert!(assessment.findings[1].reason.contains("/etc/hosts.bak"));
+        assert_eq!(assessment.required_action(RiskLevel::ReadOnly, &security.risk_policy), RiskAction::Typed);
+
+        // A target only the shell can resolve might be anywhere
+        let assessment = check("cd \"$DIR\" && touch notes.txt");
+        assert_eq!(assessment.required_action(RiskLevel::ReadOnly, &security.risk_policy), RiskAction::Typed);
+
+        // Reads outside are fine, and so is discarding output
+        assert!(check("cat /etc/hosts 2>/dev/null").findings.is_empty());
+
+        security.allowed_directories.clear();
+        assert!(analyze("touch /etc/hosts", &ShellType::Bash, &security, &context()).findings.is_empty());
+    }
+
+    #[test]
+    fn test_privilege_escalation_needs_confirmation() {
+        let mut security = security(&[]);
+        security.risk_policy.modifying = RiskAction::Auto;
+        let assessment = analyze("sudo systemctl restart nginx", &ShellType::Bash, &security, &context());
+        let root: Vec<_> = assessment.runs_as_root().map(|f| f.segment.as_deref()).collect();
+        assert_eq!(root, vec![Some("systemctl restart nginx")]);
+        assert_eq!(assessment.required_action(RiskLevel::ReadOnly, &security.risk_policy), RiskAction::Confirm);
+
+        let assessment = analyze("systemctl status nginx", &ShellType::Bash, &security, &context());
+        assert_eq!(assessment.runs_as_root().count(), 0);
+    }
+
+    #[test]
+    fn test_policy_rules_cannot_skip_root_confirmation() {
+        use crate::policy::Match;
+        let security = security(&[]);
+        let allowed = Verdict {
+            matches: vec![Match {
+                command: "systemctl restart nginx".to_string(),
+                rule: "ops".to_string(),
+                action: RiskAction::Auto,
+                reason: None,
+            }],
+            all_covered: true,
+        };
+        let decide = |command: &str| {
+            let assessment = analyze(command, &ShellType::Bash, &security, &context());
+            assessment.decide(assessment.risk, &security.risk_policy, &allowed)
+        };
+        assert_eq!(decide("sudo systemctl restart nginx"), RiskAction::Confirm);
+        assert_eq!(decide("systemctl restart nginx"), RiskAction::Auto);
+    }
+
+    #[test]
+    fn test_default_config_catches_rm_rf_root() {
+        let findings = rules("rm -rf /", &["rm -rf", "dd"]);
+        assert!(findings.contains(&"rm -rf".to_string()));
+        assert!(findings.contains(&"recursive-delete-root".to_string()));
+    }
+}
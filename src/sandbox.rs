@@ -0,0 +1,556 @@
+// src/sandbox.rs
+use crate::config::SecurityConfig;
+use crate::executor::CommandOutput;
+use anyhow::Result;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// What a trial run did to one path under the working directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ChangeKind {
+    Created,
+    Modified,
+    Deleted,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    pub kind: ChangeKind,
+    /// Relative to the directory the command ran in.
+    pub path: PathBuf,
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (marker, verb) = match self.kind {
+            ChangeKind::Created => ("+", "created"),
+            ChangeKind::Modified => ("~", "modified"),
+            ChangeKind::Deleted => ("-", "deleted"),
+        };
+        write!(f, "{} {:<8} {}", marker, verb, self.path.display())
+    }
+}
+
+/// The result of running a command in the sandbox.
+pub struct Trial {
+    pub output: CommandOutput,
+    pub changes: Vec<Change>,
+    /// Ways the sandbox was weaker than usual for this run.
+    pub notes: Vec<String>,
+}
+
+/// Runs `command` in a throwaway sandbox and reports what it changed under
+/// `cwd`. The sandbox is an unprivileged user, mount, PID and IPC namespace
+/// with no network, where the command runs as PID 1 and sees only its own
+/// processes, `cwd` is an overlay whose writes land in a scratch directory,
+/// `/tmp` and the runtime directories holding service sockets are empty,
+/// `/dev` has only the basic devices and every other mount but `/sys` is
+/// read-only. The command still has the user's own access to what `/sys`
+/// exposes and, unless we run as root, to what their supplementary groups
+/// grant. Its output is shown as it runs, and Ctrl-C and
+/// `command_timeout_secs` stop it as they would the real run.
+#[cfg(target_os = "linux")]
+pub async fn trial_run(command: &str, cwd: &Path, security: &SecurityConfig) -> Result<Trial> {
+    linux::trial_run(command, cwd, security).await
+}
+
+#[cfg(not(target_os = "linux"))]
+pub async fn trial_run(_command: &str, _cwd: &Path, _security: &SecurityConfig) -> Result<Trial> {
+    Err(anyhow::anyhow!("Trial runs need Linux user namespaces"))
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use super::{Change, ChangeKind, Trial};
+    use crate::config::SecurityConfig;
+    use crate::executor;
+    use crate::shell::ShellType;
+    use anyhow::{anyhow, Result};
+    use std::ffi::{CStr, CString};
+    use std::fs;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::{FileTypeExt, MetadataExt, PermissionsExt};
+    use std::os::unix::process::CommandExt;
+    use std::path::{Path, PathBuf};
+
+    /// Pseudo file systems the command needs working as they are. `/proc` is
+    /// replaced by one for the sandbox's PID namespace.
+    const LEAVE_WRITABLE: [&str; 2] = ["/proc", "/sys"];
+
+    /// Where services put the sockets that would let the command act outside
+    /// the sandbox (Docker, D-Bus, systemd). They are covered with empty
+    /// directories, as is `$XDG_RUNTIME_DIR`.
+    const RUNTIME_DIRS: [&str; 2] = ["/run", "/var/run"];
+
+    /// Device nodes the fresh `/dev` keeps from the real one.
+    const DEVICES: [&str; 6] = ["null", "zero", "full", "random", "urandom", "tty"];
+
+    pub async fn trial_run(command: &str, cwd: &Path, security: &SecurityConfig) -> Result<Trial> {
+        let cwd = fs::canonicalize(cwd)?;
+        let hidden = runtime_dirs();
+        if let Some(dir) = hidden.iter().find(|dir| cwd.starts_with(dir)) {
+            return Err(anyhow!("Cannot try commands in {}: it is hidden in the sandbox", dir.display()));
+        }
+        let mut notes = Vec::new();
+        let groups = supplementary_groups();
+        // SAFETY: geteuid cannot fail.
+        let drop_groups = groups && unsafe { libc::geteuid() } == 0;
+        if groups && !drop_groups {
+            notes.push(
+                "your supplementary groups (see `id`) still applied in the sandbox, so it could use the files, \
+                 sockets and devices they grant"
+                    .to_string(),
+            );
+        }
+        let scratch = Scratch::new(&cwd)?;
+        let plan = MountPlan::new(&cwd, &scratch, &hidden, drop_groups)?;
+
+        let shell_type = ShellType::detect();
+        let mut cmd = executor::shell_command(command, &shell_type, security);
+        cmd.current_dir(&cwd);
+        // SAFETY: `enter` only makes system calls on data prepared above; it
+        // does not allocate or take locks in the forked child.
+        unsafe {
+            cmd.pre_exec(move || plan.enter());
+        }
+        let output = executor::run(cmd, &shell_type, security).await.map_err(|e| {
+            anyhow!("Could not set up the sandbox: {} (are unprivileged user namespaces enabled?)", e)
+        })?;
+
+        let mut changes = Vec::new();
+        collect_changes(&scratch.upper, &cwd, Path::new(""), &mut changes)?;
+        Ok(Trial { output, changes, notes })
+    }
+
+    /// The runtime directories that exist, without symlinks or duplicates.
+    fn runtime_dirs() -> Vec<PathBuf> {
+        let mut dirs: Vec<PathBuf> = RUNTIME_DIRS
+            .iter()
+            .map(PathBuf::from)
+            .chain(std::env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from))
+            .filter_map(|dir| fs::canonicalize(dir).ok())
+            .collect();
+        dirs.sort();
+        dirs.dedup_by(|inner, outer| inner.starts_with(outer));
+        dirs
+    }
+
+    /// Whether the process is in groups besides its own. A user namespace
+    /// keeps their access to files, sockets and devices, and only root can
+    /// drop them.
+    fn supplementary_groups() -> bool {
+        // SAFETY: a zero-length call only returns the count; the second call
+        // gets a buffer of that length.
+        let groups = unsafe {
+            let count = libc::getgroups(0, std::ptr::null_mut());
+            let mut groups = vec![0; count.max(0) as usize];
+            let count = libc::getgroups(groups.len() as libc::c_int, groups.as_mut_ptr());
+            groups.truncate(count.max(0) as usize);
+            groups
+        };
+        // SAFETY: getgid cannot fail.
+        let gid = unsafe { libc::getgid() };
+        groups.iter().any(|group| *group != gid)
+    }
+
+    /// Upper and work directories for the overlay, removed on drop.
+    struct Scratch {
+        root: PathBuf,
+        upper: PathBuf,
+        work: PathBuf,
+    }
+
+    impl Scratch {
+        /// Where the fresh `/dev` is put together before it is mounted.
+        fn dev(&self) -> PathBuf {
+            self.root.join("dev")
+        }
+
+        fn new(cwd: &Path) -> Result<Self> {
+            // The overlay layers may not live inside the directory they cover
+            let base = [Some(std::env::temp_dir()), Some(PathBuf::from("/var/tmp")), dirs::cache_dir()]
+                .into_iter()
+                .flatten()
+                .filter_map(|dir| fs::canonicalize(dir).ok())
+                .find(|dir| !dir.starts_with(cwd))
+                .ok_or_else(|| anyhow!("Cannot try commands in {}: no scratch directory outside it", cwd.display()))?;
+
+            let nanos = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.subsec_nanos())
+                .unwrap_or(0);
+            let root = base.join(format!("spren-trial-{}-{}", std::process::id(), nanos));
+            let scratch = Scratch {
+                upper: root.join("upper"),
+                work: root.join("work"),
+                root,
+            };
+            fs::create_dir_all(&scratch.upper)?;
+            fs::create_dir_all(&scratch.work)?;
+            let dev = scratch.dev();
+            fs::create_dir_all(dev.join("shm"))?;
+            for device in DEVICES {
+                fs::write(dev.join(device), "")?;
+            }
+            std::os::unix::fs::symlink("/proc/self/fd", dev.join("fd"))?;
+            for (fd, name) in ["stdin", "stdout", "stderr"].iter().enumerate() {
+                std::os::unix::fs::symlink(format!("/proc/self/fd/{}", fd), dev.join(name))?;
+            }
+            fs::set_permissions(&scratch.root, fs::Permissions::from_mode(0o700))?;
+            Ok(scratch)
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            // overlayfs leaves an unreadable directory in `work`
+            make_removable(&self.root);
+            let _ = fs::remove_dir_all(&self.root);
+        }
+    }
+
+    fn make_removable(dir: &Path) {
+        let _ = fs::set_permissions(dir, fs::Permissions::from_mode(0o700));
+        if let Ok(children) = fs::read_dir(dir) {
+            for child in children.flatten() {
+                if child.file_type().is_ok_and(|t| t.is_dir()) {
+                    make_removable(&child.path());
+                }
+            }
+        }
+    }
+
+    /// Everything the child needs to build the sandbox, prepared before
+    /// forking so that `enter` is plain system calls.
+    struct MountPlan {
+        uid_map: Vec<u8>,
+        gid_map: Vec<u8>,
+        cwd: CString,
+        overlay_options: CString,
+        /// Bind mounts from the real `/dev` into the staged one.
+        devices: Vec<(CString, CString)>,
+        staged_dev: CString,
+        dev: CString,
+        dev_shm: CString,
+        hidden: Vec<CString>,
+        tmp: Option<CString>,
+        read_only: Vec<(CString, libc::c_ulong)>,
+        drop_groups: bool,
+    }
+
+    impl MountPlan {
+        fn new(cwd: &Path, scratch: &Scratch, hidden: &[PathBuf], drop_groups: bool) -> Result<Self> {
+            let cwd_c = c_path(cwd)?;
+            let overlay_options = CString::new(format!(
+                "lowerdir={},upperdir={},workdir={},userxattr",
+                escape_option(cwd),
+                escape_option(&scratch.upper),
+                escape_option(&scratch.work)
+            ))?;
+            let tmp = if cwd.starts_with("/tmp") { None } else { Some(c_path(Path::new("/tmp"))?) };
+            let devices = DEVICES
+                .iter()
+                .map(|device| Ok((c_path(&Path::new("/dev").join(device))?, c_path(&scratch.dev().join(device))?)))
+                .collect::<Result<_>>()?;
+
+            let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+            let read_only = parse_mountinfo(&mountinfo)
+                .into_iter()
+                .filter(|(target, _)| {
+                    // Paths under our own mounts now resolve to those instead
+                    let covered = target.starts_with(cwd)
+                        || target.starts_with("/dev")
+                        || hidden.iter().any(|dir| target.starts_with(dir))
+                        || (tmp.is_some() && target.starts_with("/tmp"));
+                    !covered && !LEAVE_WRITABLE.iter().any(|dir| target.starts_with(dir))
+                })
+                .map(|(target, flags)| Ok((c_path(&target)?, flags)))
+                .collect::<Result<_>>()?;
+
+            // SAFETY: getuid and getgid cannot fail.
+            let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+            Ok(MountPlan {
+                uid_map: format!("{} {} 1", uid, uid).into_bytes(),
+                gid_map: format!("{} {} 1", gid, gid).into_bytes(),
+                cwd: cwd_c,
+                overlay_options,
+                devices,
+                staged_dev: c_path(&scratch.dev())?,
+                dev: c_path(Path::new("/dev"))?,
+                dev_shm: c_path(Path::new("/dev/shm"))?,
+                hidden: hidden.iter().map(|dir| c_path(dir)).collect::<Result<_>>()?,
+                tmp,
+                read_only,
+                drop_groups,
+            })
+        }
+
+        /// Runs in the forked child before `exec`.
+        fn enter(&self) -> io::Result<()> {
+            use libc::{MS_BIND, MS_NODEV, MS_NOSUID, MS_PRIVATE, MS_RDONLY, MS_REC, MS_REMOUNT};
+
+            // SAFETY: plain system calls on valid, NUL-terminated buffers.
+            unsafe {
+                // Only possible outside the user namespace, where we are root
+                if self.drop_groups {
+                    check(libc::setgroups(0, std::ptr::null()))?;
+                }
+                check(libc::unshare(
+                    libc::CLONE_NEWUSER | libc::CLONE_NEWNS | libc::CLONE_NEWNET | libc::CLONE_NEWPID | libc::CLONE_NEWIPC,
+                ))?;
+                write_proc(c"/proc/self/setgroups", b"deny")?;
+                write_proc(c"/proc/self/uid_map", &self.uid_map)?;
+                write_proc(c"/proc/self/gid_map", &self.gid_map)?;
+
+                mount(None, c"/", None, MS_REC | MS_PRIVATE, None)?;
+                mount(Some(c"overlay"), &self.cwd, Some(c"overlay"), 0, Some(&self.overlay_options))?;
+                for (device, staged) in &self.devices {
+                    mount(Some(device), staged, None, MS_BIND, None)?;
+                }
+                mount(Some(&self.staged_dev), &self.dev, None, MS_BIND | MS_REC, None)?;
+                mount(Some(c"tmpfs"), &self.dev_shm, Some(c"tmpfs"), MS_NOSUID | MS_NODEV, None)?;
+                for dir in &self.hidden {
+                    mount(Some(c"tmpfs"), dir, Some(c"tmpfs"), MS_NOSUID | MS_NODEV, None)?;
+                }
+                if let Some(tmp) = &self.tmp {
+                    mount(Some(c"tmpfs"), tmp, Some(c"tmpfs"), MS_NOSUID | MS_NODEV, None)?;
+                }
+                for (target, flags) in &self.read_only {
+                    match mount(None, target, None, MS_BIND | MS_REMOUNT | MS_RDONLY | flags, None) {
+                        // Mounts we cannot reach cannot be reached by the command either
+                        Err(e) if matches!(e.raw_os_error(), Some(libc::EACCES | libc::ENOENT | libc::EINVAL)) => {}
+                        result => result?,
+                    }
+                }
+                // Step onto the overlay rather than the directory under it
+                check(libc::chdir(self.cwd.as_ptr()))?;
+                become_init()
+            }
+        }
+    }
+
+    /// Forks so that the command runs as PID 1 of the new PID namespace, with
+    /// a `/proc` that lists only its processes. The calling process stays
+    /// behind and exits the way the command did; when PID 1 exits, the
+    /// kernel kills whatever it left running in the namespace.
+    unsafe fn become_init() -> io::Result<()> {
+        let pid = libc::fork();
+        check(pid)?;
+        if pid == 0 {
+            let flags = libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC;
+            return mount(Some(c"proc"), c"/proc", Some(c"proc"), flags, None);
+        }
+
+        // Signals to the process group reach the command too; this process
+        // has to outlive it to report how it ended
+        for signal in [libc::SIGINT, libc::SIGTERM, libc::SIGHUP, libc::SIGQUIT] {
+            libc::signal(signal, libc::SIG_IGN);
+        }
+        // Drop the pipes, including the one `spawn` waits on for `exec`
+        if libc::syscall(libc::SYS_close_range, 0, libc::c_uint::MAX, 0) < 0 {
+            for fd in 0..libc::sysconf(libc::_SC_OPEN_MAX).clamp(3, 65536) as libc::c_int {
+                libc::close(fd);
+            }
+        }
+
+        let mut status = 0;
+        while libc::waitpid(pid, &mut status, 0) < 0 {
+            if io::Error::last_os_error().raw_os_error() != Some(libc::EINTR) {
+                libc::_exit(127);
+            }
+        }
+        if libc::WIFSIGNALED(status) {
+            let signal = libc::WTERMSIG(status);
+            libc::signal(signal, libc::SIG_DFL);
+            libc::kill(libc::getpid(), signal);
+        }
+        libc::_exit(if libc::WIFEXITED(status) { libc::WEXITSTATUS(status) } else { 1 })
+    }
+
+    unsafe fn mount(
+        source: Option<&CStr>,
+        target: &CStr,
+        fstype: Option<&CStr>,
+        flags: libc::c_ulong,
+        data: Option<&CStr>,
+    ) -> io::Result<()> {
+        let ptr = |s: Option<&CStr>| s.map_or(std::ptr::null(), |s| s.as_ptr());
+        check(libc::mount(ptr(source), target.as_ptr(), ptr(fstype), flags, ptr(data).cast()))
+    }
+
+    unsafe fn write_proc(path: &CStr, contents: &[u8]) -> io::Result<()> {
+        let fd = libc::open(path.as_ptr(), libc::O_WRONLY);
+        check(fd)?;
+        let written = libc::write(fd, contents.as_ptr().cast(), contents.len());
+        libc::close(fd);
+        if written < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn check(result: libc::c_int) -> io::Result<()> {
+        if result < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    fn c_path(path: &Path) -> Result<CString> {
+        Ok(CString::new(path.as_os_str().as_bytes())?)
+    }
+
+    /// Overlay options are comma separated, so commas and backslashes in
+    /// paths need escaping.
+    fn escape_option(path: &Path) -> String {
+        path.display().to_string().replace('\\', "\\\\").replace(',', "\\,")
+    }
+
+    /// Mount points and the per-mount flags a bind remount must keep, from
+    /// `/proc/self/mountinfo`, parents before children.
+    pub(super) fn parse_mountinfo(mountinfo: &str) -> Vec<(PathBuf, libc::c_ulong)> {
+        mountinfo
+            .lines()
+            .filter_map(|line| {
+                let fields: Vec<&str> = line.split(' ').collect();
+                let target = unescape_mountinfo(fields.get(4)?);
+                let flags = fields.get(5)?.split(',').fold(0, |flags, option| {
+                    flags
+                        | match option {
+                            "nosuid" => libc::MS_NOSUID,
+                            "nodev" => libc::MS_NODEV,
+                            "noexec" => libc::MS_NOEXEC,
+                            "noatime" => libc::MS_NOATIME,
+                            "nodiratime" => libc::MS_NODIRATIME,
+                            "relatime" => libc::MS_RELATIME,
+                            "strictatime" => libc::MS_STRICTATIME,
+                            _ => 0,
+                        }
+                });
+                Some((PathBuf::from(target), flags))
+            })
+            .collect()
+    }
+
+    /// Mountinfo writes spaces, tabs, newlines and backslashes as octal escapes.
+    fn unescape_mountinfo(field: &str) -> String {
+        let mut out = String::new();
+        let mut rest = field;
+        while let Some(index) = rest.find('\\') {
+            out.push_str(&rest[..index]);
+            let code = rest.get(index + 1..index + 4).and_then(|octal| u8::from_str_radix(octal, 8).ok());
+            match code {
+                Some(byte) => {
+                    out.push(byte as char);
+                    rest = &rest[index + 4..];
+                }
+                None => {
+                    out.push('\\');
+                    rest = &rest[index + 1..];
+                }
+            }
+        }
+        out.push_str(rest);
+        out
+    }
+
+    /// Reads the overlay's upper directory: whiteouts are deletions, anything
+    /// else is new or a copied-up original.
+    pub(super) fn collect_changes(upper: &Path, lower: &Path, relative: &Path, out: &mut Vec<Change>) -> io::Result<()> {
+        let mut entries: Vec<_> = fs::read_dir(upper.join(relative))?.flatten().collect();
+        entries.sort_by_key(|entry| entry.file_name());
+        let mut present = Vec::new();
+
+        for entry in entries {
+            let path = relative.join(entry.file_name());
+            present.push(entry.file_name());
+            let metadata = fs::symlink_metadata(entry.path())?;
+            let existed = fs::symlink_metadata(lower.join(&path)).is_ok();
+
+            if metadata.file_type().is_char_device() && metadata.rdev() == 0 {
+                out.push(Change { kind: ChangeKind::Deleted, path });
+            } else if metadata.is_dir() {
+                if !existed {
+                    out.push(Change { kind: ChangeKind::Created, path: path.clone() });
+                }
+                collect_changes(upper, lower, &path, out)?;
+            } else {
+                let kind = if existed { ChangeKind::Modified } else { ChangeKind::Created };
+                out.push(Change { kind, path });
+            }
+        }
+
+        // An opaque directory was removed and recreated, hiding everything below
+        if is_opaque(&upper.join(relative)) {
+            if let Ok(originals) = fs::read_dir(lower.join(relative)) {
+                for original in originals.flatten() {
+                    if !present.contains(&original.file_name()) {
+                        let path = relative.join(original.file_name());
+                        out.push(Change { kind: ChangeKind::Deleted, path });
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn is_opaque(dir: &Path) -> bool {
+        let Ok(path) = c_path(dir) else {
+            return false;
+        };
+        let mut value = [0u8; 1];
+        // SAFETY: valid path and a buffer of the length passed.
+        let len = unsafe {
+            libc::lgetxattr(path.as_ptr(), c"user.overlay.opaque".as_ptr(), value.as_mut_ptr().cast(), value.len())
+        };
+        len == 1 && value[0] == b'y'
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::linux::{collect_changes, parse_mountinfo};
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_parse_mountinfo() {
+        let mountinfo = "\
+22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+23 22 0:5 / /dev rw,nosuid,noexec shared:2 - devtmpfs udev rw
+24 22 0:40 / /mnt/my\\040disk ro,nodev,noatime - ext4 /dev/sdb1 rw";
+        let mounts = parse_mountinfo(mountinfo);
+        assert_eq!(mounts[0], (PathBuf::from("/"), libc::MS_RELATIME));
+        assert_eq!(mounts[1], (PathBuf::from("/dev"), libc::MS_NOSUID | libc::MS_NOEXEC));
+        assert_eq!(mounts[2], (PathBuf::from("/mnt/my disk"), libc::MS_NODEV | libc::MS_NOATIME));
+    }
+
+    #[test]
+    fn test_collect_changes() {
+        let lower = tempfile::TempDir::new().unwrap();
+        let upper = tempfile::TempDir::new().unwrap();
+        fs::write(lower.path().join("edited.txt"), "old").unwrap();
+        fs::create_dir(lower.path().join("src")).unwrap();
+
+        fs::write(upper.path().join("edited.txt"), "new").unwrap();
+        fs::create_dir_all(upper.path().join("src")).unwrap();
+        fs::write(upper.path().join("src/new.rs"), "").unwrap();
+        fs::create_dir_all(upper.path().join("out")).unwrap();
+        fs::write(upper.path().join("out/a.o"), "").unwrap();
+
+        let mut changes = Vec::new();
+        collect_changes(upper.path(), lower.path(), Path::new(""), &mut changes).unwrap();
+        let summary: Vec<String> = changes.iter().map(|c| c.to_string()).collect();
+        assert_eq!(
+            summary,
+            vec![
+                "~ modified edited.txt",
+                "+ created  out",
+                "+ created  out/a.o",
+                "+ created  src/new.rs",
+            ]
+        );
+    }
+}
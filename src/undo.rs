@@ -0,0 +1,397 @@
+// src/undo.rs
+use crate::config::UndoConfig;
+use crate::parser;
+use crate::paths::{self, Context};
+use crate::secrets;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Refuse to snapshot commands that touch more files than this.
+const MAX_FILES: usize = 10_000;
+
+/// What a path looked like before the command ran.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Saved {
+    /// A regular file, copied into the snapshot as `stored`.
+    File { stored: String, mode: Option<u32> },
+    Directory { mode: Option<u32> },
+    Symlink { target: PathBuf },
+    /// Nothing was there, so undo removes whatever the command created.
+    Absent,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SavedPath {
+    pub path: PathBuf,
+    #[serde(flatten)]
+    pub saved: Saved,
+}
+
+/// Everything needed to put the files back, stored as `manifest.json`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Manifest {
+    /// The history entry the snapshot belongs to.
+    pub id: u64,
+    pub timestamp: u64,
+    pub cwd: PathBuf,
+    pub command: String,
+    pub paths: Vec<SavedPath>,
+}
+
+impl Manifest {
+    /// How many saved paths existed before the command ran.
+    pub fn saved_count(&self) -> usize {
+        self.paths.iter().filter(|p| p.saved != Saved::Absent).count()
+    }
+}
+
+/// Snapshots on disk, one directory per history entry id.
+pub struct SnapshotStore {
+    root: PathBuf,
+}
+
+impl SnapshotStore {
+    pub fn open(data_dir: &Path) -> Self {
+        SnapshotStore { root: data_dir.join("snapshots") }
+    }
+
+    /// Saves the files under the working directory that `command` is
+    /// predicted to write. Returns `None` when there is nothing to save.
+    pub fn snapshot(
+        &self,
+        id: u64,
+        timestamp: u64,
+        command: &str,
+        context: &Context,
+        limits: &UndoConfig,
+    ) -> Result<Option<Manifest>> {
+        let targets = predicted_writes(command, context)?;
+        if targets.is_empty() {
+            return Ok(None);
+        }
+
+        let mut found = BTreeMap::new();
+        let mut size = 0;
+        for target in &targets {
+            survey(target, &mut found, &mut size)?;
+            if size > limits.max_snapshot_size {
+                return Err(anyhow!(
+                    "the files this command touches are over the {} byte snapshot limit",
+                    limits.max_snapshot_size
+                ));
+            }
+        }
+
+        let dir = self.root.join(id.to_string());
+        if dir.exists() {
+            fs::remove_dir_all(&dir)?;
+        }
+        fs::create_dir_all(dir.join("files"))?;
+
+        let mut paths = Vec::new();
+        for (index, (path, saved)) in found.into_iter().enumerate() {
+            let saved = match saved {
+                Saved::File { mode, .. } => {
+                    let stored = index.to_string();
+                    fs::copy(&path, dir.join("files").join(&stored))?;
+                    Saved::File { stored, mode }
+                }
+                other => other,
+            };
+            paths.push(SavedPath { path, saved });
+        }
+
+        let manifest = Manifest { id, timestamp, cwd: context.cwd.clone(), command: secrets::redact(command), paths };
+        fs::write(dir.join("manifest.json"), serde_json::to_string_pretty(&manifest)?)?;
+        self.prune(limits, timestamp, id)?;
+        Ok(Some(manifest))
+    }
+
+    /// All snapshots, oldest first.
+    pub fn list(&self) -> Result<Vec<Manifest>> {
+        let mut manifests = Vec::new();
+        if !self.root.exists() {
+            return Ok(manifests);
+        }
+        for entry in fs::read_dir(&self.root)?.flatten() {
+            if let Ok(manifest) = read_manifest(&entry.path()) {
+                manifests.push(manifest);
+            }
+        }
+        manifests.sort_by_key(|m| m.id);
+        Ok(manifests)
+    }
+
+    pub fn load(&self, id: u64) -> Result<Manifest> {
+        read_manifest(&self.root.join(id.to_string()))
+            .map_err(|_| anyhow!("No snapshot for #{}; it may have expired or been undone", id))
+    }
+
+    /// Puts every saved path back as it was and deletes the snapshot.
+    /// Returns paths that could not be restored exactly, with the reason.
+    pub fn restore(&self, manifest: &Manifest) -> Result<Vec<String>> {
+        let dir = self.root.join(manifest.id.to_string());
+        let mut problems = Vec::new();
+
+        // Recreate directories before the files inside them, and remove
+        // created files before the directories that hold them
+        let mut absent: Vec<&SavedPath> = manifest.paths.iter().filter(|p| p.saved == Saved::Absent).collect();
+        absent.sort_by(|a, b| b.path.cmp(&a.path));
+        for entry in absent {
+            if let Err(e) = remove_created(&entry.path) {
+                problems.push(format!("{}: {}", entry.path.display(), e));
+            }
+        }
+        for entry in manifest.paths.iter().filter(|p| p.saved != Saved::Absent) {
+            if let Err(e) = restore_path(&dir, entry) {
+                problems.push(format!("{}: {}", entry.path.display(), e));
+            }
+        }
+
+        if problems.is_empty() {
+            fs::remove_dir_all(&dir)?;
+        }
+        Ok(problems)
+    }
+
+    /// Drops snapshots past the age, count and size limits, oldest first,
+    /// never touching the one just taken.
+    fn prune(&self, limits: &UndoConfig, now: u64, keep: u64) -> Result<()> {
+        let max_age = limits.max_age_days * 24 * 60 * 60;
+        let mut snapshots: Vec<(Manifest, u64)> = self
+            .list()?
+            .into_iter()
+            .map(|m| {
+                let size = dir_size(&self.root.join(m.id.to_string()));
+                (m, size)
+            })
+            .collect();
+
+        let mut total: u64 = snapshots.iter().map(|(_, size)| size).sum();
+        while let Some((oldest, size)) = snapshots.first() {
+            let expired = now.saturating_sub(oldest.timestamp) > max_age;
+            let over = snapshots.len() > limits.max_snapshots || total > limits.max_store_size;
+            if oldest.id == keep || !(expired || over) {
+                break;
+            }
+            fs::remove_dir_all(self.root.join(oldest.id.to_string()))?;
+            total -= size;
+            snapshots.remove(0);
+        }
+        Ok(())
+    }
+}
+
+/// Existing paths under the working directory the command writes to, with
+/// globs expanded, plus paths it may create.
+fn predicted_writes(command: &str, context: &Context) -> Result<Vec<PathBuf>> {
+    let script = parser::parse(command).map_err(|e| anyhow!("cannot predict which files change: {}", e))?;
+    let mut targets = Vec::new();
+    for touched in paths::touched_paths(&script, context) {
+        if !touched.write || touched.unresolved || !touched.path.starts_with(&context.cwd) {
+            continue;
+        }
+        if touched.glob {
+            if let Ok(matches) = glob::glob(&touched.path.to_string_lossy()) {
+                targets.extend(matches.flatten());
+            }
+        } else {
+            targets.push(touched.path);
+        }
+    }
+    Ok(targets)
+}
+
+/// Records `path` and, for directories, everything inside it.
+fn survey(path: &Path, found: &mut BTreeMap<PathBuf, Saved>, size: &mut u64) -> Result<()> {
+    if found.contains_key(path) {
+        return Ok(());
+    }
+    if found.len() >= MAX_FILES {
+        return Err(anyhow!("the command touches more than {} files", MAX_FILES));
+    }
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        found.insert(path.to_path_buf(), Saved::Absent);
+        return Ok(());
+    };
+
+    let saved = if metadata.file_type().is_symlink() {
+        Saved::Symlink { target: fs::read_link(path)? }
+    } else if metadata.is_dir() {
+        Saved::Directory { mode: mode(&metadata) }
+    } else {
+        *size += metadata.len();
+        Saved::File { stored: String::new(), mode: mode(&metadata) }
+    };
+    let is_dir = matches!(saved, Saved::Directory { .. });
+    found.insert(path.to_path_buf(), saved);
+
+    if is_dir {
+        for child in fs::read_dir(path)?.flatten() {
+            survey(&child.path(), found, size)?;
+        }
+    }
+    Ok(())
+}
+
+fn restore_path(dir: &Path, entry: &SavedPath) -> Result<()> {
+    let path = &entry.path;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match &entry.saved {
+        Saved::Directory { mode } => {
+            if !path.is_dir() {
+                remove_created(path)?;
+                fs::create_dir(path)?;
+            }
+            set_mode(path, *mode)?;
+        }
+        Saved::File { stored, mode } => {
+            if fs::symlink_metadata(path).is_ok_and(|m| !m.is_file()) {
+                remove_created(path)?;
+            }
+            fs::copy(dir.join("files").join(stored), path)?;
+            set_mode(path, *mode)?;
+        }
+        Saved::Symlink { target } => {
+            remove_created(path)?;
+            make_symlink(target, path)?;
+        }
+        Saved::Absent => {}
+    }
+    Ok(())
+}
+
+/// Removes something the command created. Directories are only removed
+/// when empty, so files added since are never lost.
+fn remove_created(path: &Path) -> Result<()> {
+    match fs::symlink_metadata(path) {
+        Err(_) => Ok(()),
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir(path)
+            .map_err(|_| anyhow!("created by the command and no longer empty, so left in place")),
+        Ok(_) => Ok(fs::remove_file(path)?),
+    }
+}
+
+fn dir_size(dir: &Path) -> u64 {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return 0;
+    };
+    entries
+        .flatten()
+        .map(|entry| match entry.metadata() {
+            Ok(m) if m.is_dir() => dir_size(&entry.path()),
+            Ok(m) => m.len(),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+fn read_manifest(dir: &Path) -> Result<Manifest> {
+    Ok(serde_json::from_str(&fs::read_to_string(dir.join("manifest.json"))?)?)
+}
+
+#[cfg(unix)]
+fn mode(metadata: &fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(metadata.permissions().mode())
+}
+
+#[cfg(not(unix))]
+fn mode(_metadata: &fs::Metadata) -> Option<u32> {
+    None
+}
+
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: Option<u32>) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    if let Some(mode) = mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: Option<u32>) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn make_symlink(target: &Path, path: &Path) -> Result<()> {
+    Ok(std::os::unix::fs::symlink(target, path)?)
+}
+
+#[cfg(not(unix))]
+fn make_symlink(_target: &Path, path: &Path) -> Result<()> {
+    Err(anyhow!("cannot restore symlink {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn setup() -> (TempDir, TempDir, Context) {
+        let work = TempDir::new().unwrap();
+        let data = TempDir::new().unwrap();
+        fs::write(work.path().join("notes.txt"), "original").unwrap();
+        fs::create_dir(work.path().join("logs")).unwrap();
+        fs::write(work.path().join("logs/a.log"), "a").unwrap();
+        let context = Context { cwd: work.path().to_path_buf(), home: None };
+        (work, data, context)
+    }
+
+    #[test]
+    fn test_snapshot_and_restore() {
+        let (work, data, context) = setup();
+        let store = SnapshotStore::open(data.path());
+        let command = "sed -i s/original/changed/ notes.txt && rm -r logs && touch new.txt ../outside.txt";
+        let manifest = store.snapshot(7, 100, command, &context, &UndoConfig::default()).unwrap().unwrap();
+        assert_eq!(manifest.saved_count(), 3);
+        assert!(!manifest.paths.iter().any(|p| p.path.ends_with("outside.txt")));
+
+        // What the command would have done
+        fs::write(work.path().join("notes.txt"), "changed").unwrap();
+        fs::remove_dir_all(work.path().join("logs")).unwrap();
+        fs::write(work.path().join("new.txt"), "").unwrap();
+
+        let manifest = store.load(7).unwrap();
+        assert!(store.restore(&manifest).unwrap().is_empty());
+        assert_eq!(fs::read_to_string(work.path().join("notes.txt")).unwrap(), "original");
+        assert_eq!(fs::read_to_string(work.path().join("logs/a.log")).unwrap(), "a");
+        assert!(!work.path().join("new.txt").exists());
+        assert!(store.load(7).is_err());
+    }
+
+    #[test]
+    fn test_nothing_to_save() {
+        let (_work, data, context) = setup();
+        let store = SnapshotStore::open(data.path());
+        assert!(store.snapshot(1, 100, "ls -la logs", &context, &UndoConfig::default()).unwrap().is_none());
+
+        let limits = UndoConfig { max_snapshot_size: 3, ..UndoConfig::default() };
+        assert!(store.snapshot(2, 100, "rm notes.txt", &context, &limits).is_err());
+    }
+
+    #[test]
+    fn test_retention() {
+        let (_work, data, context) = setup();
+        let store = SnapshotStore::open(data.path());
+        let limits = UndoConfig { max_snapshots: 2, max_age_days: 1, ..UndoConfig::default() };
+        for id in 1..=3 {
+            store.snapshot(id, 100_000 + id, "rm notes.txt", &context, &limits).unwrap();
+        }
+        let ids: Vec<u64> = store.list().unwrap().iter().map(|m| m.id).collect();
+        assert_eq!(ids, vec![2, 3]);
+
+        // A day later only the newest survives
+        store.snapshot(4, 100_000 + 86_400 + 10, "rm notes.txt", &context, &limits).unwrap();
+        let ids: Vec<u64> = store.list().unwrap().iter().map(|m| m.id).collect();
+        assert_eq!(ids, vec![4]);
+    }
+}